@@ -1,18 +1,26 @@
-use crate::utilities::{determine_encrypted_size, format_colors, quit_sfs, remove_colors};
-use crate::Configuration;
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use rand::{distributions::Alphanumeric, Rng};
+use crate::journal::{remove_temporary_files, Journal, JournalState, JournalWriter};
+use crate::utilities::{
+    format_checksum, format_colors, get_configuration_directory, interrupted, quit_sfs,
+    read_password_source, remove_colors, PasswordSource,
+};
+use crate::{write_password_verifier, Configuration};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
 use serde_derive::{Deserialize, Serialize};
-use sfs::{Decrypter, Encrypter, FileMetadata, HashingAlgorithm};
-use std::collections::HashMap;
+use sfs::{
+    Cipher, Compression, Credentials, Decrypter, Encrypter, FileCipher, FileHeader, FileMetadata,
+    HashingAlgorithm, Identity, KeyDerivationFunction, KeySlot, Padding, Record,
+};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::io::{BufRead, BufReader, Read, Seek, Write};
+use std::io::{BufRead, BufReader, BufWriter, Seek, Write};
+use std::sync::Arc;
 use walkdir::WalkDir;
+use zeroize::Zeroizing;
 
 #[derive(Clone)]
 pub enum Context {
-    Configuration(Configuration),
-    Fernet(fernet::Fernet),
+    Configuration(Box<Configuration>),
+    Credentials(Arc<Credentials>),
 }
 
 #[derive(Debug, Clone)]
@@ -27,13 +35,12 @@ pub struct Command {
     pub metadata: CommandMetadata,
     pub flags: &'static [Flag],
     pub aliases: &'static [&'static str],
-    pub callback: fn(ParsedCommand),
+    pub callback: fn(ParsedCommand) -> bool,
     pub contexts: &'static [&'static str],
 }
 
 #[derive(Clone)]
 pub struct ParsedCommand {
-    pub name: String,
     pub flags: Vec<ParsedFlag>,
     pub contexts: HashMap<String, Context>,
 }
@@ -73,6 +80,24 @@ pub struct EncryptCommandConfiguration {
     pub hashing_algorithm: String,
     pub chunk_size: u64,
     pub assign_random_name: bool,
+    #[serde(default = "default_cipher")]
+    pub cipher: String,
+    #[serde(default = "default_compression")]
+    pub compression: String,
+    #[serde(default = "default_compression_level")]
+    pub compression_level: i32,
+    #[serde(default = "default_padding")]
+    pub padding: String,
+    #[serde(default = "default_verify")]
+    pub verify: bool,
+    #[serde(default)]
+    pub secure_delete: bool,
+    #[serde(default = "default_key_derivation_memory_cost")]
+    pub key_derivation_memory_cost: u32,
+    #[serde(default = "default_key_derivation_time_cost")]
+    pub key_derivation_time_cost: u32,
+    #[serde(default = "default_key_derivation_parallelism")]
+    pub key_derivation_parallelism: u32,
     pub progress_bar_format: String,
 }
 
@@ -84,262 +109,509 @@ pub struct DecryptCommandConfiguration {
     pub keep_file: bool,
     pub use_original_name: bool,
     pub no_verify_chunks: bool,
+    #[serde(default = "default_verify")]
+    pub verify: bool,
     pub progress_bar_format: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShredCommandConfiguration {
+    #[serde(default = "default_passes")]
+    pub passes: u32,
+}
+
+// Settings that were added later have defaults, so that older configuration files can still be read
+fn default_cipher() -> String {
+    String::from("xchacha20poly1305")
+}
+
+fn default_compression() -> String {
+    String::from("none")
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+fn default_padding() -> String {
+    String::from("none")
+}
+
+fn default_verify() -> bool {
+    true
+}
+
+pub fn default_passes() -> u32 {
+    3
+}
+
+fn default_key_derivation_memory_cost() -> u32 {
+    19456
+}
+
+fn default_key_derivation_time_cost() -> u32 {
+    2
+}
+
+fn default_key_derivation_parallelism() -> u32 {
+    1
+}
+
 pub fn get_commands() -> Vec<Command> {
-    let mut commands = Vec::new();
-    commands.push(Command {
-        name: "help",
-        metadata: CommandMetadata {
-            description: "Get help for a specified command (or list all)",
-            arguments: &["(COMMAND)..."],
-        },
-        flags: &[],
-        aliases: &["h", "?"],
-        callback: help_command,
-        contexts: &[],
-    });
-    commands.push(Command {
-        name: "version",
-        metadata: CommandMetadata {
-            description: "Display the current SFS and file format version",
-            arguments: &[],
-        },
-        flags: &[],
-        aliases: &["ver", "about"],
-        callback: version_command,
-        contexts: &[],
-    });
-    commands.push(Command {
-        name: "quit",
-        metadata: CommandMetadata {
-            description: "Save everything and quit SFS",
-            arguments: &[],
+    vec![
+        Command {
+            name: "help",
+            metadata: CommandMetadata {
+                description: "Get help for a specified command (or list all)",
+                arguments: &["(COMMAND)..."],
+            },
+            flags: &[],
+            aliases: &["h", "?"],
+            callback: help_command,
+            contexts: &[],
         },
-        flags: &[],
-        aliases: &["q", "exit"],
-        callback: quit_command,
-        contexts: &[],
-    });
-    commands.push(Command {
-        name: "cd",
-        metadata: CommandMetadata {
-            description: "Change your current working directory",
-            arguments: &["[DIRECTORY]"],
+        Command {
+            name: "version",
+            metadata: CommandMetadata {
+                description: "Display the current SFS and file format version",
+                arguments: &[],
+            },
+            flags: &[],
+            aliases: &["ver", "about"],
+            callback: version_command,
+            contexts: &[],
         },
-        flags: &[],
-        aliases: &[],
-        callback: change_directory_command,
-        contexts: &[],
-    });
-    commands.push(Command {
-        name: "ls",
-        metadata: CommandMetadata {
-            description: "List all the files in a specified directory",
-            arguments: &["(DIRECTORY)..."],
+        Command {
+            name: "quit",
+            metadata: CommandMetadata {
+                description: "Save everything and quit SFS",
+                arguments: &[],
+            },
+            flags: &[],
+            aliases: &["q", "exit"],
+            callback: quit_command,
+            contexts: &[],
         },
-        flags: &[
-            Flag {
-                name: "all",
-                short_name: "a",
-                description: "List hidden files (dot files) as well",
-                has_value: false,
+        Command {
+            name: "cd",
+            metadata: CommandMetadata {
+                description: "Change your current working directory",
+                arguments: &["[DIRECTORY]"],
             },
-            Flag {
-                name: "list",
-                short_name: "l",
-                description: "Display one file on a line (list view)",
-                has_value: false,
+            flags: &[],
+            aliases: &[],
+            callback: change_directory_command,
+            contexts: &[],
+        },
+        Command {
+            name: "ls",
+            metadata: CommandMetadata {
+                description: "List all the files in a specified directory",
+                arguments: &["(DIRECTORY)..."],
             },
-            Flag {
-                name: "columns",
-                short_name: "c",
-                description: "The amount of columns to print for grid view",
-                has_value: true,
+            flags: &[
+                Flag {
+                    name: "all",
+                    short_name: "a",
+                    description: "List hidden files (dot files) as well",
+                    has_value: false,
+                },
+                Flag {
+                    name: "list",
+                    short_name: "l",
+                    description: "Display one file on a line (list view)",
+                    has_value: false,
+                },
+                Flag {
+                    name: "columns",
+                    short_name: "c",
+                    description: "The amount of columns to print for grid view",
+                    has_value: true,
+                },
+                Flag {
+                    name: "decrypt-name",
+                    short_name: "d",
+                    description: "Display the decrypted names of encrypted files",
+                    has_value: false,
+                },
+            ],
+            aliases: &[],
+            callback: list_command,
+            contexts: &["credentials", "configuration"],
+        },
+        Command {
+            name: "rm",
+            metadata: CommandMetadata {
+                description: "Permanently remove a file",
+                arguments: &["[FILE]..."],
             },
-            Flag {
-                name: "decrypt-name",
-                short_name: "d",
-                description: "Display the decrypted names of encrypted files",
+            flags: &[Flag {
+                name: "dry-run",
+                short_name: "w",
+                description: "Only show what would be done, without changing anything",
                 has_value: false,
-            },
-        ],
-        aliases: &[],
-        callback: list_command,
-        contexts: &["fernet", "configuration"],
-    });
-    commands.push(Command {
-        name: "rm",
-        metadata: CommandMetadata {
-            description: "Permanently remove a file",
-            arguments: &["[FILE]..."],
-        },
-        flags: &[],
-        aliases: &["del", "delete"],
-        callback: remove_command,
-        contexts: &[],
-    });
-    commands.push(Command {
-        name: "cp",
-        metadata: CommandMetadata {
-            description: "Copy a file to a different location",
-            arguments: &["[FILE]", "[DESTINATION]"],
+            }],
+            aliases: &["del", "delete"],
+            callback: remove_command,
+            contexts: &[],
         },
-        flags: &[],
-        aliases: &["copy"],
-        callback: copy_command,
-        contexts: &[],
-    });
-    commands.push(Command {
-        name: "mv",
-        metadata: CommandMetadata {
-            description: "Move a file to a different location",
-            arguments: &["[FILE]", "[DESTINATION]"],
-        },
-        flags: &[],
-        aliases: &["move"],
-        callback: move_command,
-        contexts: &[],
-    });
-    commands.push(Command {
-        name: "clear",
-        metadata: CommandMetadata {
-            description: "Clear the terminal screen",
-            arguments: &[],
-        },
-        flags: &[],
-        aliases: &["cls"],
-        callback: clear_command,
-        contexts: &[],
-    });
-    commands.push(Command {
-        name: "encrypt",
-        metadata: CommandMetadata {
-            description: "Encrypt the specified file(s) with your password",
-            arguments: &["[FILE]..."],
+        Command {
+            name: "shred",
+            metadata: CommandMetadata {
+                description: "Overwrite a file with random data before removing it",
+                arguments: &["[FILE]..."],
+            },
+            flags: &[
+                Flag {
+                    name: "recursive",
+                    short_name: "r",
+                    description: "Shred directories and everything in them",
+                    has_value: false,
+                },
+                Flag {
+                    name: "passes",
+                    short_name: "p",
+                    description: "How many times to overwrite the file",
+                    has_value: true,
+                },
+                Flag {
+                    name: "dry-run",
+                    short_name: "w",
+                    description: "Only show what would be done, without changing anything",
+                    has_value: false,
+                },
+            ],
+            aliases: &[],
+            callback: shred_command,
+            contexts: &["configuration"],
         },
-        flags: &[
-            Flag {
-                name: "recursive",
-                short_name: "r",
-                description: "Recursively encrypt all files",
-                has_value: false,
+        Command {
+            name: "cp",
+            metadata: CommandMetadata {
+                description: "Copy a file to a different location",
+                arguments: &["[FILE]", "[DESTINATION]"],
             },
-            Flag {
-                name: "silent",
-                short_name: "s",
-                description: "Do not display a progress bar or any warnings",
+            flags: &[Flag {
+                name: "dry-run",
+                short_name: "w",
+                description: "Only show what would be done, without changing anything",
                 has_value: false,
+            }],
+            aliases: &["copy"],
+            callback: copy_command,
+            contexts: &[],
+        },
+        Command {
+            name: "mv",
+            metadata: CommandMetadata {
+                description: "Move a file to a different location",
+                arguments: &["[FILE]", "[DESTINATION]"],
             },
-            Flag {
-                name: "overwrite",
-                short_name: "o",
-                description: "Overwrite the output file even if it exists",
+            flags: &[Flag {
+                name: "dry-run",
+                short_name: "w",
+                description: "Only show what would be done, without changing anything",
                 has_value: false,
+            }],
+            aliases: &["move"],
+            callback: move_command,
+            contexts: &[],
+        },
+        Command {
+            name: "clear",
+            metadata: CommandMetadata {
+                description: "Clear the terminal screen",
+                arguments: &[],
             },
-            Flag {
-                name: "keep-file",
-                short_name: "k",
-                description: "Keep the original file after encrypting",
-                has_value: false,
+            flags: &[],
+            aliases: &["cls"],
+            callback: clear_command,
+            contexts: &[],
+        },
+        Command {
+            name: "encrypt",
+            metadata: CommandMetadata {
+                description: "Encrypt the specified file(s) with your password (`-` encrypts stdin to stdout)",
+                arguments: &["[FILE]..."],
             },
-            Flag {
-                name: "hashing-algorithm",
-                short_name: "h",
-                description: "Which hashing algorithm to use (None/XXH3/XXH64/XXH32)",
-                has_value: true,
+            flags: &[
+                Flag {
+                    name: "recursive",
+                    short_name: "r",
+                    description: "Recursively encrypt all files",
+                    has_value: false,
+                },
+                Flag {
+                    name: "silent",
+                    short_name: "s",
+                    description: "Do not display a progress bar or any warnings",
+                    has_value: false,
+                },
+                Flag {
+                    name: "overwrite",
+                    short_name: "o",
+                    description: "Overwrite the output file even if it exists",
+                    has_value: false,
+                },
+                Flag {
+                    name: "keep-file",
+                    short_name: "k",
+                    description: "Keep the original file after encrypting",
+                    has_value: false,
+                },
+                Flag {
+                    name: "hashing-algorithm",
+                    short_name: "h",
+                    description: "Which hashing algorithm to use (None/XXH3/XXH64/XXH32/BLAKE3/SHA256)",
+                    has_value: true,
+                },
+                Flag {
+                    name: "chunk-size",
+                    short_name: "c",
+                    description: "The chunk size to encrypt data in",
+                    has_value: true,
+                },
+                Flag {
+                    name: "assign-random-name",
+                    short_name: "a",
+                    description: "Assign a random name to the encrypted file",
+                    has_value: false,
+                },
+                Flag {
+                    name: "cipher",
+                    short_name: "e",
+                    description: "Which cipher to use (AES256GCM/XCHACHA20POLY1305)",
+                    has_value: true,
+                },
+                Flag {
+                    name: "compression",
+                    short_name: "z",
+                    description: "Compress the data before encrypting it (None/ZSTD/Deflate)",
+                    has_value: true,
+                },
+                Flag {
+                    name: "compression-level",
+                    short_name: "l",
+                    description: "The compression level (1-22 for ZSTD, 0-9 for Deflate)",
+                    has_value: true,
+                },
+                Flag {
+                    name: "padding",
+                    short_name: "p",
+                    description: "Hide the size of the file with padding (None/Power-Of-Two/Bucket:SIZE/Random:1-100)",
+                    has_value: true,
+                },
+                Flag {
+                    name: "archive",
+                    short_name: "b",
+                    description: "Pack each directory into a single encrypted archive",
+                    has_value: false,
+                },
+                Flag {
+                    name: "verify",
+                    short_name: "v",
+                    description: "Toggle decrypting the encrypted file again before the original file is removed",
+                    has_value: false,
+                },
+                Flag {
+                    name: "secure-delete",
+                    short_name: "d",
+                    description: "Shred the original file instead of just removing it",
+                    has_value: false,
+                },
+                Flag {
+                    name: "dry-run",
+                    short_name: "w",
+                    description: "Only show what would be done, without changing anything",
+                    has_value: false,
+                },
+                Flag {
+                    name: "recipient",
+                    short_name: "t",
+                    description: "Encrypt for a recipient (name or public key) instead of your password",
+                    has_value: true,
+                },
+            ],
+            aliases: &[],
+            callback: encrypt_command,
+            contexts: &["credentials", "configuration"],
+        },
+        Command {
+            name: "decrypt",
+            metadata: CommandMetadata {
+                description: "Decrypt the specified file(s) with your password (`-` decrypts stdin to stdout)",
+                arguments: &["[FILE]..."],
             },
-            Flag {
-                name: "chunk-size",
-                short_name: "c",
-                description: "The chunk size to encrypt data in",
-                has_value: true,
+            flags: &[
+                Flag {
+                    name: "recursive",
+                    short_name: "r",
+                    description: "Recursively decrypt all files",
+                    has_value: false,
+                },
+                Flag {
+                    name: "silent",
+                    short_name: "s",
+                    description: "Do not display a progress bar or any warnings",
+                    has_value: false,
+                },
+                Flag {
+                    name: "overwrite",
+                    short_name: "o",
+                    description: "Overwrite the output file even if it already exists",
+                    has_value: false,
+                },
+                Flag {
+                    name: "keep-file",
+                    short_name: "k",
+                    description: "Keep the encrypted file after decrypting",
+                    has_value: false,
+                },
+                Flag {
+                    name: "use-original-name",
+                    short_name: "u",
+                    description: "Use the name of the original file while decrypting (enabled by default if name is random)",
+                    has_value: false,
+                },
+                Flag {
+                    name: "no-verify-chunks",
+                    short_name: "n",
+                    description: "Don't verify that the chunks match the checksum",
+                    has_value: false,
+                },
+                Flag {
+                    name: "verify",
+                    short_name: "v",
+                    description: "Toggle keeping the encrypted file if the checksum of the decrypted file wasn't verified",
+                    has_value: false,
+                },
+                Flag {
+                    name: "dry-run",
+                    short_name: "w",
+                    description: "Only show what would be done, without changing anything",
+                    has_value: false,
+                },
+                Flag {
+                    name: "force",
+                    short_name: "f",
+                    description: "Decrypt the file even if the file format version isn't supported",
+                    has_value: false,
+                },
+            ],
+            aliases: &[],
+            callback: decrypt_command,
+            contexts: &["credentials", "configuration"],
+        },
+        Command {
+            name: "resume",
+            metadata: CommandMetadata {
+                description: "Finish (or undo) a recursive encrypt/decrypt that was interrupted",
+                arguments: &[],
             },
-            Flag {
-                name: "assign-random-name",
-                short_name: "a",
-                description: "Assign a random name to the encrypted file",
+            flags: &[Flag {
+                name: "rollback",
+                short_name: "r",
+                description: "Undo what was already done instead of finishing it",
                 has_value: false,
+            }],
+            aliases: &[],
+            callback: resume_command,
+            contexts: &["credentials", "configuration"],
+        },
+        Command {
+            name: "information",
+            metadata: CommandMetadata {
+                description: "Display information about an encrypted file",
+                arguments: &["[FILE]..."],
             },
-        ],
-        aliases: &[],
-        callback: encrypt_command,
-        contexts: &["fernet", "configuration"],
-    });
-    commands.push(Command {
-        name: "decrypt",
-        metadata: CommandMetadata {
-            description: "Decrypt the specified file(s) with your password",
-            arguments: &["[FILE]..."],
+            flags: &[],
+            aliases: &["info", "metadata"],
+            callback: information_command,
+            contexts: &["credentials"],
         },
-        flags: &[
-            Flag {
-                name: "recursive",
-                short_name: "r",
-                description: "Recursively decrypt all files",
-                has_value: false,
+        Command {
+            name: "rekey",
+            metadata: CommandMetadata {
+                description: "Change the password of all encrypted files in the specified directories",
+                arguments: &["(PATH)..."],
             },
-            Flag {
-                name: "silent",
-                short_name: "s",
-                description: "Do not display a progress bar or any warnings",
-                has_value: false,
+            flags: &[
+                Flag {
+                    name: "silent",
+                    short_name: "s",
+                    description: "Only display errors",
+                    has_value: false,
+                },
+                Flag {
+                    name: "keyfile",
+                    short_name: "f",
+                    description: "Use a different keyfile from now on",
+                    has_value: true,
+                },
+                Flag {
+                    name: "no-keyfile",
+                    short_name: "n",
+                    description: "Stop using a keyfile from now on",
+                    has_value: false,
+                },
+                Flag {
+                    name: "new-password-fd",
+                    short_name: "d",
+                    description: "Read the new password from a file descriptor instead of asking for it",
+                    has_value: true,
+                },
+                Flag {
+                    name: "new-password-file",
+                    short_name: "p",
+                    description: "Read the new password from a file instead of asking for it",
+                    has_value: true,
+                },
+                Flag {
+                    name: "new-password-command",
+                    short_name: "c",
+                    description: "Read the new password from the output of a command instead of asking for it",
+                    has_value: true,
+                },
+            ],
+            aliases: &["passwd"],
+            callback: rekey_command,
+            contexts: &["credentials", "configuration"],
+        },
+        Command {
+            name: "keygen",
+            metadata: CommandMetadata {
+                description: "Generate an identity (keypair) that others can encrypt files for",
+                arguments: &[],
             },
-            Flag {
+            flags: &[Flag {
                 name: "overwrite",
                 short_name: "o",
-                description: "Overwrite the output file even if it already exists",
-                has_value: false,
-            },
-            Flag {
-                name: "keep-file",
-                short_name: "k",
-                description: "Keep the encrypted file after decrypting",
-                has_value: false,
-            },
-            Flag {
-                name: "use-original-name",
-                short_name: "u",
-                description: "Use the name of the original file while decrypting (enabled by default if name is random)",
-                has_value: false,
-            },
-            Flag {
-                name: "no-verify-chunks",
-                short_name: "n",
-                description: "Don't verify that the chunks match the checksum",
-                has_value: false,
-            },
-            Flag {
-                name: "force",
-                short_name: "f",
-                description: "Decrypt the file even if the file format version doesn't match",
+                description: "Replace your existing identity (files encrypted for it will be lost)",
                 has_value: false,
+            }],
+            aliases: &[],
+            callback: keygen_command,
+            contexts: &[],
+        },
+        Command {
+            name: "recipients",
+            metadata: CommandMetadata {
+                description: "List, add or remove the recipients you can encrypt files for",
+                arguments: &["(add [NAME] [PUBLIC KEY] | remove [NAME]...)"],
             },
-        ],
-        aliases: &[],
-        callback: decrypt_command,
-        contexts: &["fernet", "configuration"],
-    });
-    commands.push(Command {
-        name: "information",
-        metadata: CommandMetadata {
-            description: "Display information about an encrypted file",
-            arguments: &["[FILE]..."],
+            flags: &[],
+            aliases: &["recipient"],
+            callback: recipients_command,
+            contexts: &[],
         },
-        flags: &[],
-        aliases: &["info", "metadata"],
-        callback: information_command,
-        contexts: &["fernet"],
-    });
-    commands
+    ]
 }
 
-pub fn help_command(command: ParsedCommand) {
-    if command.flags.len() > 0 {
+pub fn help_command(command: ParsedCommand) -> bool {
+    let mut succeeded = true;
+    if !command.flags.is_empty() {
         for flag in command.flags {
-            if flag.name.is_none() && flag.value.is_some() {
-                let input_command = flag.value.unwrap();
+            if let (None, Some(input_command)) = (flag.name, flag.value) {
                 let mut command_found = false;
                 for command in get_commands() {
                     let mut matched = false;
@@ -356,17 +628,17 @@ pub fn help_command(command: ParsedCommand) {
                         command_found = true;
 
                         let mut contexts = String::from("None");
-                        if command.contexts.len() > 0 {
+                        if !command.contexts.is_empty() {
                             contexts.clear();
                             contexts = command.contexts.join(", ");
                         }
 
-                        let mut usage = format!("{}", command.name);
-                        if command.flags.len() > 0 {
+                        let mut usage = command.name.to_string();
+                        if !command.flags.is_empty() {
                             usage.push_str(" [FLAG]...")
                         }
                         for argument in command.metadata.arguments {
-                            usage.push_str(&(String::from(" ") + &argument))
+                            usage.push_str(&(String::from(" ") + argument))
                         }
 
                         let mut alias_list = Vec::new();
@@ -374,12 +646,12 @@ pub fn help_command(command: ParsedCommand) {
                             alias_list.push(format!("$BOLD$`{}`$NORMAL$", alias))
                         }
                         let mut aliases = String::new();
-                        if alias_list.len() > 0 {
+                        if !alias_list.is_empty() {
                             aliases = format!(" (AKA {})", alias_list.join("/"));
                         }
 
                         let mut flags = String::from(" None");
-                        if command.flags.len() > 0 {
+                        if !command.flags.is_empty() {
                             flags = String::new();
                             for flag in command.flags {
                                 let mut has_value = "";
@@ -410,7 +682,8 @@ pub fn help_command(command: ParsedCommand) {
                             "Unknown command $BOLD$`{}`$NORMAL$. Type $BOLD$`help`$NORMAL$ for a list of commands.",
                             input_command
                         ))
-                    )
+                    );
+                    succeeded = false;
                 }
             }
         }
@@ -425,9 +698,10 @@ pub fn help_command(command: ParsedCommand) {
             )
         }
     }
+    succeeded
 }
 
-pub fn version_command(_command: ParsedCommand) {
+pub fn version_command(_command: ParsedCommand) -> bool {
     println!(
         "{}",
         format_colors(&format!(
@@ -435,42 +709,53 @@ pub fn version_command(_command: ParsedCommand) {
             sfs::SFS_VERSION_STRING,
             sfs::SFS_FORMAT_VERSION,
         ))
-    )
+    );
+    true
 }
 
-pub fn quit_command(_: ParsedCommand) {
+pub fn quit_command(_: ParsedCommand) -> bool {
     quit_sfs();
 }
 
-pub fn change_directory_command(command: ParsedCommand) {
+pub fn change_directory_command(command: ParsedCommand) -> bool {
+    let mut succeeded = true;
     for flag in command.flags {
-        if flag.name.is_none() && flag.value.is_some() {
-            match std::env::set_current_dir(flag.value.unwrap()) {
+        if let (None, Some(value)) = (flag.name, flag.value) {
+            match std::env::set_current_dir(value) {
                 Ok(_) => (),
                 Err(error) => {
                     println!(
                         "{} {:?}",
                         format_colors(&String::from("$BOLD$Unable to change directory:$NORMAL$")),
                         error,
-                    )
+                    );
+                    succeeded = false;
                 }
             };
         }
     }
+    succeeded
 }
 
-pub fn list_command(command: ParsedCommand) {
-    let fernet = match command.contexts.get(&String::from("fernet")) {
-        Some(fernet) => match fernet {
-            Context::Fernet(fernet) => fernet,
+pub fn list_command(command: ParsedCommand) -> bool {
+    let credentials = match command.contexts.get(&String::from("credentials")) {
+        Some(credentials) => match credentials {
+            Context::Credentials(credentials) => credentials,
             _ => unreachable!(),
         },
         None => {
             println!(
-                "{} Fernet was not passed by SFS!",
+                "{} Credentials were not passed by SFS!",
                 format_colors(&String::from("$BOLD$Fatal error:$NORMAL$")),
             );
-            return;
+            return false;
+        }
+    };
+    let identity = match read_identity() {
+        Ok(identity) => identity,
+        Err(error) => {
+            println!("{}", error);
+            return false;
         }
     };
     let configuration = match command.contexts.get(&String::from("configuration")) {
@@ -483,7 +768,7 @@ pub fn list_command(command: ParsedCommand) {
                 "{} Configuration was not passed by SFS!",
                 format_colors(&String::from("$BOLD$Fatal error:$NORMAL$")),
             );
-            return;
+            return false;
         }
     };
 
@@ -493,27 +778,31 @@ pub fn list_command(command: ParsedCommand) {
     let mut decrypt_name = configuration.list_command.decrypt_name;
     let mut input_paths = Vec::new();
     for flag in command.flags {
-        if flag.name.is_some() {
-            match flag.name.unwrap().as_str() {
+        if let Some(name) = flag.name {
+            match name.as_str() {
                 "all" => display_all_files = !display_all_files,
                 "list" => list_view = !list_view,
                 "columns" => grid_columns = flag.value.unwrap().parse().unwrap_or(grid_columns),
                 "decrypt-name" => decrypt_name = !decrypt_name,
                 _ => (),
             }
-        } else if flag.value.is_some() {
-            input_paths.push(flag.value.unwrap())
+        } else if let Some(value) = flag.value {
+            input_paths.push(value)
         }
     }
     if grid_columns < 1 {
         grid_columns = 1
     }
-    if input_paths.len() == 0 {
+    if input_paths.is_empty() {
         input_paths.push(String::from("."))
     }
 
     let mut current_column = 0;
-    let termsize::Size { cols, rows: _ } = termsize::get().unwrap();
+    // Output that isn't going to a terminal (a pipe, for example) is listed one file per line
+    let cols = match termsize::get() {
+        Some(size) => size.cols,
+        None => 0,
+    };
     let padding: usize = (cols / grid_columns).into();
     if padding <= 3 {
         list_view = true;
@@ -526,32 +815,26 @@ pub fn list_command(command: ParsedCommand) {
         } else {
             let mut file_metadata: (bool, FileMetadata) = (false, FileMetadata::default());
             if decrypt_name && file_name.ends_with(".sfs") {
-                match fs::File::open(&path.path().into_os_string().into_string().unwrap()) {
-                    Ok(file) => {
-                        let mut buffer = String::new();
-                        match BufReader::new(&file).read_line(&mut buffer) {
-                            Ok(_) => {
-                                buffer = buffer.trim().to_string();
-                                match fernet.decrypt(&buffer) {
-                                    Ok(metadata_bytes) => {
-                                        match FileMetadata::parse(&metadata_bytes) {
-                                            Ok(metadata) => file_metadata = (true, metadata),
-                                            Err(_) => (),
-                                        }
-                                    }
-                                    Err(_) => (),
-                                };
-                            }
-                            Err(_) => (),
-                        };
+                let input_path = path.path().into_os_string().into_string().unwrap();
+                if let Ok(file) = fs::File::open(&input_path) {
+                    if let Ok(encrypted_file) = open_encrypted_file(
+                        &input_path,
+                        BufReader::new(&file),
+                        credentials,
+                        identity.as_ref(),
+                    ) {
+                        file_metadata = (true, encrypted_file.metadata)
                     }
-                    Err(_) => (),
                 };
             };
             if file_metadata.0 {
+                let mut decrypted_name = file_metadata.1.original_name.clone();
+                if file_metadata.1.archive {
+                    decrypted_name.push('/')
+                }
                 file_name = format_colors(&configuration.list_command.decrypted_name_format)
                     .replace("$sfs::name$", &file_name)
-                    .replace("$sfs::decrypted_name$", &file_metadata.1.original_name);
+                    .replace("$sfs::decrypted_name$", &decrypted_name);
             } else {
                 if file_name.ends_with(".sfs") {
                     file_name = format_colors(&configuration.list_command.encrypted_format)
@@ -564,7 +847,7 @@ pub fn list_command(command: ParsedCommand) {
         }
         let mut colorless_file_name = remove_colors(&file_name);
 
-        if list_view == false {
+        if !list_view {
             if current_column == &grid_columns {
                 *current_column = 0;
                 println!();
@@ -596,7 +879,38 @@ pub fn list_command(command: ParsedCommand) {
         }
     };
 
+    let mut succeeded = true;
     for (index, input_path) in input_paths.iter().enumerate() {
+        // Archives are listed like directories
+        if decrypt_name
+            && input_path.ends_with(".sfs")
+            && fs::metadata(input_path).is_ok_and(|metadata| metadata.is_file())
+        {
+            match read_archive_entries(input_path, credentials, identity.as_ref()) {
+                Ok(archive_entries) => {
+                    for archive_entry in archive_entries {
+                        let format = if archive_entry.kind == sfs::ArchiveEntryKind::Directory {
+                            &configuration.list_command.folder_format
+                        } else {
+                            &configuration.list_command.file_format
+                        };
+                        println!(
+                            "{}",
+                            format_colors(format)
+                                .replace("$sfs::name$", &format_archive_entry(&archive_entry))
+                        )
+                    }
+                }
+                Err(error) => {
+                    println!("{}", error);
+                    succeeded = false;
+                }
+            }
+            if index != input_paths.len() - 1 {
+                println!();
+            }
+            continue;
+        }
         match fs::read_dir(input_path) {
             Ok(paths) => {
                 for path in paths {
@@ -618,7 +932,8 @@ pub fn list_command(command: ParsedCommand) {
                                     input_path
                                 )),
                                 error,
-                            )
+                            );
+                            succeeded = false;
                         }
                     }
                 }
@@ -627,31 +942,54 @@ pub fn list_command(command: ParsedCommand) {
                 }
                 current_column = 0;
             }
-            Err(error) => println!(
-                "{} {:?}",
-                format_colors(&format!(
-                    "$BOLD$[{}] Unable to read directory:$NORMAL$",
-                    input_path
-                )),
-                error
-            ),
+            Err(error) => {
+                println!(
+                    "{} {:?}",
+                    format_colors(&format!(
+                        "$BOLD$[{}] Unable to read directory:$NORMAL$",
+                        input_path
+                    )),
+                    error
+                );
+                succeeded = false;
+            }
         }
         if index != input_paths.len() - 1 {
             println!();
         }
     }
+    succeeded
 }
 
-pub fn remove_command(command: ParsedCommand) {
+pub fn remove_command(command: ParsedCommand) -> bool {
+    let mut succeeded = true;
+    let mut dry_run = false;
     let mut input_paths = Vec::new();
     for flag in command.flags {
-        if flag.name.is_none() && flag.value.is_some() {
-            input_paths.push(flag.value.unwrap())
+        match (flag.name, flag.value) {
+            (Some(name), _) if name == "dry-run" => dry_run = !dry_run,
+            (None, Some(value)) => input_paths.push(value),
+            _ => (),
         }
     }
 
     for input_path in input_paths {
-        match fs::remove_file(&input_path) {
+        let result = if dry_run {
+            fs::symlink_metadata(&input_path).and_then(|metadata| {
+                // `remove_file` refuses directories, so the dry run has to as well
+                if metadata.is_dir() {
+                    return Err(is_a_directory_error());
+                }
+                println!(
+                    "{}",
+                    format_colors(&format!("$BOLD$[{}] Would remove file$NORMAL$", input_path))
+                );
+                Ok(())
+            })
+        } else {
+            fs::remove_file(&input_path)
+        };
+        match result {
             Ok(_) => (),
             Err(error) => {
                 println!(
@@ -662,82 +1000,224 @@ pub fn remove_command(command: ParsedCommand) {
                     )),
                     error
                 );
+                succeeded = false;
+                continue;
+            }
+        }
+    }
+    succeeded
+}
+
+pub fn shred_command(command: ParsedCommand) -> bool {
+    let configuration = match command.contexts.get(&String::from("configuration")) {
+        Some(configuration) => match configuration {
+            Context::Configuration(configuration) => configuration,
+            _ => unreachable!(),
+        },
+        None => {
+            eprintln!(
+                "{} Configuration was not passed by SFS!",
+                format_colors(&String::from("$BOLD$Fatal error:$NORMAL$")),
+            );
+            return false;
+        }
+    };
+
+    let mut recursive = false;
+    let mut dry_run = false;
+    let mut passes = configuration.shred_command.passes;
+    let mut input_paths = Vec::new();
+    for flag in command.flags {
+        if let Some(name) = flag.name {
+            match name.as_str() {
+                "recursive" => recursive = !recursive,
+                "dry-run" => dry_run = !dry_run,
+                "passes" => passes = flag.value.unwrap().parse().unwrap_or(passes),
+                _ => (),
+            }
+        } else if let Some(value) = flag.value {
+            input_paths.push(value)
+        }
+    }
+
+    let mut succeeded = true;
+    for input_path in input_paths {
+        let path = std::path::Path::new(&input_path);
+        if !recursive && fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir()) {
+            eprintln!(
+                "{} Use --recursive to shred directories",
+                format_colors(&format!(
+                    "$BOLD$[{}] Unable to shred file:$NORMAL$",
+                    input_path
+                )),
+            );
+            succeeded = false;
+            continue;
+        }
+        warn_copy_on_write(&input_path);
+        let result = if dry_run {
+            fs::symlink_metadata(path)
+                .map_err(sfs::Error::from)
+                .map(|metadata| {
+                    println!(
+                        "{} ({} passes)",
+                        format_colors(&format!(
+                            "$BOLD$[{}] Would shred {}$NORMAL$",
+                            input_path,
+                            if metadata.is_dir() {
+                                "directory and everything in it"
+                            } else {
+                                "file"
+                            }
+                        )),
+                        passes
+                    )
+                })
+        } else {
+            shred_path(path, passes)
+        };
+        match result {
+            Ok(_) => (),
+            Err(error) => {
+                eprintln!(
+                    "{} {}",
+                    format_colors(&format!(
+                        "$BOLD$[{}] Unable to shred file:$NORMAL$",
+                        input_path
+                    )),
+                    error
+                );
+                succeeded = false;
                 continue;
             }
         }
     }
+    succeeded
 }
 
-pub fn copy_command(command: ParsedCommand) {
+pub fn copy_command(command: ParsedCommand) -> bool {
+    let mut dry_run = false;
     let mut input_paths = Vec::new();
     for flag in command.flags {
-        if flag.name.is_none() && flag.value.is_some() {
-            input_paths.push(flag.value.unwrap())
+        match (flag.name, flag.value) {
+            (Some(name), _) if name == "dry-run" => dry_run = !dry_run,
+            (None, Some(value)) => input_paths.push(value),
+            _ => (),
         }
     }
     if input_paths.len() <= 1 {
         println!("Not enough arguments!");
-        return;
+        return false;
+    }
+    if dry_run {
+        return match fs::symlink_metadata(input_paths.first().unwrap()) {
+            Ok(_) => {
+                print_dry_run(
+                    input_paths.first().unwrap(),
+                    "copy",
+                    input_paths.iter().last().unwrap(),
+                    true,
+                    None,
+                );
+                true
+            }
+            Err(error) => {
+                println!(
+                    "{} {:?}",
+                    format_colors(&String::from("$BOLD$Unable to copy file:$NORMAL$")),
+                    error
+                );
+                false
+            }
+        };
     }
 
     match fs::copy(
-        input_paths.iter().nth(0).unwrap(),
+        input_paths.first().unwrap(),
         input_paths.iter().last().unwrap(),
     ) {
-        Ok(_) => (),
+        Ok(_) => true,
         Err(error) => {
             println!(
                 "{} {:?}",
                 format_colors(&String::from("$BOLD$Unable to copy file:$NORMAL$")),
                 error
             );
+            false
         }
     }
 }
 
-pub fn move_command(command: ParsedCommand) {
+pub fn move_command(command: ParsedCommand) -> bool {
+    let mut dry_run = false;
     let mut input_paths = Vec::new();
     for flag in command.flags {
-        if flag.name.is_none() && flag.value.is_some() {
-            input_paths.push(flag.value.unwrap())
+        match (flag.name, flag.value) {
+            (Some(name), _) if name == "dry-run" => dry_run = !dry_run,
+            (None, Some(value)) => input_paths.push(value),
+            _ => (),
         }
     }
     if input_paths.len() <= 1 {
         println!("Not enough arguments!");
-        return;
+        return false;
+    }
+    if dry_run {
+        return match fs::symlink_metadata(input_paths.first().unwrap()) {
+            Ok(_) => {
+                print_dry_run(
+                    input_paths.first().unwrap(),
+                    "move",
+                    input_paths.iter().last().unwrap(),
+                    true,
+                    None,
+                );
+                true
+            }
+            Err(error) => {
+                println!(
+                    "{} {:?}",
+                    format_colors(&String::from("$BOLD$Unable to move file:$NORMAL$")),
+                    error
+                );
+                false
+            }
+        };
     }
 
     match fs::rename(
-        input_paths.iter().nth(0).unwrap(),
+        input_paths.first().unwrap(),
         input_paths.iter().last().unwrap(),
     ) {
-        Ok(_) => (),
+        Ok(_) => true,
         Err(error) => {
             println!(
                 "{} {:?}",
                 format_colors(&String::from("$BOLD$Unable to move file:$NORMAL$")),
                 error
             );
+            false
         }
     }
 }
 
-pub fn clear_command(_command: ParsedCommand) {
-    print!("\u{001b}[2J\u{001b}[H")
+pub fn clear_command(_command: ParsedCommand) -> bool {
+    print!("\u{001b}[2J\u{001b}[H");
+    true
 }
 
-pub fn encrypt_command(command: ParsedCommand) {
-    let fernet = match command.contexts.get(&String::from("fernet")) {
-        Some(fernet) => match fernet {
-            Context::Fernet(fernet) => fernet,
+pub fn encrypt_command(command: ParsedCommand) -> bool {
+    let credentials = match command.contexts.get(&String::from("credentials")) {
+        Some(credentials) => match credentials {
+            Context::Credentials(credentials) => credentials,
             _ => unreachable!(),
         },
         None => {
-            println!(
-                "{} Fernet was not passed by SFS!",
+            eprintln!(
+                "{} Credentials were not passed by SFS!",
                 format_colors(&String::from("$BOLD$Fatal error:$NORMAL$")),
             );
-            return;
+            return false;
         }
     };
     let configuration = match command.contexts.get(&String::from("configuration")) {
@@ -746,11 +1226,11 @@ pub fn encrypt_command(command: ParsedCommand) {
             _ => unreachable!(),
         },
         None => {
-            println!(
+            eprintln!(
                 "{} Configuration was not passed by SFS!",
                 format_colors(&String::from("$BOLD$Fatal error:$NORMAL$")),
             );
-            return;
+            return false;
         }
     };
 
@@ -761,10 +1241,23 @@ pub fn encrypt_command(command: ParsedCommand) {
     let mut input_hashing_algorithm = configuration.encrypt_command.hashing_algorithm.clone();
     let mut chunk_size = configuration.encrypt_command.chunk_size;
     let mut assign_random_name = configuration.encrypt_command.assign_random_name;
+    let mut input_cipher = configuration.encrypt_command.cipher.clone();
+    let mut input_compression = configuration.encrypt_command.compression.clone();
+    let mut compression_level = configuration.encrypt_command.compression_level;
+    let mut input_padding = configuration.encrypt_command.padding.clone();
+    let mut verify = configuration.encrypt_command.verify;
+    let mut secure_delete = configuration.encrypt_command.secure_delete;
+    let mut dry_run = false;
+    let mut archive = false;
+    let mut input_recipients = Vec::new();
+    let key_derivation_memory_cost = configuration.encrypt_command.key_derivation_memory_cost;
+    let key_derivation_time_cost = configuration.encrypt_command.key_derivation_time_cost;
+    let key_derivation_parallelism = configuration.encrypt_command.key_derivation_parallelism;
     let mut raw_input_paths = Vec::new();
+    let parsed_flags = command.flags.clone();
     for flag in command.flags {
-        if flag.name.is_some() {
-            match flag.name.unwrap().as_str() {
+        if let Some(name) = flag.name {
+            match name.as_str() {
                 "recursive" => recursive = !recursive,
                 "silent" => silent = !silent,
                 "overwrite" => overwrite = !overwrite,
@@ -772,20 +1265,37 @@ pub fn encrypt_command(command: ParsedCommand) {
                 "hashing-algorithm" => input_hashing_algorithm = flag.value.unwrap().to_owned(),
                 "chunk-size" => chunk_size = flag.value.unwrap().parse().unwrap_or(chunk_size),
                 "assign-random-name" => assign_random_name = !assign_random_name,
+                "cipher" => input_cipher = flag.value.unwrap().to_owned(),
+                "compression" => input_compression = flag.value.unwrap().to_owned(),
+                "compression-level" => {
+                    compression_level = flag.value.unwrap().parse().unwrap_or(compression_level)
+                }
+                "padding" => input_padding = flag.value.unwrap().to_owned(),
+                "archive" => archive = !archive,
+                "verify" => verify = !verify,
+                "secure-delete" => secure_delete = !secure_delete,
+                "dry-run" => dry_run = !dry_run,
+                "recipient" => input_recipients.push(flag.value.unwrap().to_owned()),
                 _ => (),
             }
-        } else if flag.value.is_some() {
-            raw_input_paths.push(flag.value.unwrap())
+        } else if let Some(value) = flag.value {
+            raw_input_paths.push(value)
         }
     }
+    let mut succeeded = true;
     let mut input_paths = Vec::new();
-    if recursive {
+    // Archives already contain everything in the directories
+    if recursive && !archive {
         for input_path in &raw_input_paths {
-            for entry in WalkDir::new(&input_path) {
+            if input_path == "-" {
+                input_paths.push(input_path.to_owned());
+                continue;
+            }
+            for entry in WalkDir::new(input_path) {
                 let path = match entry {
                     Ok(entry) => entry.path().to_owned(),
                     Err(error) => {
-                        println!(
+                        eprintln!(
                             "{} {:?}",
                             format_colors(&format!(
                                 "$BOLD$[{}] Unable to get file information:$NORMAL$",
@@ -793,6 +1303,7 @@ pub fn encrypt_command(command: ParsedCommand) {
                             )),
                             error
                         );
+                        succeeded = false;
                         continue;
                     }
                 };
@@ -809,117 +1320,223 @@ pub fn encrypt_command(command: ParsedCommand) {
         "xxh3" => HashingAlgorithm::Xxh3,
         "xxh64" => HashingAlgorithm::Xxh64,
         "xxh32" => HashingAlgorithm::Xxh32,
+        "blake3" => HashingAlgorithm::Blake3,
+        "sha256" => HashingAlgorithm::Sha256,
         _ => {
-            println!("{}", format_colors(&format!("Unknown hashing algorithm $BOLD$`{}`$NORMAL$, defaulting to $BOLD$`None`$NORMAL$!", input_hashing_algorithm)));
+            eprintln!("{}", format_colors(&format!("Unknown hashing algorithm $BOLD$`{}`$NORMAL$, defaulting to $BOLD$`None`$NORMAL$!", input_hashing_algorithm)));
             HashingAlgorithm::None
         }
     };
-
-    'input_loop: for input_path in input_paths {
-        let input_file = match fs::File::open(&input_path) {
-            Ok(file) => file,
+    let cipher = match input_cipher.to_lowercase().as_str() {
+        "aes256gcm" => Cipher::Aes256Gcm,
+        "xchacha20poly1305" => Cipher::XChaCha20Poly1305,
+        _ => {
+            eprintln!("{}", format_colors(&format!("Unknown cipher $BOLD$`{}`$NORMAL$, defaulting to $BOLD$`XChaCha20Poly1305`$NORMAL$!", input_cipher)));
+            Cipher::XChaCha20Poly1305
+        }
+    };
+    let compression = match input_compression.to_lowercase().as_str() {
+        "none" => Compression::None,
+        "zstd" => Compression::Zstd,
+        "deflate" => Compression::Deflate,
+        _ => {
+            eprintln!(
+                "{}",
+                format_colors(&format!(
+                    "Unknown compression $BOLD$`{}`$NORMAL$, defaulting to $BOLD$`None`$NORMAL$!",
+                    input_compression
+                ))
+            );
+            Compression::None
+        }
+    };
+    let lowercase_padding = input_padding.to_lowercase();
+    let padding = match lowercase_padding.split_once(':') {
+        None if lowercase_padding == "none" => Padding::None,
+        None if lowercase_padding == "power-of-two" => Padding::PowerOfTwo,
+        Some(("bucket", bucket_size)) if bucket_size.parse::<u64>().is_ok() => {
+            Padding::Bucket(bucket_size.parse().unwrap())
+        }
+        Some(("random", percentage)) if percentage.parse::<u64>().is_ok() => {
+            match percentage.parse().unwrap() {
+                percentage @ 1..=100 => Padding::Random(percentage),
+                _ => {
+                    eprintln!(
+                        "{}",
+                        format_colors(&format!(
+                            "$BOLD$Invalid padding:$NORMAL$ The percentage of $BOLD$`{}`$NORMAL$ has to be between 1 and 100",
+                            input_padding
+                        ))
+                    );
+                    return false;
+                }
+            }
+        }
+        _ => {
+            eprintln!(
+                "{}",
+                format_colors(&format!(
+                    "Unknown padding $BOLD$`{}`$NORMAL$, defaulting to $BOLD$`None`$NORMAL$!",
+                    input_padding
+                ))
+            );
+            Padding::None
+        }
+    };
+    let mut recipients = Vec::new();
+    if !input_recipients.is_empty() {
+        let known_recipients = match read_recipients() {
+            Ok(known_recipients) => known_recipients,
             Err(error) => {
-                println!(
-                    "{} {:?}",
-                    format_colors(&format!(
-                        "$BOLD$[{}] Unable to open file:$NORMAL$",
-                        input_path
-                    )),
-                    error
-                );
-                continue;
+                eprintln!("{}", error);
+                return false;
             }
         };
-        let original_name = std::path::Path::new(&input_path)
-            .file_name()
-            .unwrap()
-            .to_os_string()
-            .into_string()
-            .unwrap();
-
-        let mut buffered_reader = BufReader::new(&input_file);
-        let output_path = if assign_random_name {
-            std::path::Path::new(&input_path)
-                .parent()
-                .unwrap()
-                .join(
-                    rand::thread_rng()
-                        .sample_iter(&Alphanumeric)
-                        .take(32)
-                        .map(char::from)
-                        .collect::<String>(),
-                )
-                .to_str()
-                .unwrap()
-                .to_string()
-        } else {
-            input_path.to_string()
-        } + ".sfs";
-        if !overwrite {
-            if fs::metadata(&output_path).is_ok() {
-                let mut input = String::new();
-                loop {
-                    if input.to_lowercase().starts_with("n") {
-                        continue 'input_loop;
-                    } else if input.to_lowercase().starts_with("y") {
-                        break;
-                    } else {
-                        print!(
-                        "{}",
-                        format_colors(&format!("$BOLD${}$NORMAL$ already exists. Do you want to overwrite it? $BOLD$Y/N:$NORMAL$ ", output_path))
+        for input_recipient in input_recipients {
+            let public_key = match known_recipients.get(&input_recipient) {
+                Some(public_key) => public_key,
+                None => &input_recipient,
+            };
+            match sfs::parse_public_key(public_key) {
+                Ok(public_key) => recipients.push(public_key),
+                Err(error) => {
+                    eprintln!(
+                        "{} {}",
+                        format_colors(&format!(
+                            "$BOLD$Unknown recipient `{}`:$NORMAL$",
+                            input_recipient
+                        )),
+                        error
                     );
-                        std::io::stdout().flush().unwrap();
-                        input.clear();
-                        match std::io::stdin().read_line(&mut input) {
-                            Ok(_) => (),
-                            Err(error) => {
-                                println!(
-                                    "{} {:?}",
-                                    format_colors(&String::from(
-                                        "$BOLD$Unable to read input:$NORMAL$"
-                                    )),
-                                    error
-                                );
-                                std::process::exit(1)
-                            }
-                        }
-                    }
+                    return false;
                 }
             }
         }
-        let mut output_file = match fs::File::create(&output_path) {
-            Ok(file) => file,
+    }
+    // Files that are only encrypted for recipients can't be verified with the
+    // credentials, but they can be with the identity if it's one of them
+    let identity = if recipients.is_empty() {
+        None
+    } else {
+        match read_identity() {
+            Ok(identity) => identity,
             Err(error) => {
-                println!(
-                    "{} {:?}",
-                    format_colors(&format!(
-                        "$BOLD$[{}] Unable to create output file:$NORMAL$",
-                        output_path
-                    )),
-                    error
-                );
-                continue;
+                eprintln!("{}", error);
+                return false;
             }
-        };
+        }
+    };
 
-        let mut encrypter = Encrypter::new(fernet.to_owned(), hashing_algorithm);
-        let mut buffer = vec![0; chunk_size as usize];
-        let file_size = match input_file.metadata() {
-            Ok(metadata) => metadata.len(),
+    // Recursive runs keep track of every file, so that they can be resumed
+    let mut journal = if recursive && !dry_run {
+        match JournalWriter::create("encrypt", &parsed_flags, &input_paths) {
+            Ok(journal) => Some(journal),
             Err(error) => {
-                println!(
-                    "{} {:?}",
-                    format_colors(&format!(
-                        "$BOLD$[{}] Unable to get file metadata:$NORMAL$",
-                        input_path
-                    )),
-                    error
-                );
-                continue 'input_loop;
+                eprintln!("{}", error);
+                return false;
             }
-        };
-        let progress_bar = ProgressBar::new(file_size);
-        let progress_bar_format = format_colors(
+        }
+    } else {
+        None
+    };
+    let mut stopped = false;
+    'input_loop: for input_path in input_paths {
+        if interrupted() {
+            eprintln!(
+                "{} Stopping before {}",
+                format_colors(&String::from("$BOLD$Interrupted:$NORMAL$")),
+                input_path
+            );
+            succeeded = false;
+            stopped = true;
+            break;
+        }
+        // `-` reads from stdin and writes to stdout
+        let streaming = input_path == "-";
+        // Files are still encrypted on their own
+        let archive = archive && fs::metadata(&input_path).is_ok_and(|metadata| metadata.is_dir());
+        let output_path = if streaming {
+            String::from("-")
+        } else {
+            // `directory/` should be archived into `directory.sfs`, not `directory/.sfs`
+            let input_path = std::path::Path::new(&input_path)
+                .components()
+                .collect::<std::path::PathBuf>();
+            sfs::encrypted_path(&input_path, assign_random_name)
+                .display()
+                .to_string()
+        };
+        if dry_run {
+            let output_path = if assign_random_name && !streaming {
+                std::path::Path::new(&output_path)
+                    .with_file_name("(random name).sfs")
+                    .display()
+                    .to_string()
+            } else {
+                output_path
+            };
+            print_dry_run(
+                &input_path,
+                if archive { "archive" } else { "encrypt" },
+                &output_path,
+                overwrite,
+                match (keep_file || streaming, secure_delete) {
+                    (true, _) => None,
+                    (false, true) => Some("shredding the original"),
+                    (false, false) => Some("removing the original"),
+                },
+            );
+            continue;
+        }
+        if !streaming && !overwrite && fs::metadata(&output_path).is_ok() {
+            let mut input = String::new();
+            loop {
+                if input.to_lowercase().starts_with("n") {
+                    continue 'input_loop;
+                } else if input.to_lowercase().starts_with("y") {
+                    break;
+                } else {
+                    print!(
+                    "{}",
+                    format_colors(&format!("$BOLD${}$NORMAL$ already exists. Do you want to overwrite it? $BOLD$Y/N:$NORMAL$ ", output_path))
+                );
+                    std::io::stdout().flush().unwrap();
+                    input.clear();
+                    match std::io::stdin().read_line(&mut input) {
+                        // There's nobody to answer (when running from a script, for example)
+                        Ok(0) => {
+                            eprintln!();
+                            succeeded = false;
+                            continue 'input_loop;
+                        }
+                        Ok(_) => (),
+                        Err(error) => {
+                            eprintln!(
+                                "{} {:?}",
+                                format_colors(&String::from("$BOLD$Unable to read input:$NORMAL$")),
+                                error
+                            );
+                            std::process::exit(1)
+                        }
+                    }
+                }
+            }
+        }
+        if !streaming
+            && !log_state(
+                &mut journal,
+                JournalState::Started,
+                &input_path,
+                &output_path,
+            )
+        {
+            succeeded = false;
+            stopped = true;
+            break;
+        }
+
+        let progress_bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
+        let progress_bar_format = format_colors(
             &configuration
                 .encrypt_command
                 .progress_bar_format
@@ -936,111 +1553,141 @@ pub fn encrypt_command(command: ParsedCommand) {
                 )
                 .progress_chars("#>-"),
         );
+        let report_progress = |progress: sfs::Progress| {
+            if let Some(total_bytes) = progress.total_bytes {
+                progress_bar.set_length(total_bytes)
+            }
+            progress_bar.set_position(progress.processed_bytes)
+        };
 
-        let encrypted_size = determine_encrypted_size(FileMetadata::default().pack().len());
-        match output_file.write(&vec![Default::default(); encrypted_size]) {
+        let options = sfs::EncryptOptions {
+            recipients: recipients.clone(),
+            cipher,
+            hashing_algorithm,
+            chunk_size,
+            compression,
+            compression_level,
+            padding,
+            key_derivation_memory_cost,
+            key_derivation_time_cost,
+            key_derivation_parallelism,
+            restore_name: assign_random_name,
+            // Only needed if the original file is removed afterwards
+            verify: verify && !keep_file,
+            identity: identity.as_ref(),
+            progress: if silent { None } else { Some(&report_progress) },
+            ..sfs::EncryptOptions::new(credentials)
+        };
+        let result = if streaming {
+            sfs::encrypt(
+                std::io::stdin(),
+                BufWriter::new(std::io::stdout().lock()),
+                &options,
+            )
+        } else if archive {
+            sfs::encrypt_archive(
+                std::path::Path::new(&input_path),
+                std::path::Path::new(&output_path),
+                &options,
+            )
+        } else {
+            sfs::encrypt_file(
+                std::path::Path::new(&input_path),
+                std::path::Path::new(&output_path),
+                &options,
+            )
+        };
+        match result {
             Ok(_) => (),
-            Err(error) => {
-                println!(
-                    "{} {:?}",
+            Err(error @ (sfs::Error::KeyDerivationFailed(_) | sfs::Error::MissingCredentials)) => {
+                eprintln!(
+                    "{} {}",
                     format_colors(&format!(
-                        "$BOLD$[{}] Unable to write metadata padding:$NORMAL$",
-                        output_path
+                        "$BOLD$[{}] Unable to derive key:$NORMAL$",
+                        input_path
                     )),
                     error
                 );
-                continue 'input_loop;
-            }
-        }
-        loop {
-            let read = match buffered_reader.read(&mut buffer) {
-                Ok(read) => read,
-                Err(error) => {
-                    println!(
-                        "{} {:?}",
-                        format_colors(&format!(
-                            "$BOLD$[{}] Unable to read chunk:$NORMAL$",
-                            input_path
-                        )),
-                        error
-                    );
-                    continue 'input_loop;
-                }
-            };
-            if read == 0 {
-                break;
-            }
-
-            let mut encrypted = "\n".as_bytes().to_vec();
-            encrypted.append(&mut encrypter.encrypt(&buffer[..read]).into_bytes());
-            match output_file.write(&encrypted) {
-                Ok(_) => (),
-                Err(error) => {
-                    println!(
-                        "{} {:?}",
-                        format_colors(&format!(
-                            "$BOLD$[{}] Unable to write chunk:$NORMAL$",
-                            output_path
-                        )),
-                        error
-                    );
-                    continue 'input_loop;
-                }
-            }
-
-            if !silent {
-                progress_bar.set_position(encrypter.total_bytes)
+                succeeded = false;
+                continue;
             }
-        }
-        match output_file.seek(std::io::SeekFrom::Start(0)) {
-            Ok(_) => (),
-            Err(error) => {
-                println!(
-                    "{} {:?}",
+            Err(sfs::Error::ChecksumMismatch { expected, actual }) => {
+                eprintln!(
+                    "{} The encrypted file does not match the checksum (expected `{}` but got `{}`)",
                     format_colors(&format!(
-                        "$BOLD$[{}] Unable to seek file:$NORMAL$",
-                        output_path
+                        "$BOLD$[{}] Unable to verify encrypted file:$NORMAL$",
+                        input_path
                     )),
-                    error
+                    format_checksum(&expected),
+                    format_checksum(&actual)
                 );
-                continue 'input_loop;
+                succeeded = false;
+                continue;
             }
-        };
-        match output_file.write(
-            &fernet
-                .encrypt(
-                    &FileMetadata {
-                        format_version: sfs::SFS_FORMAT_VERSION,
-                        original_name,
-                        restore_name: assign_random_name,
-                        total_bytes: encrypter.total_bytes,
-                        hashing_algorithm: hashing_algorithm as u8,
-                        checksum: encrypter.get_checksum(),
-                        chunk_size,
-                    }
-                    .pack(),
-                )
-                .into_bytes(),
-        ) {
-            Ok(_) => (),
             Err(error) => {
-                println!(
-                    "{} {:?}",
+                eprintln!(
+                    "{} {}",
                     format_colors(&format!(
-                        "$BOLD$[{}] Unable to write metadata:$NORMAL$",
-                        output_path
+                        "$BOLD$[{}] Unable to encrypt file:$NORMAL$",
+                        input_path
                     )),
                     error
                 );
-                continue 'input_loop;
+                succeeded = false;
+                continue;
             }
         }
 
-        if !keep_file {
-            match fs::remove_file(&input_path) {
+        // Rolling back has to know if the input can still be trusted
+        if !keep_file
+            && !streaming
+            && !log_state(
+                &mut journal,
+                if secure_delete {
+                    JournalState::Shredding
+                } else {
+                    JournalState::Written
+                },
+                &input_path,
+                &output_path,
+            )
+        {
+            succeeded = false;
+            stopped = true;
+            break;
+        }
+        if !keep_file && !streaming && secure_delete {
+            if !silent {
+                warn_copy_on_write(&input_path);
+            }
+            match shred_path(
+                std::path::Path::new(&input_path),
+                configuration.shred_command.passes,
+            ) {
                 Ok(_) => (),
                 Err(error) => {
-                    println!(
+                    eprintln!(
+                        "{} {}",
+                        format_colors(&format!(
+                            "$BOLD$[{}] Unable to shred file:$NORMAL$",
+                            input_path
+                        )),
+                        error
+                    );
+                    succeeded = false;
+                    continue;
+                }
+            }
+        } else if !keep_file && !streaming {
+            let result = if archive {
+                remove_directory(std::path::Path::new(&input_path))
+            } else {
+                fs::remove_file(&input_path)
+            };
+            match result {
+                Ok(_) => (),
+                Err(error) => {
+                    eprintln!(
                         "{} {:?}",
                         format_colors(&format!(
                             "$BOLD$[{}] Unable to remove file:$NORMAL$",
@@ -1048,27 +1695,42 @@ pub fn encrypt_command(command: ParsedCommand) {
                         )),
                         error
                     );
-                    continue 'input_loop;
+                    succeeded = false;
+                    continue;
                 }
             }
         }
 
-        println!()
+        if !streaming && !log_state(&mut journal, JournalState::Done, &input_path, &output_path) {
+            succeeded = false;
+            stopped = true;
+            break;
+        }
+
+        eprintln!()
     }
+    finish_journal(journal, stopped) && succeeded
 }
 
-pub fn decrypt_command(command: ParsedCommand) {
-    let fernet = match command.contexts.get(&String::from("fernet")) {
-        Some(fernet) => match fernet {
-            Context::Fernet(fernet) => fernet,
+pub fn decrypt_command(command: ParsedCommand) -> bool {
+    let credentials = match command.contexts.get(&String::from("credentials")) {
+        Some(credentials) => match credentials {
+            Context::Credentials(credentials) => credentials,
             _ => unreachable!(),
         },
         None => {
-            println!(
-                "{} Fernet was not passed by SFS!",
+            eprintln!(
+                "{} Credentials were not passed by SFS!",
                 format_colors(&String::from("$BOLD$Fatal error:$NORMAL$")),
             );
-            return;
+            return false;
+        }
+    };
+    let identity = match read_identity() {
+        Ok(identity) => identity,
+        Err(error) => {
+            eprintln!("{}", error);
+            return false;
         }
     };
     let configuration = match command.contexts.get(&String::from("configuration")) {
@@ -1077,11 +1739,11 @@ pub fn decrypt_command(command: ParsedCommand) {
             _ => unreachable!(),
         },
         None => {
-            println!(
+            eprintln!(
                 "{} Configuration was not passed by SFS!",
                 format_colors(&String::from("$BOLD$Fatal error:$NORMAL$")),
             );
-            return;
+            return false;
         }
     };
 
@@ -1091,32 +1753,42 @@ pub fn decrypt_command(command: ParsedCommand) {
     let mut keep_file = configuration.decrypt_command.keep_file;
     let mut use_original_name = configuration.decrypt_command.use_original_name;
     let mut no_verify_chunks = configuration.decrypt_command.no_verify_chunks;
+    let mut verify = configuration.decrypt_command.verify;
     let mut force = false;
+    let mut dry_run = false;
     let mut raw_input_paths = Vec::new();
+    let parsed_flags = command.flags.clone();
     for flag in command.flags {
-        if flag.name.is_some() {
-            match flag.name.unwrap().as_str() {
+        if let Some(name) = flag.name {
+            match name.as_str() {
                 "recursive" => recursive = !recursive,
                 "silent" => silent = !silent,
                 "overwrite" => overwrite = !overwrite,
                 "keep-file" => keep_file = !keep_file,
                 "use-original-name" => use_original_name = !use_original_name,
                 "no-verify-chunks" => no_verify_chunks = !no_verify_chunks,
+                "verify" => verify = !verify,
                 "force" => force = !force,
+                "dry-run" => dry_run = !dry_run,
                 _ => (),
             }
-        } else if flag.value.is_some() {
-            raw_input_paths.push(flag.value.unwrap())
+        } else if let Some(value) = flag.value {
+            raw_input_paths.push(value)
         }
     }
+    let mut succeeded = true;
     let mut input_paths = Vec::new();
     if recursive {
         for input_path in &raw_input_paths {
-            for entry in WalkDir::new(&input_path) {
+            if input_path == "-" {
+                input_paths.push(input_path.to_owned());
+                continue;
+            }
+            for entry in WalkDir::new(input_path) {
                 let path = match entry {
                     Ok(entry) => entry.path().to_owned(),
                     Err(error) => {
-                        println!(
+                        eprintln!(
                             "{} {:?}",
                             format_colors(&format!(
                                 "$BOLD$[{}] Unable to get file information:$NORMAL$",
@@ -1124,6 +1796,7 @@ pub fn decrypt_command(command: ParsedCommand) {
                             )),
                             error
                         );
+                        succeeded = false;
                         continue;
                     }
                 };
@@ -1136,10 +1809,35 @@ pub fn decrypt_command(command: ParsedCommand) {
         input_paths = raw_input_paths
     }
 
+    // Recursive runs keep track of every file, so that they can be resumed
+    let mut journal = if recursive && !dry_run {
+        match JournalWriter::create("decrypt", &parsed_flags, &input_paths) {
+            Ok(journal) => Some(journal),
+            Err(error) => {
+                eprintln!("{}", error);
+                return false;
+            }
+        }
+    } else {
+        None
+    };
+    let mut stopped = false;
     'input_loop: for input_path in input_paths {
-        if !input_path.ends_with(".sfs") {
-            if !silent {
-                println!(
+        if interrupted() {
+            eprintln!(
+                "{} Stopping before {}",
+                format_colors(&String::from("$BOLD$Interrupted:$NORMAL$")),
+                input_path
+            );
+            succeeded = false;
+            stopped = true;
+            break;
+        }
+        // `-` reads from stdin and writes to stdout
+        let streaming = input_path == "-";
+        if !streaming && !input_path.ends_with(".sfs") {
+            if !silent || dry_run {
+                eprintln!(
                     "{}",
                     format_colors(&format!(
                         "$BOLD$[{}] Warning - Ignoring file:$NORMAL$ File does not end with .sfs",
@@ -1150,154 +1848,146 @@ pub fn decrypt_command(command: ParsedCommand) {
             continue;
         }
 
-        let input_file = match fs::File::open(&input_path) {
-            Ok(file) => file,
-            Err(error) => {
-                println!(
-                    "{} {:?}",
-                    format_colors(&format!(
-                        "$BOLD$[{}] Unable to open file:$NORMAL$",
-                        input_path
-                    )),
-                    error
-                );
-                continue;
-            }
-        };
-        let mut buffered_reader = BufReader::new(&input_file);
-
-        let mut metadata_buffer = String::new();
-        match buffered_reader.read_line(&mut metadata_buffer) {
-            Ok(_) => metadata_buffer = metadata_buffer.trim().to_string(),
-            Err(error) => {
-                println!(
-                    "{} {:?}",
-                    format_colors(&format!(
-                        "$BOLD$[{}] Unable to read metadata:$NORMAL$",
-                        input_path
-                    )),
-                    error
-                );
-                continue;
+        let encrypted_file = if streaming {
+            match open_encrypted_file(
+                &input_path,
+                std::io::stdin().lock(),
+                credentials,
+                identity.as_ref(),
+            ) {
+                Ok(encrypted_file) => encrypted_file.boxed(),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    succeeded = false;
+                    continue;
+                }
             }
-        }
-        let metadata_bytes = match fernet.decrypt(&metadata_buffer) {
-            Ok(metadata_bytes) => metadata_bytes,
-            Err(error) => {
-                println!(
-                    "{} {:?} (possibly incorrect password?)",
-                    format_colors(&format!(
-                        "$BOLD$[{}] Unable to decrypt metadata:$NORMAL$",
-                        input_path
-                    )),
-                    error
-                );
-                continue;
+        } else {
+            let input_file = match fs::File::open(&input_path) {
+                Ok(file) => file,
+                Err(error) => {
+                    eprintln!(
+                        "{} {:?}",
+                        format_colors(&format!(
+                            "$BOLD$[{}] Unable to open file:$NORMAL$",
+                            input_path
+                        )),
+                        error
+                    );
+                    succeeded = false;
+                    continue;
+                }
+            };
+            match read_metadata(
+                &input_path,
+                BufReader::new(input_file),
+                credentials,
+                identity.as_ref(),
+            ) {
+                Ok(encrypted_file) => encrypted_file.boxed(),
+                Err(error) => {
+                    eprintln!("{}", error);
+                    succeeded = false;
+                    continue;
+                }
             }
         };
-        let metadata = match FileMetadata::parse(&metadata_bytes) {
-            Ok(metadata) => metadata,
-            Err(error) => {
-                println!(
-                    "{} {:?}",
+        let metadata = &encrypted_file.metadata;
+
+        if !force
+            && (metadata.format_version < sfs::SFS_OLDEST_FORMAT_VERSION
+                || metadata.format_version > sfs::SFS_FORMAT_VERSION)
+        {
+            if !silent || dry_run {
+                eprintln!(
+                    "{}",
                     format_colors(&format!(
-                        "$BOLD$[{}] Unable to unpack metadata:$NORMAL$",
+                        "$BOLD$[{}] Ignoring file:$NORMAL$ File format version is not supported",
                         input_path
                     )),
-                    error
                 );
-                continue;
-            }
-        };
-
-        if !force {
-            if metadata.format_version != sfs::SFS_FORMAT_VERSION {
-                if !silent {
-                    println!(
-                        "{}",
-                        format_colors(&format!(
-                            "$BOLD$[{}] Ignoring file:$NORMAL$ File format version does not match",
-                            input_path
-                        )),
-                    );
-                }
-                continue;
             }
+            continue;
         }
 
-        let mut temporary_use_original_name = use_original_name;
-        if metadata.restore_name {
-            temporary_use_original_name = !temporary_use_original_name
-        }
-        let output_path = if temporary_use_original_name {
-            std::path::Path::new(&input_path)
-                .parent()
-                .unwrap()
-                .join(metadata.original_name)
-                .to_str()
-                .unwrap()
-                .to_string()
+        let output_path = if streaming {
+            String::from("-")
         } else {
-            match input_path.to_string().strip_suffix(".sfs") {
-                Some(path) => path.to_string(),
-                None => unreachable!(),
-            }
+            sfs::decrypted_path(
+                std::path::Path::new(&input_path),
+                metadata,
+                use_original_name,
+            )
+            .display()
+            .to_string()
         };
-        if !overwrite {
-            if fs::metadata(&output_path).is_ok() {
-                let mut input = String::new();
-                loop {
-                    if input.to_lowercase().starts_with("n") {
-                        continue 'input_loop;
-                    } else if input.to_lowercase().starts_with("y") {
-                        break;
-                    } else {
-                        print!(
-                        "{}",
-                        format_colors(&format!("$BOLD${}$NORMAL$ already exists. Do you want to overwrite it? $BOLD$Y/N:$NORMAL$ ", output_path))
-                    );
-                        std::io::stdout().flush().unwrap();
-                        input.clear();
-                        match std::io::stdin().read_line(&mut input) {
-                            Ok(_) => (),
-                            Err(error) => {
-                                println!(
-                                    "{} {:?}",
-                                    format_colors(&String::from(
-                                        "$BOLD$Unable to read input:$NORMAL$"
-                                    )),
-                                    error
-                                );
-                                std::process::exit(1)
-                            }
+        if dry_run {
+            print_dry_run(
+                &input_path,
+                if metadata.archive && !streaming {
+                    "extract"
+                } else {
+                    "decrypt"
+                },
+                &output_path,
+                overwrite,
+                if keep_file || streaming || (verify && no_verify_chunks) {
+                    None
+                } else {
+                    Some("removing the encrypted file")
+                },
+            );
+            continue;
+        }
+        if !streaming && !overwrite && fs::metadata(&output_path).is_ok() {
+            let mut input = String::new();
+            loop {
+                if input.to_lowercase().starts_with("n") {
+                    continue 'input_loop;
+                } else if input.to_lowercase().starts_with("y") {
+                    break;
+                } else {
+                    print!(
+                    "{}",
+                    format_colors(&format!("$BOLD${}$NORMAL$ already exists. Do you want to overwrite it? $BOLD$Y/N:$NORMAL$ ", output_path))
+                );
+                    std::io::stdout().flush().unwrap();
+                    input.clear();
+                    match std::io::stdin().read_line(&mut input) {
+                        // There's nobody to answer (when running from a script, for example)
+                        Ok(0) => {
+                            eprintln!();
+                            succeeded = false;
+                            continue 'input_loop;
+                        }
+                        Ok(_) => (),
+                        Err(error) => {
+                            eprintln!(
+                                "{} {:?}",
+                                format_colors(&String::from("$BOLD$Unable to read input:$NORMAL$")),
+                                error
+                            );
+                            std::process::exit(1)
                         }
                     }
                 }
             }
         }
-        let mut output_file = match fs::File::create(&output_path) {
-            Ok(file) => file,
-            Err(error) => {
-                println!(
-                    "{} {:?}",
-                    format_colors(&format!(
-                        "$BOLD$[{}] Unable to create output file:$NORMAL$",
-                        output_path
-                    )),
-                    error
-                );
-                continue;
-            }
-        };
+        let archive = metadata.archive;
+        if !streaming
+            && !log_state(
+                &mut journal,
+                JournalState::Started,
+                &input_path,
+                &output_path,
+            )
+        {
+            succeeded = false;
+            stopped = true;
+            break;
+        }
 
-        let mut line_buffer = String::new();
-        let hashing_algorithm = if no_verify_chunks {
-            HashingAlgorithm::None
-        } else {
-            HashingAlgorithm::from_u8(metadata.hashing_algorithm)
-        };
-        let mut decrypter = Decrypter::new(fernet.to_owned(), hashing_algorithm);
-        let progress_bar = ProgressBar::new(metadata.total_bytes);
+        let progress_bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
         let progress_bar_format = format_colors(
             &configuration
                 .encrypt_command
@@ -1315,73 +2005,87 @@ pub fn decrypt_command(command: ParsedCommand) {
                 )
                 .progress_chars("#>-"),
         );
-
-        loop {
-            let read = match buffered_reader.read_line(&mut line_buffer) {
-                Ok(read) => read,
-                Err(error) => {
-                    println!(
-                        "{} {:?}",
-                        format_colors(&format!(
-                            "$BOLD$[{}] Unable to read chunk:$NORMAL$",
-                            input_path
-                        )),
-                        error
-                    );
-                    continue 'input_loop;
-                }
-            };
-            if read == 0 {
-                break;
+        let report_progress = |progress: sfs::Progress| {
+            if let Some(total_bytes) = progress.total_bytes {
+                progress_bar.set_length(total_bytes)
             }
+            progress_bar.set_position(progress.processed_bytes)
+        };
 
-            let decrypted = match decrypter.decrypt(&line_buffer.trim()) {
-                Ok(decrypted) => decrypted,
-                Err(error) => {
-                    println!(
-                        "{} {:?} (possibly incorrect password?)",
-                        format_colors(&format!(
-                            "$BOLD$[{}] Unable to decrypt chunk:$NORMAL$",
-                            input_path
-                        )),
-                        error
-                    );
-                    continue 'input_loop;
-                }
-            };
-            line_buffer.clear();
-            match output_file.write(&decrypted) {
-                Ok(_) => (),
-                Err(error) => {
-                    println!(
-                        "{} {:?}",
-                        format_colors(&format!(
-                            "$BOLD$[{}] Unable to write chunk:$NORMAL$",
-                            output_path
-                        )),
-                        error
-                    );
-                    continue 'input_loop;
-                }
+        let options = sfs::DecryptOptions {
+            identity: identity.as_ref(),
+            verify_checksum: !no_verify_chunks,
+            progress: if silent { None } else { Some(&report_progress) },
+            ..sfs::DecryptOptions::new(credentials)
+        };
+        // Files are decrypted into a temporary file first, so the output is only
+        // replaced once everything has been decrypted and verified. Archives are
+        // extracted into a directory, unless they're being decrypted to stdout.
+        let result = if streaming {
+            encrypted_file.decrypt_to(BufWriter::new(std::io::stdout().lock()), &options)
+        } else if archive {
+            encrypted_file.extract_to(std::path::Path::new(&output_path), &options)
+        } else {
+            encrypted_file.decrypt_to_file(std::path::Path::new(&output_path), &options)
+        };
+        match result {
+            Ok(_) => (),
+            Err(sfs::Error::ChecksumMismatch { expected, actual }) => {
+                eprintln!("{}", format_colors(&format!("$BOLD$$RED$WARNING - DECRYPTED FILE DOES NOT MATCH CHECKSUM! EXPECTED `{}` but GOT `{}`!", format_checksum(&expected), format_checksum(&actual))));
+                succeeded = false;
+                continue;
             }
-
-            if !silent {
-                progress_bar.set_position(decrypter.total_bytes)
+            Err(sfs::Error::Truncated) => {
+                eprintln!(
+                    "{} The end of the file has been cut off",
+                    format_colors(&format!(
+                        "$BOLD$[{}] Unable to decrypt file:$NORMAL$",
+                        input_path
+                    )),
+                );
+                succeeded = false;
+                continue;
             }
-        }
-
-        if !no_verify_chunks {
-            let output_checksum = decrypter.get_checksum();
-            if output_checksum != metadata.checksum {
-                println!("{}", format_colors(&format!("$BOLD$$RED$WARNING - DECRYPTED FILE DOES NOT MATCH CHECKSUM! EXPECTED `{}` but GOT `{}`!", metadata.checksum, output_checksum)));
+            Err(error) => {
+                eprintln!(
+                    "{} {}",
+                    format_colors(&format!(
+                        "$BOLD$[{}] Unable to decrypt file:$NORMAL$",
+                        input_path
+                    )),
+                    error
+                );
+                succeeded = false;
+                continue;
             }
         }
 
-        if !keep_file {
+        let keep_input = keep_file || (verify && no_verify_chunks);
+        if !keep_input
+            && !streaming
+            && !log_state(
+                &mut journal,
+                JournalState::Written,
+                &input_path,
+                &output_path,
+            )
+        {
+            succeeded = false;
+            stopped = true;
+            break;
+        }
+        if !keep_file && !streaming && verify && no_verify_chunks {
+            if !silent {
+                eprintln!(
+                    "{} The checksum of the decrypted file wasn't verified",
+                    format_colors(&format!("$BOLD$[{}] Keeping file:$NORMAL$", input_path)),
+                );
+            }
+        } else if !keep_file && !streaming {
             match fs::remove_file(&input_path) {
                 Ok(_) => (),
                 Err(error) => {
-                    println!(
+                    eprintln!(
                         "{} {:?}",
                         format_colors(&format!(
                             "$BOLD$[{}] Unable to remove file:$NORMAL$",
@@ -1389,37 +2093,249 @@ pub fn decrypt_command(command: ParsedCommand) {
                         )),
                         error
                     );
-                    continue 'input_loop;
+                    succeeded = false;
+                    continue;
+                }
+            }
+        }
+
+        if !streaming && !log_state(&mut journal, JournalState::Done, &input_path, &output_path) {
+            succeeded = false;
+            stopped = true;
+            break;
+        }
+
+        eprintln!()
+    }
+    finish_journal(journal, stopped) && succeeded
+}
+
+pub fn resume_command(command: ParsedCommand) -> bool {
+    let configuration = match command.contexts.get(&String::from("configuration")) {
+        Some(configuration) => match configuration {
+            Context::Configuration(configuration) => configuration,
+            _ => unreachable!(),
+        },
+        None => {
+            eprintln!(
+                "{} Configuration was not passed by SFS!",
+                format_colors(&String::from("$BOLD$Fatal error:$NORMAL$")),
+            );
+            return false;
+        }
+    };
+    let mut rollback = false;
+    for flag in &command.flags {
+        if flag.name.as_deref() == Some("rollback") {
+            rollback = !rollback
+        }
+    }
+    let journal = match Journal::read() {
+        Ok(Some(journal)) => journal,
+        Ok(None) => {
+            println!("There's nothing to resume!");
+            return true;
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            return false;
+        }
+    };
+    let callback = match journal.command.as_str() {
+        "encrypt" => encrypt_command,
+        "decrypt" => decrypt_command,
+        _ => {
+            eprintln!(
+                "{}",
+                format_colors(&format!(
+                    "$BOLD$Unable to resume:$NORMAL$ Unknown command `{}`",
+                    journal.command
+                ))
+            );
+            return false;
+        }
+    };
+
+    // The inputs that the command is run on again, the inputs that still have to be
+    // removed (and if they're shredded), the outputs that are removed because their
+    // input is intact, and the outputs that the opposite command is run on
+    let mut input_paths = Vec::new();
+    let mut removed_inputs = Vec::new();
+    let mut removed_outputs = Vec::new();
+    let mut output_paths = Vec::new();
+    for state in journal.states() {
+        let input_exists = fs::symlink_metadata(&state.input_path).is_ok();
+        let output_path = match &state.output_path {
+            Some(output_path) => output_path.to_owned(),
+            None => {
+                if !rollback && input_exists {
+                    input_paths.push(state.input_path)
+                }
+                continue;
+            }
+        };
+        let output_exists = fs::symlink_metadata(&output_path).is_ok();
+        match state.state {
+            JournalState::Pending | JournalState::Started => {
+                // It's not possible to tell if it was there before
+                if rollback && output_exists {
+                    eprintln!(
+                        "{} It might have existed before",
+                        format_colors(&format!("$BOLD$[{}] Keeping file:$NORMAL$", output_path)),
+                    );
+                }
+                if !rollback && input_exists {
+                    input_paths.push(state.input_path)
+                }
+            }
+            // The output is complete, so finishing only has to remove the input
+            JournalState::Written | JournalState::Shredding if !rollback => {
+                if input_exists {
+                    removed_inputs.push((state.input_path, state.state == JournalState::Shredding))
+                }
+            }
+            JournalState::Done if !rollback => (),
+            // The input hasn't been touched, so the output can just be removed
+            JournalState::Written | JournalState::Done if input_exists => {
+                if output_exists {
+                    removed_outputs.push(output_path)
                 }
             }
+            // The output is the only complete copy, so whatever is left of the
+            // input is removed before it's restored from the output
+            JournalState::Written | JournalState::Shredding | JournalState::Done => {
+                if input_exists {
+                    removed_inputs.push((state.input_path, true))
+                }
+                if output_exists {
+                    output_paths.push(output_path)
+                }
+            }
+        }
+    }
+    // The recipients, cipher and name of the encrypted files are gone with them
+    if rollback && journal.command == "decrypt" && !output_paths.is_empty() {
+        eprintln!(
+            "{}",
+            format_colors(&format!(
+                "$BOLD$Unable to roll back:$NORMAL$ The encrypted files of {} decrypted file(s) were already removed, so they can't be encrypted again with the same recipients, cipher and name. Use $BOLD$`resume`$NORMAL$ to finish the run instead.",
+                output_paths.len()
+            ))
+        );
+        return false;
+    }
+
+    let mut succeeded = true;
+    for state in journal.states() {
+        remove_temporary_files(std::path::Path::new(&state.input_path));
+        if let Some(output_path) = &state.output_path {
+            remove_temporary_files(std::path::Path::new(output_path));
+        }
+    }
+    for (input_path, shred) in &removed_inputs {
+        let path = std::path::Path::new(input_path);
+        let result = if *shred {
+            shred_path(path, configuration.shred_command.passes)
+        } else if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir()) {
+            remove_directory(path).map_err(sfs::Error::from)
+        } else {
+            fs::remove_file(path).map_err(sfs::Error::from)
+        };
+        if let Err(error) = result {
+            eprintln!(
+                "{} {}",
+                format_colors(&format!(
+                    "$BOLD$[{}] Unable to remove file:$NORMAL$",
+                    input_path
+                )),
+                error
+            );
+            succeeded = false;
+        }
+    }
+    for output_path in &removed_outputs {
+        let path = std::path::Path::new(output_path);
+        let result = if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir()) {
+            remove_directory(path)
+        } else {
+            fs::remove_file(path)
+        };
+        if let Err(error) = result {
+            eprintln!(
+                "{} {:?}",
+                format_colors(&format!(
+                    "$BOLD$[{}] Unable to remove file:$NORMAL$",
+                    output_path
+                )),
+                error
+            );
+            succeeded = false;
         }
+    }
+    if let Err(error) = fs::remove_file(Journal::path()) {
+        eprintln!(
+            "{} {:?}",
+            format_colors(&String::from("$BOLD$Unable to remove journal:$NORMAL$")),
+            error
+        );
+        return false;
+    }
 
-        println!()
+    // Finishing runs the same command again on everything that wasn't written yet,
+    // and rolling back decrypts everything that was encrypted
+    let (callback, mut flags, paths) = if rollback {
+        (
+            decrypt_command as fn(ParsedCommand) -> bool,
+            Vec::new(),
+            output_paths,
+        )
+    } else {
+        (callback, journal.parsed_flags(), input_paths)
+    };
+    if paths.is_empty() {
+        println!("There's nothing left to do!");
+        return succeeded;
     }
+    flags.extend(paths.into_iter().map(|path| ParsedFlag {
+        name: None,
+        value: Some(path),
+    }));
+    callback(ParsedCommand {
+        flags,
+        contexts: command.contexts,
+    }) && succeeded
 }
 
-pub fn information_command(command: ParsedCommand) {
-    let fernet = match command.contexts.get(&String::from("fernet")) {
-        Some(fernet) => match fernet {
-            Context::Fernet(fernet) => fernet,
+pub fn information_command(command: ParsedCommand) -> bool {
+    let credentials = match command.contexts.get(&String::from("credentials")) {
+        Some(credentials) => match credentials {
+            Context::Credentials(credentials) => credentials,
             _ => unreachable!(),
         },
         None => {
             println!(
-                "{} Fernet was not passed by SFS!",
+                "{} Credentials were not passed by SFS!",
                 format_colors(&String::from("$BOLD$Fatal error:$NORMAL$")),
             );
-            return;
+            return false;
+        }
+    };
+    let identity = match read_identity() {
+        Ok(identity) => identity,
+        Err(error) => {
+            println!("{}", error);
+            return false;
         }
     };
 
     let mut input_paths = Vec::new();
     for flag in command.flags {
-        if flag.name.is_none() && flag.value.is_some() {
-            input_paths.push(flag.value.unwrap())
+        if let (None, Some(value)) = (flag.name, flag.value) {
+            input_paths.push(value)
         }
     }
 
+    let mut succeeded = true;
     for input_path in input_paths {
         let input_file = match fs::File::open(&input_path) {
             Ok(file) => file,
@@ -1432,69 +2348,1112 @@ pub fn information_command(command: ParsedCommand) {
                     )),
                     error
                 );
+                succeeded = false;
                 continue;
             }
         };
-
-        let mut buffer = String::new();
-        match BufReader::new(&input_file).read_line(&mut buffer) {
-            Ok(_) => buffer = buffer.trim().to_string(),
+        let sfs::EncryptedFile {
+            header,
+            metadata,
+            cipher: file_cipher,
+            stored_bytes,
+            ..
+        } = match read_metadata(
+            &input_path,
+            BufReader::new(&input_file),
+            credentials,
+            identity.as_ref(),
+        ) {
+            Ok(result) => result,
             Err(error) => {
-                println!(
-                    "{} {:?}",
-                    format_colors(&format!(
-                        "$BOLD$[{}] Unable to read metadata:$NORMAL$",
-                        input_path
-                    )),
-                    error
-                );
+                println!("{}", error);
+                succeeded = false;
                 continue;
             }
-        }
-        let metadata_bytes = match fernet.decrypt(&buffer) {
-            Ok(metadata_bytes) => metadata_bytes,
-            Err(error) => {
-                println!(
-                    "{} {:?} (possibly incorrect password?)",
-                    format_colors(&format!(
-                        "$BOLD$[{}] Unable to decrypt metadata:$NORMAL$",
-                        input_path
-                    )),
-                    error
-                );
-                continue;
+        };
+        let key_slots = match header {
+            Some(header) => {
+                let mut key_slots = Vec::new();
+                for key_slot in header.key_slots {
+                    key_slots.push(match key_slot {
+                        KeySlot::Password {
+                            key_derivation_function,
+                            factors,
+                            memory_cost,
+                            time_cost,
+                            parallelism,
+                            ..
+                        } => {
+                            let factors = sfs::describe_factors(factors)
+                                .trim_start_matches("a ")
+                                .replace(" and a ", " + ")
+                                .to_uppercase();
+                            match KeyDerivationFunction::from_u8(key_derivation_function) {
+                                Some(KeyDerivationFunction::Argon2id) => format!(
+                                    "{} ({}, memory: {} KiB, iterations: {}, parallelism: {})",
+                                    factors,
+                                    KeyDerivationFunction::Argon2id,
+                                    memory_cost,
+                                    time_cost,
+                                    parallelism
+                                ),
+                                Some(key_derivation_function) => {
+                                    format!("{} ({})", factors, key_derivation_function)
+                                }
+                                None => format!("{} (UNKNOWN)", factors),
+                            }
+                        }
+                        KeySlot::X25519 { .. } => String::from("X25519 RECIPIENT"),
+                        KeySlot::Unknown { kind, .. } => format!("UNKNOWN ({})", kind),
+                    })
+                }
+                key_slots.join(", ")
             }
+            None => format!("PASSWORD ({}, unsalted)", KeyDerivationFunction::Sha256),
         };
-        let metadata = match FileMetadata::parse(&metadata_bytes) {
-            Ok(metadata) => metadata,
-            Err(error) => {
-                println!(
-                    "{} {:?}",
-                    format_colors(&format!(
-                        "$BOLD$[{}] Unable to unpack metadata:$NORMAL$",
-                        input_path
-                    )),
-                    error
-                );
-                continue;
+        let encrypted_size = input_file
+            .metadata()
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        let archive_contents = if metadata.archive {
+            match read_archive_entries(&input_path, credentials, identity.as_ref()) {
+                Ok(archive_entries) => archive_entries
+                    .iter()
+                    .map(|archive_entry| format!("\n\t\t{}", format_archive_entry(archive_entry)))
+                    .collect::<String>(),
+                Err(error) => {
+                    println!("{}", error);
+                    succeeded = false;
+                    continue;
+                }
             }
+        } else {
+            String::new()
+        };
+        let compression_ratio = match stored_bytes {
+            Some(stored_bytes) if stored_bytes > 0 => {
+                format!("{:.2}", metadata.total_bytes as f64 / stored_bytes as f64)
+            }
+            _ => String::from("N/A"),
         };
 
         println!(
             "{}",
             format_colors(&format!(
-                "$BOLD$`{}`$NORMAL$:\n\t$BOLD$Original Name:$NORMAL$ {}\n\t$BOLD$Restore Original Name:$NORMAL$ {}\n\t$BOLD$Decrypted Size:$NORMAL$ {} ({})\n\t$BOLD$SFS File Format Version:$NORMAL$ v{}\n\t$BOLD$Hashing Algorithm:$NORMAL$ {}\n\t$BOLD$Checksum:$NORMAL$ {:X}\n\t$BOLD$Chunk Size:$NORMAL$ {} ({})",
+                "$BOLD$`{}`$NORMAL$:\n\t$BOLD$Original Name:$NORMAL$ {}\n\t$BOLD$Restore Original Name:$NORMAL$ {}\n\t$BOLD$Decrypted Size:$NORMAL$ {} ({})\n\t$BOLD$Encrypted Size:$NORMAL$ {} ({})\n\t$BOLD$Compression:$NORMAL$ {} (ratio: {})\n\t$BOLD$SFS File Format Version:$NORMAL$ v{}\n\t$BOLD$Cipher:$NORMAL$ {}\n\t$BOLD$Key Slots:$NORMAL$ {}\n\t$BOLD$Hashing Algorithm:$NORMAL$ {}\n\t$BOLD$Checksum:$NORMAL$ {}\n\t$BOLD$Chunk Size:$NORMAL$ {} ({})\n\t$BOLD$Archive:$NORMAL$ {}{}",
                 input_path,
                 metadata.original_name,
                 if metadata.restore_name { "Yes" } else { "No" },
                 metadata.total_bytes,
                 humansize::format_size(metadata.total_bytes, humansize::BINARY),
+                encrypted_size,
+                humansize::format_size(encrypted_size, humansize::BINARY),
+                match Compression::from_u8(metadata.compression) {
+                    Some(compression) => compression.to_string(),
+                    None => format!("UNKNOWN ({})", metadata.compression),
+                },
+                compression_ratio,
                 metadata.format_version,
+                file_cipher.cipher(),
+                key_slots,
                 HashingAlgorithm::from_u8(metadata.hashing_algorithm),
-                metadata.checksum,
+                format_checksum(&metadata.checksum),
                 metadata.chunk_size,
                 humansize::format_size(metadata.chunk_size, humansize::BINARY),
+                if metadata.archive { "Yes" } else { "No" },
+                archive_contents,
             ))
         )
     }
+    succeeded
+}
+
+pub fn rekey_command(command: ParsedCommand) -> bool {
+    let credentials = match command.contexts.get(&String::from("credentials")) {
+        Some(credentials) => match credentials {
+            Context::Credentials(credentials) => credentials,
+            _ => unreachable!(),
+        },
+        None => {
+            println!(
+                "{} Credentials were not passed by SFS!",
+                format_colors(&String::from("$BOLD$Fatal error:$NORMAL$")),
+            );
+            return false;
+        }
+    };
+    let configuration = match command.contexts.get(&String::from("configuration")) {
+        Some(configuration) => match configuration {
+            Context::Configuration(configuration) => configuration,
+            _ => unreachable!(),
+        },
+        None => {
+            println!(
+                "{} Configuration was not passed by SFS!",
+                format_colors(&String::from("$BOLD$Fatal error:$NORMAL$")),
+            );
+            return false;
+        }
+    };
+    let identity = match read_identity() {
+        Ok(identity) => identity,
+        Err(error) => {
+            println!("{}", error);
+            return false;
+        }
+    };
+
+    let mut silent = false;
+    let mut new_keyfile = credentials.keyfile.clone();
+    let mut new_password_source = None;
+    let mut raw_input_paths = Vec::new();
+    for flag in command.flags {
+        if let Some(name) = flag.name {
+            match name.as_str() {
+                "silent" => silent = !silent,
+                "new-password-fd" => {
+                    let value = flag.value.unwrap();
+                    match value.parse() {
+                        Ok(file_descriptor) => {
+                            new_password_source =
+                                Some(PasswordSource::FileDescriptor(file_descriptor))
+                        }
+                        Err(_) => {
+                            println!(
+                                "{}",
+                                format_colors(&format!(
+                                    "Invalid file descriptor $BOLD$`{}`$NORMAL$",
+                                    value
+                                ))
+                            );
+                            return false;
+                        }
+                    }
+                }
+                "new-password-file" => {
+                    new_password_source = Some(PasswordSource::File(flag.value.unwrap()))
+                }
+                "new-password-command" => {
+                    new_password_source = Some(PasswordSource::Command(flag.value.unwrap()))
+                }
+                "keyfile" => {
+                    let keyfile_path = flag.value.unwrap();
+                    match fs::read(&keyfile_path) {
+                        Ok(keyfile) => {
+                            new_keyfile = Some(sfs::hash_keyfile(&Zeroizing::new(keyfile)))
+                        }
+                        Err(error) => {
+                            println!(
+                                "{} {:?}",
+                                format_colors(&format!(
+                                    "$BOLD$[{}] Unable to read keyfile:$NORMAL$",
+                                    keyfile_path
+                                )),
+                                error
+                            );
+                            return false;
+                        }
+                    }
+                }
+                "no-keyfile" => new_keyfile = None,
+                _ => (),
+            }
+        } else if let Some(value) = flag.value {
+            raw_input_paths.push(value)
+        }
+    }
+    if raw_input_paths.is_empty() {
+        raw_input_paths.push(String::from("."))
+    }
+
+    let read_password = |prompt: &str| -> Option<Zeroizing<String>> {
+        print!("{}", prompt);
+        std::io::stdout().flush().unwrap();
+        match rpassword::read_password() {
+            Ok(password) => Some(Zeroizing::new(password)),
+            Err(error) => {
+                println!(
+                    "{} {:?}",
+                    format_colors("$BOLD$Unable to read input:$NORMAL$"),
+                    error
+                );
+                None
+            }
+        }
+    };
+    if new_password_source.is_none() && std::env::var_os("SFS_NEW_PASSWORD").is_some() {
+        new_password_source = Some(PasswordSource::Environment(String::from(
+            "SFS_NEW_PASSWORD",
+        )));
+    }
+    // Without a terminal to ask on, the files are unlocked with the current password
+    let (old_credentials, new_password) = match &new_password_source {
+        Some(new_password_source) => match read_password_source(new_password_source) {
+            Ok(new_password) => (None, Zeroizing::new(new_password)),
+            Err(error) => {
+                println!("{}", error);
+                return false;
+            }
+        },
+        None => {
+            let old_credentials =
+                match read_password("Old password (leave empty to use your current one): ") {
+                    Some(old_password) if old_password.is_empty() => None,
+                    Some(old_password) => Some(Credentials::with_keyfile_digest(
+                        old_password.to_string(),
+                        credentials.keyfile.clone(),
+                    )),
+                    None => return false,
+                };
+            let new_password = match read_password(if new_keyfile.is_some() {
+                "New password (leave empty to only use your keyfile): "
+            } else {
+                "New password: "
+            }) {
+                Some(new_password) => new_password,
+                None => return false,
+            };
+            if !new_password.is_empty() || new_keyfile.is_some() {
+                match read_password("Repeat new password: ") {
+                    Some(repeat_password) if *repeat_password == *new_password => (),
+                    Some(_) => {
+                        println!("{}", format_colors("$BOLD$Passwords do not match!$NORMAL$"));
+                        return false;
+                    }
+                    None => return false,
+                }
+            }
+            (old_credentials, new_password)
+        }
+    };
+    let old_credentials = old_credentials.as_ref().unwrap_or(credentials.as_ref());
+    if new_password.is_empty() && new_keyfile.is_none() {
+        println!("No password specified.");
+        return false;
+    }
+    let new_credentials = Credentials::with_keyfile_digest(new_password.to_string(), new_keyfile);
+
+    let mut succeeded = true;
+    let mut rekeyed_files = 0;
+    for raw_input_path in &raw_input_paths {
+        for entry in WalkDir::new(raw_input_path) {
+            let path = match entry {
+                Ok(entry) => entry.path().to_owned(),
+                Err(error) => {
+                    println!(
+                        "{} {:?}",
+                        format_colors(&format!(
+                            "$BOLD$[{}] Unable to get file information:$NORMAL$",
+                            raw_input_path
+                        )),
+                        error
+                    );
+                    succeeded = false;
+                    continue;
+                }
+            };
+            let input_path = path.display().to_string();
+            if !path.is_file() || !input_path.ends_with(".sfs") {
+                continue;
+            }
+
+            let temporary_path = sfs::temporary_path(&path).display().to_string();
+            match rekey_file(
+                &input_path,
+                &temporary_path,
+                old_credentials,
+                identity.as_ref(),
+                &new_credentials,
+                &configuration.encrypt_command,
+            ) {
+                Ok(true) => {
+                    rekeyed_files += 1;
+                    if !silent {
+                        println!(
+                            "{}",
+                            format_colors(&format!(
+                                "$BOLD$[{}]$NORMAL$ Changed password",
+                                input_path
+                            ))
+                        )
+                    }
+                }
+                Ok(false) => {
+                    if !silent {
+                        println!(
+                            "{}",
+                            format_colors(&format!(
+                                "$BOLD$[{}] Ignoring file:$NORMAL$ File is only encrypted for recipients",
+                                input_path
+                            ))
+                        )
+                    }
+                }
+                Err(error) => {
+                    let _ = fs::remove_file(&temporary_path);
+                    println!("{}", error);
+                    succeeded = false;
+                }
+            }
+        }
+    }
+    if rekeyed_files > 0 {
+        // Otherwise the new password would be reported as a typo from now on
+        if configuration.verify_password {
+            write_password_verifier(
+                &get_configuration_directory().join("verifier"),
+                &new_credentials,
+                configuration,
+            );
+        }
+        println!(
+            "{}",
+            format_colors("Use your new password (and keyfile) the next time you launch SFS.")
+        )
+    }
+    succeeded
+}
+
+/// Writes the file with the new password to `temporary_path`, and then renames it
+/// over `input_path`. v3 files only need the content key to be wrapped again, but
+/// older files are decrypted and encrypted again as v3 files. Returns false if the
+/// file was left alone, because it's only encrypted for recipients.
+fn rekey_file(
+    input_path: &str,
+    temporary_path: &str,
+    old_credentials: &Credentials,
+    identity: Option<&Identity>,
+    new_credentials: &Credentials,
+    encrypt_configuration: &EncryptCommandConfiguration,
+) -> Result<bool, String> {
+    let format_error = |message: &str, path: &str, error: String| {
+        format!(
+            "{} {}",
+            format_colors(&format!("$BOLD$[{}] {}:$NORMAL$", path, message)),
+            error
+        )
+    };
+
+    let input_file = match fs::File::open(input_path) {
+        Ok(file) => file,
+        Err(error) => {
+            return Err(format_error(
+                "Unable to open file",
+                input_path,
+                format!("{:?}", error),
+            ))
+        }
+    };
+    let mut buffered_reader = BufReader::new(&input_file);
+    let header = match FileHeader::read(&mut buffered_reader) {
+        Ok(header) => header,
+        Err(sfs::Error::Io(error)) => {
+            return Err(format_error(
+                "Unable to read header",
+                input_path,
+                format!("{:?}", error),
+            ))
+        }
+        Err(error) => {
+            return Err(format_error(
+                "Unable to unpack header",
+                input_path,
+                error.to_string(),
+            ))
+        }
+    };
+    if header.as_ref().is_some_and(|header| !header.has_password()) {
+        return Ok(false);
+    }
+
+    let mut output_file = match fs::File::options()
+        .write(true)
+        .create_new(true)
+        .open(temporary_path)
+    {
+        Ok(file) => file,
+        Err(error) => {
+            return Err(format_error(
+                "Unable to create output file",
+                temporary_path,
+                format!("{:?}", error),
+            ))
+        }
+    };
+    match header {
+        Some(mut header) => {
+            let content_key = match header.unwrap_content_key(old_credentials, identity) {
+                Ok(content_key) => content_key,
+                Err(error) => {
+                    return Err(format_error(
+                        "Unable to unlock file",
+                        input_path,
+                        error.to_string(),
+                    ))
+                }
+            };
+            match header.set_password(
+                &content_key,
+                new_credentials,
+                encrypt_configuration.key_derivation_memory_cost,
+                encrypt_configuration.key_derivation_time_cost,
+                encrypt_configuration.key_derivation_parallelism,
+            ) {
+                Ok(_) => (),
+                Err(error) => {
+                    return Err(format_error(
+                        "Unable to derive key",
+                        input_path,
+                        error.to_string(),
+                    ))
+                }
+            }
+            match output_file
+                .write_all(&header.pack())
+                .and_then(|_| std::io::copy(&mut buffered_reader, &mut output_file))
+            {
+                Ok(_) => (),
+                Err(error) => {
+                    return Err(format_error(
+                        "Unable to write file",
+                        temporary_path,
+                        format!("{:?}", error),
+                    ))
+                }
+            }
+        }
+        None => {
+            let sfs::EncryptedFile {
+                metadata,
+                cipher: file_cipher,
+                ..
+            } = open_encrypted_file(input_path, &mut buffered_reader, old_credentials, None)?;
+            let hashing_algorithm = HashingAlgorithm::from_u8(metadata.hashing_algorithm);
+            let mut decrypter = Decrypter::new(file_cipher, hashing_algorithm);
+
+            let content_key = sfs::generate_content_key();
+            let mut header = FileHeader::new(Cipher::XChaCha20Poly1305);
+            match header.add_password(
+                &content_key,
+                new_credentials,
+                encrypt_configuration.key_derivation_memory_cost,
+                encrypt_configuration.key_derivation_time_cost,
+                encrypt_configuration.key_derivation_parallelism,
+            ) {
+                Ok(_) => (),
+                Err(error) => {
+                    return Err(format_error(
+                        "Unable to derive key",
+                        input_path,
+                        error.to_string(),
+                    ))
+                }
+            }
+            let file_cipher = match FileCipher::new(&header, &content_key) {
+                Ok(file_cipher) => file_cipher,
+                Err(error) => {
+                    return Err(format_error(
+                        "Unable to set up cipher",
+                        input_path,
+                        error.to_string(),
+                    ))
+                }
+            };
+            let mut encrypted = header.pack();
+            encrypted.append(&mut file_cipher.encrypt_metadata(&FileMetadata {
+                format_version: sfs::SFS_FORMAT_VERSION,
+                header_checksum: header.checksum(),
+                ..metadata.clone()
+            }));
+            let mut encrypter = Encrypter::new(file_cipher, hashing_algorithm);
+            loop {
+                let record = match Record::read(&mut buffered_reader, metadata.format_version) {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    Err(error) => {
+                        return Err(format_error(
+                            "Unable to read chunk",
+                            input_path,
+                            error.to_string(),
+                        ))
+                    }
+                };
+                let decrypted = match decrypter.decrypt(&record) {
+                    Ok(decrypted) => decrypted,
+                    Err(error) => {
+                        return Err(format_error(
+                            "Unable to decrypt chunk",
+                            input_path,
+                            error.to_string(),
+                        ))
+                    }
+                };
+                encrypted.append(&mut encrypter.encrypt(&decrypted));
+                match output_file.write_all(&encrypted) {
+                    Ok(_) => encrypted.clear(),
+                    Err(error) => {
+                        return Err(format_error(
+                            "Unable to write file",
+                            temporary_path,
+                            format!("{:?}", error),
+                        ))
+                    }
+                }
+            }
+            match decrypter.verify_checksum(&metadata.checksum) {
+                Ok(_) => (),
+                Err(error) => {
+                    return Err(format_error(
+                        "Unable to decrypt file",
+                        input_path,
+                        error.to_string(),
+                    ))
+                }
+            }
+            encrypted.append(&mut encrypter.finish());
+            match output_file.write_all(&encrypted) {
+                Ok(_) => (),
+                Err(error) => {
+                    return Err(format_error(
+                        "Unable to write file",
+                        temporary_path,
+                        format!("{:?}", error),
+                    ))
+                }
+            }
+        }
+    }
+
+    // The new file is created with the default permissions, which might let others read it
+    match input_file
+        .metadata()
+        .and_then(|metadata| fs::set_permissions(temporary_path, metadata.permissions()))
+        .and_then(|_| output_file.sync_all())
+        .and_then(|_| fs::rename(temporary_path, input_path))
+    {
+        Ok(_) => {
+            sfs::sync_parent_directory(std::path::Path::new(input_path));
+            Ok(true)
+        }
+        Err(error) => Err(format_error(
+            "Unable to replace file",
+            input_path,
+            format!("{:?}", error),
+        )),
+    }
+}
+
+pub fn keygen_command(command: ParsedCommand) -> bool {
+    let mut overwrite = false;
+    for flag in command.flags {
+        if let Some(name) = flag.name {
+            if name.as_str() == "overwrite" {
+                overwrite = !overwrite
+            }
+        }
+    }
+
+    match read_identity() {
+        Ok(Some(identity)) if !overwrite => {
+            println!(
+                "{}",
+                format_colors(&format!(
+                    "You already have an identity. Use $BOLD$`keygen --overwrite`$NORMAL$ to replace it.\n$BOLD$Public key:$NORMAL$ {}",
+                    sfs::encode_public_key(&identity.public_key())
+                ))
+            );
+            return false;
+        }
+        Err(error) if !overwrite => {
+            println!("{}", error);
+            return false;
+        }
+        _ => (),
+    }
+
+    let identity = Identity::generate();
+    let identity_path = get_configuration_directory().join("identity.txt");
+    let mut open_options = fs::OpenOptions::new();
+    open_options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut open_options, 0o600);
+    match open_options
+        .open(&identity_path)
+        .and_then(|mut identity_file| {
+            identity_file.write_all(Zeroizing::new(identity.encode()).as_bytes())
+        }) {
+        Ok(_) => (),
+        Err(error) => {
+            println!(
+                "{} {:?}",
+                format_colors("$BOLD$Unable to save identity:$NORMAL$"),
+                error
+            );
+            return false;
+        }
+    }
+    println!(
+        "{}",
+        format_colors(&format!(
+            "Your identity has been saved to $BOLD${}$NORMAL$. Share your public key with the people who want to encrypt files for you.\n$BOLD$Public key:$NORMAL$ {}",
+            identity_path.display(),
+            sfs::encode_public_key(&identity.public_key())
+        ))
+    );
+    true
+}
+
+pub fn recipients_command(command: ParsedCommand) -> bool {
+    let mut arguments = Vec::new();
+    for flag in command.flags {
+        if let (None, Some(value)) = (flag.name, flag.value) {
+            arguments.push(value)
+        }
+    }
+
+    let mut succeeded = true;
+    let mut recipients = match read_recipients() {
+        Ok(recipients) => recipients,
+        Err(error) => {
+            println!("{}", error);
+            return false;
+        }
+    };
+    match arguments.first().map(|argument| argument.as_str()) {
+        None => {
+            if recipients.is_empty() {
+                println!(
+                    "{}",
+                    format_colors("You don't have any recipients. Add one with $BOLD$`recipients add [NAME] [PUBLIC KEY]`$NORMAL$.")
+                );
+            }
+            for (name, public_key) in &recipients {
+                println!(
+                    "{}",
+                    format_colors(&format!("$BOLD${}:$NORMAL$ {}", name, public_key))
+                )
+            }
+            return true;
+        }
+        Some("add") => {
+            if arguments.len() != 3 {
+                println!(
+                    "{}",
+                    format_colors("$BOLD$Usage:$NORMAL$ recipients add [NAME] [PUBLIC KEY]")
+                );
+                return false;
+            }
+            match sfs::parse_public_key(&arguments[2]) {
+                Ok(_) => (),
+                Err(error) => {
+                    println!(
+                        "{} {}",
+                        format_colors("$BOLD$Invalid public key:$NORMAL$"),
+                        error
+                    );
+                    return false;
+                }
+            }
+            recipients.insert(arguments[1].clone(), arguments[2].trim().to_string());
+        }
+        Some("remove") => {
+            for name in &arguments[1..] {
+                if recipients.remove(name).is_none() {
+                    println!(
+                        "{}",
+                        format_colors(&format!("Unknown recipient $BOLD$`{}`$NORMAL$", name))
+                    );
+                    succeeded = false;
+                }
+            }
+        }
+        Some(subcommand) => {
+            println!(
+                "{}",
+                format_colors(&format!(
+                    "Unknown subcommand $BOLD$`{}`$NORMAL$. Type $BOLD$`help recipients`$NORMAL$ for more information.",
+                    subcommand
+                ))
+            );
+            return false;
+        }
+    }
+    match write_recipients(recipients) {
+        Ok(_) => succeeded,
+        Err(error) => {
+            println!("{}", error);
+            false
+        }
+    }
+}
+
+/// Shreds a file, or everything in a directory before removing the directory
+/// (symlinks are only removed, not the files they point to).
+fn shred_path(path: &std::path::Path, passes: u32) -> Result<(), sfs::Error> {
+    // WalkDir would follow a symlink if it's the path itself
+    if fs::symlink_metadata(path)?.is_symlink() {
+        return Ok(fs::remove_file(path)?);
+    }
+    for entry in WalkDir::new(path).contents_first(true) {
+        let entry = entry.map_err(std::io::Error::from)?;
+        if entry.file_type().is_dir() {
+            fs::remove_dir(entry.path())?
+        } else if entry.file_type().is_file() {
+            sfs::shred_file(entry.path(), passes)?
+        } else {
+            fs::remove_file(entry.path())?
+        }
+    }
+    Ok(())
+}
+
+fn warn_copy_on_write(path: &str) {
+    if let Some(filesystem) = sfs::copy_on_write_filesystem(std::path::Path::new(path)) {
+        eprintln!(
+            "{} {} doesn't overwrite files in place, so the original data might still be on the disk",
+            format_colors(&format!("$BOLD$[{}] Warning:$NORMAL$", path)),
+            filesystem
+        );
+    }
+}
+
+/// The error `fs::remove_file` gives for a directory.
+fn is_a_directory_error() -> std::io::Error {
+    #[cfg(unix)]
+    return std::io::Error::from_raw_os_error(libc::EISDIR);
+    #[cfg(not(unix))]
+    return std::io::Error::from(std::io::ErrorKind::IsADirectory);
+}
+
+/// Removes a directory by renaming it out of the way first, so that it's either
+/// still complete or gone if this is interrupted.
+fn remove_directory(path: &std::path::Path) -> std::io::Result<()> {
+    let temporary_path = sfs::temporary_path(path);
+    fs::rename(path, &temporary_path)?;
+    fs::remove_dir_all(temporary_path)
+}
+
+/// Describes what would happen to a file, without touching the disk.
+fn print_dry_run(
+    input_path: &str,
+    action: &str,
+    output_path: &str,
+    overwrite: bool,
+    removal: Option<&str>,
+) {
+    let mut notes = Vec::new();
+    if output_path != "-" && fs::symlink_metadata(output_path).is_ok() {
+        notes.push(if overwrite {
+            "overwriting it"
+        } else {
+            "asking before overwriting it"
+        })
+    }
+    if let Some(removal) = removal {
+        notes.push(removal)
+    }
+    println!(
+        "{} {}{}",
+        format_colors(&format!(
+            "$BOLD$[{}] Would {} to:$NORMAL$",
+            input_path, action
+        )),
+        output_path,
+        if notes.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", notes.join(", "))
+        }
+    )
+}
+
+/// Logs the state of a file if the run is journaled, and reports if that failed
+/// (the run should stop then, since it couldn't be resumed anymore).
+fn log_state(
+    journal: &mut Option<JournalWriter>,
+    state: JournalState,
+    input_path: &str,
+    output_path: &str,
+) -> bool {
+    match journal {
+        Some(journal) => match journal.log(state, input_path, output_path) {
+            Ok(_) => true,
+            Err(error) => {
+                eprintln!("{}", error);
+                false
+            }
+        },
+        None => true,
+    }
+}
+
+/// Removes the journal if the run went through all files, and keeps it if it was stopped.
+fn finish_journal(journal: Option<JournalWriter>, stopped: bool) -> bool {
+    match journal {
+        Some(_) if stopped => {
+            eprintln!("{}", format_colors("Use $BOLD$`resume`$NORMAL$ to finish the rest of the files, or $BOLD$`resume --rollback`$NORMAL$ to undo what was done."));
+            true
+        }
+        Some(journal) => match journal.finish() {
+            Ok(_) => true,
+            Err(error) => {
+                eprintln!("{}", error);
+                false
+            }
+        },
+        None => true,
+    }
+}
+
+/// Decrypts an archive (without extracting it) to list what's inside.
+fn read_archive_entries(
+    input_path: &str,
+    credentials: &Credentials,
+    identity: Option<&Identity>,
+) -> Result<Vec<sfs::ArchiveEntry>, String> {
+    let input_file = match fs::File::open(input_path) {
+        Ok(input_file) => input_file,
+        Err(error) => {
+            return Err(format!(
+                "{} {:?}",
+                format_colors(&format!(
+                    "$BOLD$[{}] Unable to open file:$NORMAL$",
+                    input_path
+                )),
+                error
+            ))
+        }
+    };
+    let encrypted_file = open_encrypted_file(
+        input_path,
+        BufReader::new(input_file),
+        credentials,
+        identity,
+    )?;
+    match encrypted_file.list_archive(true) {
+        Ok(archive_entries) => Ok(archive_entries),
+        Err(error) => Err(format!(
+            "{} {}",
+            format_colors(&format!(
+                "$BOLD$[{}] Unable to read archive:$NORMAL$",
+                input_path
+            )),
+            error
+        )),
+    }
+}
+
+fn format_archive_entry(archive_entry: &sfs::ArchiveEntry) -> String {
+    let path = archive_entry.path.display();
+    match archive_entry.kind {
+        sfs::ArchiveEntryKind::Directory => {
+            format!("{}/ ({:o})", path, archive_entry.mode & 0o7777)
+        }
+        sfs::ArchiveEntryKind::Symlink => match &archive_entry.link_target {
+            Some(link_target) => format!("{} -> {}", path, link_target.display()),
+            None => format!("{} -> ?", path),
+        },
+        _ => format!(
+            "{} ({}, {:o})",
+            path,
+            humansize::format_size(archive_entry.size, humansize::BINARY),
+            archive_entry.mode & 0o7777
+        ),
+    }
+}
+
+/// Opens an encrypted file and reads the size and checksum from its trailer.
+fn read_metadata<R: BufRead + Seek>(
+    input_path: &str,
+    buffered_reader: R,
+    credentials: &Credentials,
+    identity: Option<&Identity>,
+) -> Result<sfs::EncryptedFile<R>, String> {
+    let mut encrypted_file =
+        open_encrypted_file(input_path, buffered_reader, credentials, identity)?;
+    encrypted_file.read_trailer().ok();
+    Ok(encrypted_file)
+}
+
+/// Like `read_metadata`, but without looking at the trailer (which can't be done
+/// without seeking), so the size and checksum of v3 files are left empty.
+fn open_encrypted_file<R: BufRead>(
+    input_path: &str,
+    buffered_reader: R,
+    credentials: &Credentials,
+    identity: Option<&Identity>,
+) -> Result<sfs::EncryptedFile<R>, String> {
+    match sfs::EncryptedFile::open(buffered_reader, credentials, identity) {
+        Ok(encrypted_file) => Ok(encrypted_file),
+        Err(sfs::Error::UnsupportedVersion(version)) => Err(format!(
+            "{} File format v{} is not supported by this version of SFS (v{} to v{}), please update SFS",
+            format_colors(&format!(
+                "$BOLD$[{}] Unsupported file:$NORMAL$",
+                input_path
+            )),
+            version,
+            sfs::SFS_OLDEST_FORMAT_VERSION,
+            sfs::SFS_FORMAT_VERSION
+        )),
+        Err(sfs::Error::WrongKey) => Err(format!(
+            "{} Incorrect password, keyfile or identity",
+            format_colors(&format!(
+                "$BOLD$[{}] Unable to unlock file:$NORMAL$",
+                input_path
+            )),
+        )),
+        Err(sfs::Error::WrongFactors { required, given }) => Err(format!(
+            "{} This file requires {}, but {} was given",
+            format_colors(&format!(
+                "$BOLD$[{}] Unable to unlock file:$NORMAL$",
+                input_path
+            )),
+            sfs::describe_factors(required),
+            sfs::describe_factors(given)
+        )),
+        Err(sfs::Error::Truncated) => Err(format!(
+            "{} File is empty or truncated",
+            format_colors(&format!(
+                "$BOLD$[{}] Unable to read metadata:$NORMAL$",
+                input_path
+            )),
+        )),
+        Err(sfs::Error::Io(error)) => Err(format!(
+            "{} {:?}",
+            format_colors(&format!(
+                "$BOLD$[{}] Unable to read file:$NORMAL$",
+                input_path
+            )),
+            error
+        )),
+        Err(sfs::Error::CorruptHeader(reason)) => Err(format!(
+            "{} {}",
+            format_colors(&format!("$BOLD$[{}] Corrupt header:$NORMAL$", input_path)),
+            reason
+        )),
+        Err(sfs::Error::CorruptMetadata(reason)) => Err(format!(
+            "{} {}",
+            format_colors(&format!("$BOLD$[{}] Corrupt metadata:$NORMAL$", input_path)),
+            reason
+        )),
+        Err(sfs::Error::HeaderMismatch) => Err(format!(
+            "{} This header does not belong to this file (or the metadata was modified)",
+            format_colors(&format!("$BOLD$[{}] Header mismatch:$NORMAL$", input_path)),
+        )),
+        Err(error) => Err(format!(
+            "{} {}",
+            format_colors(&format!(
+                "$BOLD$[{}] Unable to unlock file:$NORMAL$",
+                input_path
+            )),
+            error
+        )),
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RecipientsFile {
+    recipients: BTreeMap<String, String>,
+}
+
+fn read_recipients() -> Result<BTreeMap<String, String>, String> {
+    let recipients_path = get_configuration_directory().join("recipients.toml");
+    let recipients_string = match fs::read_to_string(&recipients_path) {
+        Ok(recipients_string) => recipients_string,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(error) => {
+            return Err(format!(
+                "{} {:?}",
+                format_colors("$BOLD$Unable to read recipients:$NORMAL$"),
+                error
+            ))
+        }
+    };
+    match toml::from_str::<RecipientsFile>(&recipients_string) {
+        Ok(recipients_file) => Ok(recipients_file.recipients),
+        Err(error) => Err(format!(
+            "{} {:?}",
+            format_colors("$BOLD$Unable to parse recipients:$NORMAL$"),
+            error
+        )),
+    }
+}
+
+fn write_recipients(recipients: BTreeMap<String, String>) -> Result<(), String> {
+    let recipients_path = get_configuration_directory().join("recipients.toml");
+    match fs::write(
+        recipients_path,
+        toml::to_string(&RecipientsFile { recipients }).unwrap(),
+    ) {
+        Ok(_) => Ok(()),
+        Err(error) => Err(format!(
+            "{} {:?}",
+            format_colors("$BOLD$Unable to save recipients:$NORMAL$"),
+            error
+        )),
+    }
+}
+
+fn read_identity() -> Result<Option<Identity>, String> {
+    let identity_path = get_configuration_directory().join("identity.txt");
+    let identity_string = match fs::read_to_string(&identity_path) {
+        Ok(identity_string) => Zeroizing::new(identity_string),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => {
+            return Err(format!(
+                "{} {:?}",
+                format_colors("$BOLD$Unable to read identity:$NORMAL$"),
+                error
+            ))
+        }
+    };
+    match Identity::parse(&identity_string) {
+        Ok(identity) => Ok(Some(identity)),
+        Err(error) => Err(format!(
+            "{} {}",
+            format_colors("$BOLD$Unable to parse identity:$NORMAL$"),
+            error
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shredding_directories() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("directory");
+        fs::create_dir_all(path.join("nested/empty")).unwrap();
+        fs::write(path.join("file.txt"), "some content").unwrap();
+        fs::write(path.join("nested/file.txt"), "more content").unwrap();
+        let outside_path = directory.path().join("outside.txt");
+        fs::write(&outside_path, "outside content").unwrap();
+        let link_path = directory.path().join("link");
+        fs::hard_link(path.join("nested/file.txt"), &link_path).unwrap();
+        // Only the symlinks themselves are removed, not the files they point to
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside_path, path.join("symlink")).unwrap();
+            std::os::unix::fs::symlink(&outside_path, directory.path().join("symlink")).unwrap();
+        }
+
+        shred_path(&path, 1).unwrap();
+        assert!(!path.exists());
+        assert_ne!(fs::read(&link_path).unwrap(), b"more content");
+        assert_eq!(
+            fs::read_to_string(&outside_path).unwrap(),
+            "outside content"
+        );
+        #[cfg(unix)]
+        {
+            shred_path(&directory.path().join("symlink"), 1).unwrap();
+            assert!(fs::symlink_metadata(directory.path().join("symlink")).is_err());
+            assert_eq!(
+                fs::read_to_string(&outside_path).unwrap(),
+                "outside content"
+            );
+        }
+    }
 }
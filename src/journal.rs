@@ -0,0 +1,375 @@
+use crate::commands::ParsedFlag;
+use crate::utilities::{format_colors, get_configuration_directory};
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalState {
+    /// The file hasn't been touched yet.
+    Pending,
+    /// The output is being written (and only exists once it's complete).
+    Started,
+    /// The output is complete and the input is still intact, but it's about to
+    /// be removed (this is skipped if the input is kept).
+    Written,
+    /// The output is complete, but the input is being overwritten, so the output
+    /// is the only complete copy left.
+    Shredding,
+    /// The input has been removed (or kept on purpose).
+    Done,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalFlag {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub state: JournalState,
+    pub input_path: String,
+    pub output_path: Option<String>,
+}
+
+/// What a recursive `encrypt` or `decrypt` run has done so far. Entries are
+/// only ever appended, so the last entry of every input is its current state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Journal {
+    pub command: String,
+    #[serde(default)]
+    pub flags: Vec<JournalFlag>,
+    #[serde(default)]
+    pub entries: Vec<JournalEntry>,
+}
+
+#[derive(Serialize)]
+struct JournalEntries<'a> {
+    entries: &'a [JournalEntry],
+}
+
+impl Journal {
+    pub fn path() -> PathBuf {
+        get_configuration_directory().join("journal.toml")
+    }
+
+    /// Reads the journal of the last interrupted run, if there is one.
+    pub fn read() -> Result<Option<Journal>, String> {
+        let journal_string = match fs::read_to_string(Journal::path()) {
+            Ok(journal_string) => journal_string,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => {
+                return Err(format!(
+                    "{} {:?}",
+                    format_colors(&String::from("$BOLD$Unable to read journal:$NORMAL$")),
+                    error
+                ))
+            }
+        };
+        Journal::parse(&journal_string).map(Some)
+    }
+
+    /// An entry that was only partially written (because of a power cut, for
+    /// example) is ignored.
+    fn parse(journal_string: &str) -> Result<Journal, String> {
+        let error = match toml::from_str(journal_string) {
+            Ok(journal) => return Ok(journal),
+            Err(error) => error,
+        };
+        if let Some(last_entry) = journal_string.rfind("[[entries]]") {
+            if let Ok(journal) = toml::from_str(&journal_string[..last_entry]) {
+                return Ok(journal);
+            }
+        }
+        Err(format!(
+            "{} {}",
+            format_colors(&String::from("$BOLD$Unable to parse journal:$NORMAL$")),
+            error
+        ))
+    }
+
+    /// The last state of every input, in the order they were first added.
+    pub fn states(&self) -> Vec<JournalEntry> {
+        let mut states: Vec<JournalEntry> = Vec::new();
+        for entry in &self.entries {
+            match states
+                .iter_mut()
+                .find(|state| state.input_path == entry.input_path)
+            {
+                Some(state) => *state = entry.clone(),
+                None => states.push(entry.clone()),
+            }
+        }
+        states
+    }
+
+    pub fn parsed_flags(&self) -> Vec<ParsedFlag> {
+        self.flags
+            .iter()
+            .map(|flag| ParsedFlag {
+                name: Some(flag.name.clone()),
+                value: flag.value.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Appends to the journal, and syncs it to the disk after every entry.
+pub struct JournalWriter {
+    file: File,
+}
+
+impl JournalWriter {
+    /// Fails if there's already a journal, so that the state of an interrupted
+    /// run isn't lost (`resume` has to finish or roll it back first).
+    pub fn create(
+        command: &str,
+        flags: &[ParsedFlag],
+        input_paths: &[String],
+    ) -> Result<JournalWriter, String> {
+        let journal = Journal {
+            command: String::from(command),
+            flags: flags
+                .iter()
+                .filter_map(|flag| {
+                    flag.name.as_ref().map(|name| JournalFlag {
+                        name: name.clone(),
+                        value: flag.value.clone(),
+                    })
+                })
+                .collect(),
+            entries: input_paths
+                .iter()
+                .filter(|input_path| *input_path != "-")
+                .map(|input_path| JournalEntry {
+                    state: JournalState::Pending,
+                    input_path: absolute_path(input_path),
+                    output_path: None,
+                })
+                .collect(),
+        };
+        let journal_string = match toml::to_string(&journal) {
+            Ok(journal_string) => journal_string,
+            Err(error) => {
+                return Err(format!(
+                    "{} {}",
+                    format_colors(&String::from("$BOLD$Unable to serialize journal:$NORMAL$")),
+                    error
+                ))
+            }
+        };
+        let mut file = match File::options()
+            .write(true)
+            .create_new(true)
+            .open(Journal::path())
+        {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(format_colors(&String::from("$BOLD$Unable to create journal:$NORMAL$ A previous run was interrupted, use $BOLD$`resume`$NORMAL$ to finish it (or $BOLD$`resume --rollback`$NORMAL$ to undo it) first")));
+            }
+            Err(error) => {
+                return Err(format!(
+                    "{} {:?}",
+                    format_colors(&String::from("$BOLD$Unable to create journal:$NORMAL$")),
+                    error
+                ))
+            }
+        };
+        match file
+            .write_all(journal_string.as_bytes())
+            .and_then(|_| file.sync_all())
+        {
+            Ok(_) => Ok(JournalWriter { file }),
+            Err(error) => {
+                fs::remove_file(Journal::path()).ok();
+                Err(format!(
+                    "{} {:?}",
+                    format_colors(&String::from("$BOLD$Unable to write journal:$NORMAL$")),
+                    error
+                ))
+            }
+        }
+    }
+
+    pub fn log(
+        &mut self,
+        state: JournalState,
+        input_path: &str,
+        output_path: &str,
+    ) -> Result<(), String> {
+        let entry = JournalEntry {
+            state,
+            input_path: absolute_path(input_path),
+            output_path: Some(absolute_path(output_path)),
+        };
+        let result = toml::to_string(&JournalEntries { entries: &[entry] })
+            .map_err(|error| error.to_string())
+            .and_then(|entry_string| {
+                self.file
+                    .write_all(format!("\n{}", entry_string).as_bytes())
+                    .and_then(|_| self.file.sync_data())
+                    .map_err(|error| format!("{:?}", error))
+            });
+        match result {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!(
+                "{} {}",
+                format_colors(&format!(
+                    "$BOLD$[{}] Unable to write journal:$NORMAL$",
+                    input_path
+                )),
+                error
+            )),
+        }
+    }
+
+    /// Removes the journal once the run is over.
+    pub fn finish(self) -> Result<(), String> {
+        drop(self.file);
+        match fs::remove_file(Journal::path()) {
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!(
+                "{} {:?}",
+                format_colors(&String::from("$BOLD$Unable to remove journal:$NORMAL$")),
+                error
+            )),
+        }
+    }
+}
+
+/// Paths are stored as absolute paths, so that `resume` works from any directory.
+fn absolute_path(path: &str) -> String {
+    match std::env::current_dir() {
+        Ok(current_directory) => current_directory.join(path).display().to_string(),
+        Err(_) => String::from(path),
+    }
+}
+
+/// Removes the temporary files that outputs are written to before they're
+/// renamed, which are left behind if SFS is killed while writing them.
+pub fn remove_temporary_files(output_path: &Path) -> usize {
+    let (parent, file_name) = match (output_path.parent(), output_path.file_name()) {
+        (Some(parent), Some(file_name)) => (parent, file_name),
+        _ => return 0,
+    };
+    let prefix = format!(".{}.", file_name.to_string_lossy());
+    let mut removed = 0;
+    if let Ok(entries) = fs::read_dir(parent) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let random_name = match name
+                .strip_prefix(&prefix)
+                .and_then(|name| name.strip_suffix(".tmp"))
+            {
+                Some(random_name) => random_name,
+                None => continue,
+            };
+            if random_name.len() != 8 || !random_name.chars().all(char::is_alphanumeric) {
+                continue;
+            }
+            let result = if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                fs::remove_dir_all(entry.path())
+            } else {
+                fs::remove_file(entry.path())
+            };
+            if result.is_ok() {
+                removed += 1
+            }
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JOURNAL: &str = r#"command = "encrypt"
+
+[[flags]]
+name = "recursive"
+
+[[entries]]
+state = "pending"
+input_path = "/files/a.txt"
+
+[[entries]]
+state = "pending"
+input_path = "/files/b.txt"
+
+[[entries]]
+state = "written"
+input_path = "/files/a.txt"
+output_path = "/files/a.txt.sfs"
+"#;
+
+    #[test]
+    fn partially_written_entries_are_ignored() {
+        let journal = Journal::parse(JOURNAL).unwrap();
+        assert_eq!(journal.command, "encrypt");
+        assert_eq!(journal.flags[0].name, "recursive");
+        assert_eq!(journal.entries.len(), 3);
+
+        let partial_journal = format!(
+            "{}\n[[entries]]\nstate = \"done\"\ninput_path = \"/files/a",
+            JOURNAL
+        );
+        let journal = Journal::parse(&partial_journal).unwrap();
+        assert_eq!(journal.entries.len(), 3);
+        assert_eq!(journal.entries[2].state, JournalState::Written);
+
+        assert!(Journal::parse("command = ").is_err());
+    }
+
+    #[test]
+    fn the_last_state_of_every_input_is_kept() {
+        let states = Journal::parse(JOURNAL).unwrap().states();
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].input_path, "/files/a.txt");
+        assert_eq!(states[0].state, JournalState::Written);
+        assert_eq!(states[0].output_path.as_deref(), Some("/files/a.txt.sfs"));
+        assert_eq!(states[1].input_path, "/files/b.txt");
+        assert_eq!(states[1].state, JournalState::Pending);
+        assert_eq!(states[1].output_path, None);
+    }
+
+    #[test]
+    fn only_temporary_files_of_the_output_are_removed() {
+        let directory = tempfile::tempdir().unwrap();
+        let output_path = directory.path().join("file.txt.sfs");
+        for name in [
+            "file.txt.sfs",
+            ".file.txt.sfs.Ab3dE6gH.tmp",
+            ".file.txt.sfs.short.tmp",
+            ".other.txt.sfs.Ab3dE6gH.tmp",
+            ".file.txt.sfs.Ab3dE6gH",
+        ] {
+            fs::write(directory.path().join(name), "").unwrap();
+        }
+        fs::create_dir(directory.path().join(".file.txt.sfs.0123abcd.tmp")).unwrap();
+        fs::write(
+            directory.path().join(".file.txt.sfs.0123abcd.tmp/file.txt"),
+            "",
+        )
+        .unwrap();
+
+        assert_eq!(remove_temporary_files(&output_path), 2);
+        let mut file_names: Vec<_> = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        file_names.sort();
+        assert_eq!(
+            file_names,
+            [
+                ".file.txt.sfs.Ab3dE6gH",
+                ".file.txt.sfs.short.tmp",
+                ".other.txt.sfs.Ab3dE6gH.tmp",
+                "file.txt.sfs"
+            ]
+        );
+    }
+}
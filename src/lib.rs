@@ -1,12 +1,192 @@
-use std::fmt;
+use aes_gcm::Aes256Gcm;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use hkdf::Hkdf;
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Write};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
 use xxhash_rust::xxh3::Xxh3;
 use xxhash_rust::xxh32::Xxh32;
 use xxhash_rust::xxh64::Xxh64;
+use zeroize::{Zeroize, Zeroizing};
 #[macro_use]
 extern crate structure;
 
-pub const SFS_FORMAT_VERSION: u8 = 2;
+pub const SFS_FORMAT_VERSION: u8 = 3;
+pub const SFS_OLDEST_FORMAT_VERSION: u8 = 2;
 pub const SFS_VERSION_STRING: &str = env!("CARGO_PKG_VERSION");
+pub const SFS_MAGIC: &[u8] = b"SFS";
+pub const SFS_PUBLIC_KEY_PREFIX: &str = "sfs-public-";
+pub const SFS_SECRET_KEY_PREFIX: &str = "sfs-secret-";
+
+/// Everything that can go wrong while reading or writing SFS files.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// Neither a password nor a keyfile was given.
+    MissingCredentials,
+    /// None of the key slots (or the metadata of a v1/v2 file) could be
+    /// decrypted with the password, keyfile or identity.
+    WrongKey,
+    /// The file was encrypted with a different combination of password and keyfile.
+    WrongFactors {
+        required: u8,
+        given: u8,
+    },
+    InvalidKey(String),
+    InvalidVerifier(String),
+    KeyDerivationFailed(String),
+    UnsupportedVersion(u8),
+    UnsupportedCipher(u8),
+    UnsupportedKeyDerivationFunction(u8),
+    UnsupportedCompression(u8),
+    CorruptHeader(String),
+    /// The metadata record failed authentication after the key was unlocked,
+    /// which means that the header was taken from a different file (or that
+    /// the metadata was modified).
+    HeaderMismatch,
+    CorruptMetadata(String),
+    CorruptTrailer(String),
+    CorruptPadding(String),
+    /// A chunk was modified, or chunks were dropped, duplicated or reordered.
+    ChunkAuthFailed {
+        index: u64,
+    },
+    /// An authenticated chunk couldn't be decompressed, or was bigger than the chunk size.
+    DecompressionFailed {
+        index: u64,
+    },
+    /// The file isn't an archive made by `encrypt_archive`.
+    NotAnArchive,
+    /// The file ends in the middle of a record, or before the trailer.
+    Truncated,
+    TrailingData,
+    ChecksumMismatch {
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+}
+impl fmt::Display for Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(formatter, "{}", error),
+            Error::MissingCredentials => {
+                write!(formatter, "neither a password nor a keyfile was given")
+            }
+            Error::WrongKey => write!(
+                formatter,
+                "the file can't be unlocked with your password, keyfile or identity"
+            ),
+            Error::WrongFactors { required, given } => write!(
+                formatter,
+                "this file requires {}, but {} was given",
+                describe_factors(*required),
+                describe_factors(*given)
+            ),
+            Error::InvalidKey(reason) => write!(formatter, "invalid key: {}", reason),
+            Error::InvalidVerifier(reason) => {
+                write!(formatter, "invalid password verifier: {}", reason)
+            }
+            Error::KeyDerivationFailed(reason) => {
+                write!(formatter, "unable to derive key: {}", reason)
+            }
+            Error::UnsupportedVersion(version) => write!(
+                formatter,
+                "file format v{} is not supported (only v{} to v{} are)",
+                version, SFS_OLDEST_FORMAT_VERSION, SFS_FORMAT_VERSION
+            ),
+            Error::UnsupportedCipher(cipher) => match Cipher::from_u8(*cipher) {
+                Some(cipher) => write!(
+                    formatter,
+                    "{} can only be used by files older than v3",
+                    cipher
+                ),
+                None => write!(formatter, "unknown cipher {}", cipher),
+            },
+            Error::UnsupportedKeyDerivationFunction(key_derivation_function) => {
+                match KeyDerivationFunction::from_u8(*key_derivation_function) {
+                    Some(key_derivation_function) => write!(
+                        formatter,
+                        "{} can only be used by files older than v3",
+                        key_derivation_function
+                    ),
+                    None => write!(
+                        formatter,
+                        "unknown key derivation function {}",
+                        key_derivation_function
+                    ),
+                }
+            }
+            Error::UnsupportedCompression(compression) => {
+                write!(formatter, "unknown compression {}", compression)
+            }
+            Error::CorruptHeader(reason) => write!(formatter, "corrupt header: {}", reason),
+            Error::HeaderMismatch => write!(formatter, "this header does not belong to this file"),
+            Error::CorruptMetadata(reason) => write!(formatter, "corrupt metadata: {}", reason),
+            Error::CorruptTrailer(reason) => write!(formatter, "corrupt trailer: {}", reason),
+            Error::CorruptPadding(reason) => write!(formatter, "corrupt padding: {}", reason),
+            Error::ChunkAuthFailed { index } => write!(
+                formatter,
+                "chunk {} failed authentication (missing, duplicated, reordered, corrupted or from a different file)",
+                index
+            ),
+            Error::DecompressionFailed { index } => {
+                write!(formatter, "chunk {} could not be decompressed", index)
+            }
+            Error::NotAnArchive => write!(formatter, "the file is not an archive"),
+            Error::Truncated => write!(formatter, "the file is truncated"),
+            Error::TrailingData => write!(formatter, "unexpected data after the trailer"),
+            Error::ChecksumMismatch { expected, actual } => write!(
+                formatter,
+                "expected checksum {} but got {}",
+                encode_hex(expected),
+                encode_hex(actual)
+            ),
+        }
+    }
+}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+/// Errors from a `DecryptReader` are turned back into the `Error` they came from.
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        if error.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            if let Ok(inner) = error.into_inner().unwrap().downcast::<Error>() {
+                return *inner;
+            }
+            unreachable!()
+        }
+        Error::Io(error)
+    }
+}
+impl From<Error> for std::io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(error) => error,
+            error => std::io::Error::new(std::io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
 
 #[derive(Debug, Default, Clone)]
 pub struct FileMetadata {
@@ -15,53 +195,73 @@ pub struct FileMetadata {
     pub restore_name: bool,
     pub total_bytes: u64,
     pub hashing_algorithm: u8,
-    pub checksum: u64,
+    pub checksum: Vec<u8>,
     pub chunk_size: u64,
+    pub header_checksum: Vec<u8>,
+    pub compression: u8,
+    /// The data is a tar archive of a directory, made by `encrypt_archive`.
+    pub archive: bool,
 }
 impl FileMetadata {
+    /// Since v3, `total_bytes` and `checksum` are stored in the `FileTrailer` instead.
+    #[allow(clippy::too_many_arguments, clippy::unused_io_amount)]
     pub fn pack(&self) -> Vec<u8> {
         let mut original_name = self.original_name.clone().into_bytes().to_vec();
         for _ in 0..255 - original_name.len() {
             original_name.push(0)
         }
 
-        let metadata_structure = structure!("B255S?QBQQ");
+        let metadata_structure = structure!("B255S?BQ32sB?");
         metadata_structure
             .pack(
                 self.format_version,
                 &original_name,
                 self.restore_name,
-                self.total_bytes,
                 self.hashing_algorithm,
-                self.checksum,
                 self.chunk_size,
+                &self.header_checksum,
+                self.compression,
+                self.archive,
             )
             .unwrap()
     }
 
-    pub fn parse(metadata_bytes: &Vec<u8>) -> Result<Self, String> {
-        let version_structure = structure!("B");
-        let version_metadata =
-            match version_structure.unpack(&metadata_bytes[..version_structure.size()]) {
-                Ok(version_metadata) => version_metadata,
-                Err(error) => return Err(error.to_string()),
-            };
-        match version_metadata.0 {
+    #[allow(clippy::too_many_arguments, clippy::unused_io_amount)]
+    pub fn parse(metadata_bytes: &[u8]) -> Result<Self, Error> {
+        let version = match metadata_bytes.first() {
+            Some(version) => *version,
+            None => return Err(Error::CorruptMetadata(String::from("metadata is empty"))),
+        };
+        let metadata_size = match version {
+            1 => structure!("BBQQQ").size(),
+            2 => structure!("B255S?QBQQ").size(),
+            3 => structure!("B255S?BQ32sB?").size(),
+            _ => return Err(Error::UnsupportedVersion(version)),
+        };
+        if metadata_bytes.len() < metadata_size {
+            return Err(Error::CorruptMetadata(String::from(
+                "metadata has an invalid size",
+            )));
+        }
+        match version {
             1 => {
                 let metadata_structure = structure!("BBQQQ");
                 let metadata =
                     match metadata_structure.unpack(&metadata_bytes[..metadata_structure.size()]) {
                         Ok(metadata) => metadata,
-                        Err(error) => return Err(error.to_string()),
+                        Err(error) => return Err(Error::CorruptMetadata(error.to_string())),
                     };
                 Ok(FileMetadata {
                     format_version: metadata.0,
                     hashing_algorithm: metadata.1,
-                    checksum: metadata.2,
+                    checksum: HashingAlgorithm::from_u8(metadata.1).legacy_checksum(metadata.2),
                     total_bytes: metadata.3,
                     chunk_size: metadata.4,
                     original_name: String::from("UNSUPPORTED"),
                     restore_name: false,
+                    header_checksum: Vec::new(),
+                    compression: Compression::None as u8,
+                    archive: false,
                 })
             }
             2 => {
@@ -69,7 +269,7 @@ impl FileMetadata {
                 let metadata =
                     match metadata_structure.unpack(&metadata_bytes[..metadata_structure.size()]) {
                         Ok(metadata) => metadata,
-                        Err(error) => return Err(error.to_string()),
+                        Err(error) => return Err(Error::CorruptMetadata(error.to_string())),
                     };
                 Ok(FileMetadata {
                     format_version: metadata.0,
@@ -80,189 +280,3647 @@ impl FileMetadata {
                     restore_name: metadata.2,
                     total_bytes: metadata.3,
                     hashing_algorithm: metadata.4,
-                    checksum: metadata.5,
+                    checksum: HashingAlgorithm::from_u8(metadata.4).legacy_checksum(metadata.5),
                     chunk_size: metadata.6,
+                    header_checksum: Vec::new(),
+                    compression: Compression::None as u8,
+                    archive: false,
+                })
+            }
+            3 => {
+                let metadata_structure = structure!("B255S?BQ32sB?");
+                let metadata =
+                    match metadata_structure.unpack(&metadata_bytes[..metadata_structure.size()]) {
+                        Ok(metadata) => metadata,
+                        Err(error) => return Err(Error::CorruptMetadata(error.to_string())),
+                    };
+                if Compression::from_u8(metadata.6).is_none() {
+                    return Err(Error::UnsupportedCompression(metadata.6));
+                }
+                Ok(FileMetadata {
+                    format_version: metadata.0,
+                    original_name: std::str::from_utf8(&metadata.1)
+                        .unwrap_or_default()
+                        .trim_matches(char::from(0))
+                        .to_string(),
+                    restore_name: metadata.2,
+                    total_bytes: 0,
+                    hashing_algorithm: metadata.3,
+                    checksum: Vec::new(),
+                    chunk_size: metadata.4,
+                    header_checksum: metadata.5,
+                    compression: metadata.6,
+                    archive: metadata.7,
                 })
             }
-            _ => Ok(FileMetadata {
-                format_version: version_metadata.0,
-                original_name: String::new(),
-                restore_name: false,
-                total_bytes: 0,
-                hashing_algorithm: 0,
-                checksum: 0,
-                chunk_size: 0,
-            }),
+            _ => unreachable!(),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum HashingAlgorithm {
-    None = 0,
-    Xxh3 = 1,
-    Xxh64 = 2,
-    Xxh32 = 3,
+#[derive(Debug, Default, Clone)]
+pub struct FileTrailer {
+    pub chunk_count: u64,
+    pub total_bytes: u64,
+    /// The size of the chunks after compression (and before encryption).
+    pub stored_bytes: u64,
+    pub padding_bytes: u64,
+    pub checksum: Vec<u8>,
 }
-impl fmt::Display for HashingAlgorithm {
-    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        write!(formatter, "{}", format!("{:?}", self).to_uppercase())
+impl FileTrailer {
+    /// The checksum is stored after its length, since digests can be anywhere
+    /// from 0 (no hashing algorithm) to 32 bytes (BLAKE3 and SHA-256) long.
+    #[allow(clippy::unused_io_amount)]
+    pub fn pack(&self) -> Vec<u8> {
+        let trailer_structure = structure!("QQQQB");
+        let mut trailer = trailer_structure
+            .pack(
+                self.chunk_count,
+                self.total_bytes,
+                self.stored_bytes,
+                self.padding_bytes,
+                self.checksum.len() as u8,
+            )
+            .unwrap();
+        trailer.extend_from_slice(&self.checksum);
+        trailer
+    }
+
+    #[allow(clippy::unused_io_amount)]
+    pub fn parse(trailer_bytes: &[u8]) -> Result<Self, Error> {
+        let trailer_structure = structure!("QQQQB");
+        if trailer_bytes.len() < trailer_structure.size() {
+            return Err(Error::CorruptTrailer(String::from(
+                "trailer has an invalid size",
+            )));
+        }
+        let trailer = match trailer_structure.unpack(&trailer_bytes[..trailer_structure.size()]) {
+            Ok(trailer) => trailer,
+            Err(error) => return Err(Error::CorruptTrailer(error.to_string())),
+        };
+        let checksum = &trailer_bytes[trailer_structure.size()..];
+        if checksum.len() != trailer.4 as usize {
+            return Err(Error::CorruptTrailer(String::from(
+                "trailer has an invalid size",
+            )));
+        }
+        Ok(FileTrailer {
+            chunk_count: trailer.0,
+            total_bytes: trailer.1,
+            stored_bytes: trailer.2,
+            padding_bytes: trailer.3,
+            checksum: checksum.to_vec(),
+        })
     }
 }
-impl HashingAlgorithm {
-    pub fn list() -> &'static [HashingAlgorithm] {
-        &[
-            HashingAlgorithm::None,
-            HashingAlgorithm::Xxh3,
-            HashingAlgorithm::Xxh64,
-            HashingAlgorithm::Xxh32,
-        ]
+
+/// The plaintext header at the start of every v3 file. Every file is encrypted
+/// with its own random content key, which is wrapped once for every key slot.
+#[derive(Debug, Clone)]
+pub struct FileHeader {
+    pub format_version: u8,
+    pub cipher: u8,
+    pub nonce: Vec<u8>,
+    pub file_id: Vec<u8>,
+    pub key_slots: Vec<KeySlot>,
+}
+impl FileHeader {
+    pub fn new(cipher: Cipher) -> Self {
+        let mut nonce = vec![0; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let mut file_id = vec![0; 16];
+        rand::thread_rng().fill_bytes(&mut file_id);
+        FileHeader {
+            format_version: SFS_FORMAT_VERSION,
+            cipher: cipher as u8,
+            nonce,
+            file_id,
+            key_slots: Vec::new(),
+        }
     }
 
-    pub fn from_u8(value: u8) -> HashingAlgorithm {
-        let mut value = value.into();
-        let hashing_algorithm_list = HashingAlgorithm::list();
-        if value >= hashing_algorithm_list.len() {
-            value = 0
+    #[allow(clippy::unused_io_amount)]
+    fn pack_fixed(&self) -> Vec<u8> {
+        let header_structure = structure!("3sBB16s16s");
+        header_structure
+            .pack(
+                SFS_MAGIC,
+                self.format_version,
+                self.cipher,
+                &self.nonce,
+                &self.file_id,
+            )
+            .unwrap()
+    }
+
+    pub fn pack(&self) -> Vec<u8> {
+        let mut header = self.pack_fixed();
+        header.push(self.key_slots.len() as u8);
+        for key_slot in &self.key_slots {
+            header.append(&mut key_slot.pack());
         }
-        hashing_algorithm_list[value]
+        header
+    }
+
+    /// Files older than v3 start with the encrypted metadata line instead of a header,
+    /// in which case `None` is returned and `generate_legacy_fernet` has to be used.
+    #[allow(clippy::too_many_arguments, clippy::unused_io_amount)]
+    pub fn read(reader: &mut impl BufRead) -> Result<Option<Self>, Error> {
+        if !reader.fill_buf()?.starts_with(SFS_MAGIC) {
+            return Ok(None);
+        }
+        let header_structure = structure!("3sBB16s16sB");
+        let mut header_bytes = vec![0; header_structure.size()];
+        read_exact(reader, &mut header_bytes)?;
+        let header = header_structure.unpack(&header_bytes)?;
+        if header.1 > SFS_FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(header.1));
+        }
+        // Files older than v3 don't have a header, so the magic can't be followed by their version
+        if header.1 < 3 {
+            return Err(Error::CorruptHeader(format!(
+                "v{} files don't have a header",
+                header.1
+            )));
+        }
+        let mut key_slots = Vec::new();
+        for _ in 0..header.5 {
+            key_slots.push(KeySlot::read(reader)?);
+        }
+        Ok(Some(FileHeader {
+            format_version: header.1,
+            cipher: header.2,
+            nonce: header.3,
+            file_id: header.4,
+            key_slots,
+        }))
+    }
+
+    /// The key slots are left out, so that they can be replaced without
+    /// re-encrypting the metadata.
+    pub fn checksum(&self) -> Vec<u8> {
+        Sha256::digest(self.pack_fixed()).to_vec()
+    }
+
+    /// The metadata record is bound to the file ID like the other records are,
+    /// so this only has to check that the rest of the header wasn't modified.
+    pub fn verify_metadata(&self, metadata: &FileMetadata) -> Result<(), Error> {
+        if metadata.header_checksum != self.checksum() {
+            return Err(Error::CorruptHeader(String::from(
+                "the header has been modified since the file was encrypted",
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn add_password(
+        &mut self,
+        content_key: &[u8],
+        credentials: &Credentials,
+        memory_cost: u32,
+        time_cost: u32,
+        parallelism: u32,
+    ) -> Result<(), Error> {
+        self.key_slots.push(KeySlot::new_password(
+            content_key,
+            credentials,
+            memory_cost,
+            time_cost,
+            parallelism,
+        )?);
+        Ok(())
+    }
+
+    /// Replaces the password key slots with a new one, keeping the recipients.
+    pub fn set_password(
+        &mut self,
+        content_key: &[u8],
+        credentials: &Credentials,
+        memory_cost: u32,
+        time_cost: u32,
+        parallelism: u32,
+    ) -> Result<(), Error> {
+        let key_slots = self.key_slots.clone();
+        self.key_slots
+            .retain(|key_slot| !matches!(key_slot, KeySlot::Password { .. }));
+        match self.add_password(
+            content_key,
+            credentials,
+            memory_cost,
+            time_cost,
+            parallelism,
+        ) {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                self.key_slots = key_slots;
+                Err(error)
+            }
+        }
+    }
+
+    pub fn has_password(&self) -> bool {
+        self.key_slots
+            .iter()
+            .any(|key_slot| matches!(key_slot, KeySlot::Password { .. }))
+    }
+
+    pub fn add_recipient(&mut self, content_key: &[u8], public_key: &PublicKey) {
+        let ephemeral_secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        let ephemeral_public_key = PublicKey::from(&ephemeral_secret);
+        let key = derive_recipient_key(
+            ephemeral_secret.diffie_hellman(public_key).as_bytes(),
+            &ephemeral_public_key,
+            public_key,
+        );
+        self.key_slots.push(KeySlot::X25519 {
+            ephemeral_public_key: ephemeral_public_key.as_bytes().to_vec(),
+            wrapped_key: wrap_key(&key, content_key),
+        });
+    }
+
+    /// Tries every key slot with the credentials and identity, and returns the
+    /// content key from the first one that can be unlocked.
+    pub fn unwrap_content_key(
+        &self,
+        credentials: &Credentials,
+        identity: Option<&Identity>,
+    ) -> Result<SecretBytes, Error> {
+        let mut last_error = None;
+        let mut factor_error = None;
+        for key_slot in &self.key_slots {
+            let key = match key_slot {
+                KeySlot::Password {
+                    key_derivation_function,
+                    factors,
+                    memory_cost,
+                    time_cost,
+                    parallelism,
+                    salt,
+                    ..
+                } => {
+                    if *factors != credentials.factors() {
+                        factor_error = Some(Error::WrongFactors {
+                            required: *factors,
+                            given: credentials.factors(),
+                        });
+                        continue;
+                    }
+                    match derive_password_key(
+                        *key_derivation_function,
+                        *memory_cost,
+                        *time_cost,
+                        *parallelism,
+                        salt,
+                        credentials,
+                    ) {
+                        Ok(key) => key,
+                        Err(error) => {
+                            last_error = Some(error);
+                            continue;
+                        }
+                    }
+                }
+                KeySlot::X25519 {
+                    ephemeral_public_key,
+                    ..
+                } => match (
+                    identity,
+                    <[u8; 32]>::try_from(ephemeral_public_key.as_slice()),
+                ) {
+                    (Some(identity), Ok(ephemeral_public_key)) => {
+                        let ephemeral_public_key = PublicKey::from(ephemeral_public_key);
+                        derive_recipient_key(
+                            identity
+                                .secret
+                                .diffie_hellman(&ephemeral_public_key)
+                                .as_bytes(),
+                            &ephemeral_public_key,
+                            &identity.public_key(),
+                        )
+                    }
+                    _ => continue,
+                },
+                KeySlot::Unknown { .. } => continue,
+            };
+            if let Some(content_key) = unwrap_key(&key, key_slot.wrapped_key()) {
+                return Ok(content_key);
+            }
+        }
+        Err(last_error.or(factor_error).unwrap_or(Error::WrongKey))
     }
 }
 
-pub trait Hasher {
-    fn update(&mut self, _: &[u8]);
-    fn digest(&mut self) -> u64;
-    fn reset(&mut self);
+#[derive(Debug, Clone)]
+pub enum KeySlot {
+    Password {
+        key_derivation_function: u8,
+        factors: u8,
+        memory_cost: u32,
+        time_cost: u32,
+        parallelism: u32,
+        salt: Vec<u8>,
+        wrapped_key: Vec<u8>,
+    },
+    X25519 {
+        ephemeral_public_key: Vec<u8>,
+        wrapped_key: Vec<u8>,
+    },
+    /// Key slots from newer versions of SFS, which are skipped.
+    Unknown { kind: u8, data: Vec<u8> },
 }
+impl KeySlot {
+    pub fn new_password(
+        content_key: &[u8],
+        credentials: &Credentials,
+        memory_cost: u32,
+        time_cost: u32,
+        parallelism: u32,
+    ) -> Result<Self, Error> {
+        let mut salt = vec![0; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let key_derivation_function = KeyDerivationFunction::Argon2id as u8;
+        let factors = credentials.factors();
+        if factors == 0 {
+            return Err(Error::MissingCredentials);
+        }
+        let key = derive_password_key(
+            key_derivation_function,
+            memory_cost,
+            time_cost,
+            parallelism,
+            &salt,
+            credentials,
+        )?;
+        Ok(KeySlot::Password {
+            key_derivation_function,
+            factors,
+            memory_cost,
+            time_cost,
+            parallelism,
+            salt,
+            wrapped_key: wrap_key(&key, content_key),
+        })
+    }
 
-#[derive(Clone)]
-pub struct DummyHasher {}
-impl Hasher for DummyHasher {
-    fn update(&mut self, _: &[u8]) {}
-    fn digest(&mut self) -> u64 {
-        0
+    pub fn kind(&self) -> u8 {
+        match self {
+            KeySlot::Password { .. } => 0,
+            KeySlot::X25519 { .. } => 1,
+            KeySlot::Unknown { kind, .. } => *kind,
+        }
+    }
+
+    fn wrapped_key(&self) -> &[u8] {
+        match self {
+            KeySlot::Password { wrapped_key, .. } => wrapped_key,
+            KeySlot::X25519 { wrapped_key, .. } => wrapped_key,
+            KeySlot::Unknown { .. } => &[],
+        }
+    }
+
+    /// Every key slot is a kind and a 16-bit length followed by the data of the slot.
+    #[allow(clippy::too_many_arguments, clippy::unused_io_amount)]
+    pub fn pack(&self) -> Vec<u8> {
+        let data = match self {
+            KeySlot::Password {
+                key_derivation_function,
+                factors,
+                memory_cost,
+                time_cost,
+                parallelism,
+                salt,
+                wrapped_key,
+            } => structure!("BBIII16s48s")
+                .pack(
+                    *key_derivation_function,
+                    *factors,
+                    *memory_cost,
+                    *time_cost,
+                    *parallelism,
+                    salt,
+                    wrapped_key,
+                )
+                .unwrap(),
+            KeySlot::X25519 {
+                ephemeral_public_key,
+                wrapped_key,
+            } => structure!("32s48s")
+                .pack(ephemeral_public_key, wrapped_key)
+                .unwrap(),
+            KeySlot::Unknown { data, .. } => data.clone(),
+        };
+        let mut key_slot = structure!("BH")
+            .pack(self.kind(), data.len() as u16)
+            .unwrap();
+        key_slot.extend_from_slice(&data);
+        key_slot
+    }
+
+    #[allow(clippy::too_many_arguments, clippy::unused_io_amount)]
+    pub fn read(reader: &mut impl Read) -> Result<Self, Error> {
+        let slot_structure = structure!("BH");
+        let mut slot_bytes = vec![0; slot_structure.size()];
+        read_exact(reader, &mut slot_bytes)?;
+        let (kind, length) = slot_structure.unpack(&slot_bytes)?;
+        let mut data = vec![0; length as usize];
+        read_exact(reader, &mut data)?;
+        match kind {
+            0 => {
+                let password_structure = structure!("BBIII16s48s");
+                if data.len() != password_structure.size() {
+                    return Err(Error::CorruptHeader(String::from(
+                        "password key slot has an invalid size",
+                    )));
+                }
+                let key_slot = password_structure.unpack(&data)?;
+                Ok(KeySlot::Password {
+                    key_derivation_function: key_slot.0,
+                    factors: key_slot.1,
+                    memory_cost: key_slot.2,
+                    time_cost: key_slot.3,
+                    parallelism: key_slot.4,
+                    salt: key_slot.5,
+                    wrapped_key: key_slot.6,
+                })
+            }
+            1 => {
+                let recipient_structure = structure!("32s48s");
+                if data.len() != recipient_structure.size() {
+                    return Err(Error::CorruptHeader(String::from(
+                        "X25519 key slot has an invalid size",
+                    )));
+                }
+                let key_slot = recipient_structure.unpack(&data)?;
+                Ok(KeySlot::X25519 {
+                    ephemeral_public_key: key_slot.0,
+                    wrapped_key: key_slot.1,
+                })
+            }
+            _ => Ok(KeySlot::Unknown { kind, data }),
+        }
     }
-    fn reset(&mut self) {}
 }
 
-#[derive(Clone)]
-pub struct Xxh3Hasher {
-    pub hasher: Xxh3,
+/// `Read::read_exact`, except that running out of data is reported as `Error::Truncated`.
+fn read_exact(reader: &mut impl Read, buffer: &mut [u8]) -> Result<(), Error> {
+    match reader.read_exact(buffer) {
+        Ok(_) => Ok(()),
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => Err(Error::Truncated),
+        Err(error) => Err(Error::Io(error)),
+    }
 }
-impl Hasher for Xxh3Hasher {
-    fn update(&mut self, data: &[u8]) {
-        self.hasher.update(data);
+
+pub const FACTOR_PASSWORD: u8 = 1;
+pub const FACTOR_KEYFILE: u8 = 2;
+
+/// What files are unlocked with: a password, a keyfile, or both. The password
+/// is locked in memory and zeroed when the credentials are dropped.
+pub struct Credentials {
+    pub password: String,
+    /// The SHA-256 digest of the keyfile, so that keyfiles of any size can be used.
+    pub keyfile: Option<SecretBytes>,
+}
+impl Credentials {
+    pub fn new(password: String, keyfile: Option<&[u8]>) -> Self {
+        Credentials::with_keyfile_digest(password, keyfile.map(hash_keyfile))
     }
 
-    fn digest(&mut self) -> u64 {
-        self.hasher.digest()
+    pub fn with_keyfile_digest(password: String, keyfile: Option<SecretBytes>) -> Self {
+        lock_memory(password.as_bytes());
+        Credentials { password, keyfile }
     }
 
-    fn reset(&mut self) {
-        self.hasher.reset()
+    pub fn factors(&self) -> u8 {
+        let mut factors = 0;
+        if !self.password.is_empty() {
+            factors |= FACTOR_PASSWORD
+        }
+        if self.keyfile.is_some() {
+            factors |= FACTOR_KEYFILE
+        }
+        factors
     }
 }
 
-pub struct Xxh64Hasher {
-    pub hasher: Xxh64,
+pub fn hash_keyfile(keyfile: &[u8]) -> SecretBytes {
+    let mut digest = Sha256::digest(keyfile);
+    let keyfile_digest = SecretBytes::new(digest.to_vec());
+    digest.as_mut_slice().zeroize();
+    keyfile_digest
 }
-impl Hasher for Xxh64Hasher {
-    fn update(&mut self, data: &[u8]) {
-        self.hasher.update(data);
+
+impl Clone for Credentials {
+    fn clone(&self) -> Self {
+        Credentials::with_keyfile_digest(self.password.clone(), self.keyfile.clone())
+    }
+}
+impl Drop for Credentials {
+    fn drop(&mut self) {
+        let (pointer, length) = (self.password.as_ptr(), self.password.len());
+        self.password.zeroize();
+        unlock_memory(pointer, length);
     }
+}
 
-    fn digest(&mut self) -> u64 {
-        self.hasher.digest()
+pub fn describe_factors(factors: u8) -> &'static str {
+    match factors {
+        FACTOR_PASSWORD => "a password",
+        FACTOR_KEYFILE => "a keyfile",
+        factors if factors == FACTOR_PASSWORD | FACTOR_KEYFILE => "a password and a keyfile",
+        _ => "nothing",
     }
+}
 
-    fn reset(&mut self) {
-        self.hasher.reset(0)
+/// Bytes that are locked in memory where possible (so that they're never
+/// written to swap or core dumps) and zeroed when they're dropped.
+pub struct SecretBytes {
+    bytes: Box<[u8]>,
+}
+impl SecretBytes {
+    /// Moves the bytes into locked memory and zeroes the original vector.
+    pub fn new(mut bytes: Vec<u8>) -> Self {
+        let mut secret_bytes = SecretBytes::zeroed(bytes.len());
+        secret_bytes.copy_from_slice(&bytes);
+        bytes.zeroize();
+        secret_bytes
+    }
+
+    pub fn zeroed(length: usize) -> Self {
+        let bytes = vec![0; length].into_boxed_slice();
+        lock_memory(&bytes);
+        SecretBytes { bytes }
     }
 }
+impl Deref for SecretBytes {
+    type Target = [u8];
 
-pub struct Xxh32Hasher {
-    pub hasher: Xxh32,
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
 }
-impl Hasher for Xxh32Hasher {
-    fn update(&mut self, data: &[u8]) {
-        self.hasher.update(data);
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+impl Clone for SecretBytes {
+    fn clone(&self) -> Self {
+        let mut secret_bytes = SecretBytes::zeroed(self.len());
+        secret_bytes.copy_from_slice(self);
+        secret_bytes
     }
+}
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "SecretBytes([REDACTED; {}])", self.len())
+    }
+}
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+        unlock_memory(self.bytes.as_ptr(), self.bytes.len());
+    }
+}
 
-    fn digest(&mut self) -> u64 {
-        self.hasher.digest().into()
+/// Failing to lock memory (because of `RLIMIT_MEMLOCK`, for example) isn't fatal,
+/// the secret is still zeroed when it's dropped.
+#[cfg(unix)]
+fn lock_memory(bytes: &[u8]) {
+    if !bytes.is_empty() {
+        unsafe {
+            libc::mlock(bytes.as_ptr() as *const libc::c_void, bytes.len());
+        }
     }
+}
 
-    fn reset(&mut self) {
-        self.hasher.reset(0)
+#[cfg(unix)]
+fn unlock_memory(pointer: *const u8, length: usize) {
+    if length != 0 {
+        unsafe {
+            libc::munlock(pointer as *const libc::c_void, length);
+        }
     }
 }
 
-fn get_hasher(hashing_algorithm: HashingAlgorithm) -> Box<dyn Hasher> {
-    match hashing_algorithm {
-        HashingAlgorithm::None => Box::new(DummyHasher {}),
-        HashingAlgorithm::Xxh3 => Box::new(Xxh3Hasher {
-            hasher: Xxh3::new(),
-        }),
-        HashingAlgorithm::Xxh64 => Box::new(Xxh64Hasher {
-            hasher: Xxh64::new(0),
-        }),
-        HashingAlgorithm::Xxh32 => Box::new(Xxh32Hasher {
-            hasher: Xxh32::new(0),
-        }),
+#[cfg(not(unix))]
+fn lock_memory(_: &[u8]) {}
+
+#[cfg(not(unix))]
+fn unlock_memory(_: *const u8, _: usize) {}
+
+/// Generates a random key for the contents of a file.
+pub fn generate_content_key() -> SecretBytes {
+    let mut content_key = SecretBytes::zeroed(32);
+    rand::rngs::OsRng.fill_bytes(&mut content_key);
+    content_key
+}
+
+/// Every wrapping key is only ever used once (the salt and ephemeral keys are
+/// random for every key slot), so a fixed nonce is fine here.
+fn wrap_key(key: &[u8], content_key: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new_from_slice(key)
+        .unwrap()
+        .encrypt(&Default::default(), content_key)
+        .unwrap()
+}
+
+fn unwrap_key(key: &[u8], wrapped_key: &[u8]) -> Option<SecretBytes> {
+    ChaCha20Poly1305::new_from_slice(key)
+        .ok()?
+        .decrypt(&Default::default(), wrapped_key)
+        .ok()
+        .map(SecretBytes::new)
+}
+
+fn derive_password_key(
+    key_derivation_function: u8,
+    memory_cost: u32,
+    time_cost: u32,
+    parallelism: u32,
+    salt: &[u8],
+    credentials: &Credentials,
+) -> Result<SecretBytes, Error> {
+    match KeyDerivationFunction::from_u8(key_derivation_function) {
+        Some(KeyDerivationFunction::Argon2id) => {
+            let parameters = match Params::new(memory_cost, time_cost, parallelism, Some(32)) {
+                Ok(parameters) => parameters,
+                Err(error) => return Err(Error::KeyDerivationFailed(error.to_string())),
+            };
+            // The keyfile is used as the Argon2 secret when it's combined with a password
+            let (argon2, input) = match (credentials.password.is_empty(), &credentials.keyfile) {
+                (false, Some(keyfile)) => (
+                    match Argon2::new_with_secret(
+                        keyfile,
+                        Algorithm::Argon2id,
+                        Version::V0x13,
+                        parameters,
+                    ) {
+                        Ok(argon2) => argon2,
+                        Err(error) => return Err(Error::KeyDerivationFailed(error.to_string())),
+                    },
+                    credentials.password.as_bytes(),
+                ),
+                (true, Some(keyfile)) => (
+                    Argon2::new(Algorithm::Argon2id, Version::V0x13, parameters),
+                    &keyfile[..],
+                ),
+                _ => (
+                    Argon2::new(Algorithm::Argon2id, Version::V0x13, parameters),
+                    credentials.password.as_bytes(),
+                ),
+            };
+            let mut key = SecretBytes::zeroed(32);
+            match argon2.hash_password_into(input, salt, &mut key) {
+                Ok(_) => (),
+                Err(error) => return Err(Error::KeyDerivationFailed(error.to_string())),
+            };
+            Ok(key)
+        }
+        _ => Err(Error::UnsupportedKeyDerivationFunction(
+            key_derivation_function,
+        )),
     }
 }
 
-pub struct Encrypter<'a> {
-    pub fernet: fernet::Fernet,
-    pub hasher: Box<dyn Hasher + 'a>,
-    pub total_bytes: u64,
+fn derive_recipient_key(
+    shared_secret: &[u8],
+    ephemeral_public_key: &PublicKey,
+    public_key: &PublicKey,
+) -> SecretBytes {
+    let mut salt = ephemeral_public_key.as_bytes().to_vec();
+    salt.extend_from_slice(public_key.as_bytes());
+    let mut key = SecretBytes::zeroed(32);
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret)
+        .expand(b"sfs x25519 key slot", &mut key)
+        .unwrap();
+    key
 }
-impl<'a> Encrypter<'a> {
-    pub fn new(fernet: fernet::Fernet, hashing_algorithm: HashingAlgorithm) -> Encrypter<'a> {
-        Encrypter {
-            fernet,
-            hasher: get_hasher(hashing_algorithm),
-            total_bytes: 0,
+
+/// An X25519 private key, which can decrypt files that were encrypted for
+/// its public key.
+pub struct Identity {
+    secret: StaticSecret,
+}
+impl Identity {
+    pub fn generate() -> Self {
+        Identity {
+            secret: StaticSecret::random_from_rng(rand::rngs::OsRng),
         }
     }
 
-    pub fn get_checksum(&mut self) -> u64 {
-        self.hasher.digest()
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        Ok(Identity {
+            secret: StaticSecret::from(decode_key(text, SFS_SECRET_KEY_PREFIX)?),
+        })
     }
 
-    pub fn encrypt(&mut self, data: &[u8]) -> String {
-        self.total_bytes += data.len() as u64;
-        self.hasher.update(&data);
-        self.fernet.encrypt(&data)
+    pub fn encode(&self) -> String {
+        encode_key(self.secret.as_bytes(), SFS_SECRET_KEY_PREFIX)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.secret)
     }
 }
 
-pub struct Decrypter<'a> {
-    pub fernet: fernet::Fernet,
-    pub hasher: Box<dyn Hasher + 'a>,
-    pub total_bytes: u64,
+pub fn encode_public_key(public_key: &PublicKey) -> String {
+    encode_key(public_key.as_bytes(), SFS_PUBLIC_KEY_PREFIX)
 }
-impl<'a> Decrypter<'a> {
-    pub fn new(fernet: fernet::Fernet, hashing_algorithm: HashingAlgorithm) -> Decrypter<'a> {
-        Decrypter {
-            fernet,
-            hasher: get_hasher(hashing_algorithm),
-            total_bytes: 0,
+
+pub fn parse_public_key(text: &str) -> Result<PublicKey, Error> {
+    Ok(PublicKey::from(decode_key(text, SFS_PUBLIC_KEY_PREFIX)?))
+}
+
+fn encode_key(key: &[u8], prefix: &str) -> String {
+    String::from(prefix) + &base64::encode_config(key, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_key(text: &str, prefix: &str) -> Result<[u8; 32], Error> {
+    let encoded_key = match text.trim().strip_prefix(prefix) {
+        Some(encoded_key) => encoded_key,
+        None => {
+            return Err(Error::InvalidKey(format!(
+                "key does not start with `{}`",
+                prefix
+            )))
         }
+    };
+    match base64::decode_config(encoded_key, base64::URL_SAFE_NO_PAD) {
+        Ok(key) => match <[u8; 32]>::try_from(Zeroizing::new(key).as_slice()) {
+            Ok(key) => Ok(key),
+            Err(_) => Err(Error::InvalidKey(String::from("key has an invalid length"))),
+        },
+        Err(error) => Err(Error::InvalidKey(error.to_string())),
     }
+}
 
-    pub fn get_checksum(&mut self) -> u64 {
-        self.hasher.digest()
+/// A random key wrapped with the credentials that were used last time, so
+/// that a mistyped password can be noticed before it is used on any file.
+pub struct PasswordVerifier {
+    key_slot: KeySlot,
+}
+impl PasswordVerifier {
+    pub fn new(
+        credentials: &Credentials,
+        memory_cost: u32,
+        time_cost: u32,
+        parallelism: u32,
+    ) -> Result<Self, Error> {
+        Ok(PasswordVerifier {
+            key_slot: KeySlot::new_password(
+                &generate_content_key(),
+                credentials,
+                memory_cost,
+                time_cost,
+                parallelism,
+            )?,
+        })
     }
 
-    pub fn decrypt(&mut self, encrypted_data: &str) -> Result<Vec<u8>, String> {
-        match self.fernet.decrypt(&encrypted_data) {
-            Ok(data) => {
-                self.total_bytes += data.len() as u64;
-                self.hasher.update(&data);
-                Ok(data)
+    pub fn pack(&self) -> Vec<u8> {
+        let mut verifier = SFS_MAGIC.to_vec();
+        verifier.extend_from_slice(&self.key_slot.pack());
+        verifier
+    }
+
+    pub fn parse(verifier_bytes: &[u8]) -> Result<Self, Error> {
+        let mut key_slot_bytes = match verifier_bytes.strip_prefix(SFS_MAGIC) {
+            Some(key_slot_bytes) => key_slot_bytes,
+            None => {
+                return Err(Error::InvalidVerifier(String::from(
+                    "this is not an SFS password verifier",
+                )))
+            }
+        };
+        match KeySlot::read(&mut key_slot_bytes) {
+            Ok(key_slot @ KeySlot::Password { .. }) => Ok(PasswordVerifier { key_slot }),
+            Ok(_) => Err(Error::InvalidVerifier(String::from(
+                "the verifier does not contain a password key slot",
+            ))),
+            Err(error) => Err(Error::InvalidVerifier(error.to_string())),
+        }
+    }
+
+    /// Returns whether the credentials are the same as the ones the verifier was made with.
+    pub fn verify(&self, credentials: &Credentials) -> Result<bool, Error> {
+        match &self.key_slot {
+            KeySlot::Password {
+                key_derivation_function,
+                factors,
+                memory_cost,
+                time_cost,
+                parallelism,
+                salt,
+                wrapped_key,
+            } => {
+                if *factors != credentials.factors() {
+                    return Ok(false);
+                }
+                let key = derive_password_key(
+                    *key_derivation_function,
+                    *memory_cost,
+                    *time_cost,
+                    *parallelism,
+                    salt,
+                    credentials,
+                )?;
+                Ok(unwrap_key(&key, wrapped_key).is_some())
             }
-            Err(error) => Err(error.to_string()),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The unsalted key derivation used by v1 and v2 files. Every intermediate
+/// string is allocated with its final size up front, so that no copies are
+/// left behind by reallocations, and zeroed when it's dropped.
+pub fn generate_legacy_fernet(password: &str) -> fernet::Fernet {
+    let mut result = Zeroizing::new(password.to_owned());
+    for _ in 0..256 {
+        let mut digest = Sha256::digest(result.as_bytes());
+        let mut hex_digest = Zeroizing::new(String::with_capacity(digest.len() * 2));
+        write!(hex_digest, "{:X}", digest).unwrap();
+        digest.as_mut_slice().zeroize();
+        result = hex_digest;
+    }
+
+    let mut key = Zeroizing::new(String::with_capacity(result.len() / 2));
+    for (index, letter) in result.chars().enumerate() {
+        if index % 2 == 0 {
+            key.push(letter);
         }
     }
+
+    let encoded_key = Zeroizing::new(base64::encode_config(key.as_bytes(), base64::URL_SAFE));
+    fernet::Fernet::new(&encoded_key).unwrap()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyDerivationFunction {
+    Sha256 = 0,
+    Argon2id = 1,
+}
+impl fmt::Display for KeyDerivationFunction {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
+impl KeyDerivationFunction {
+    pub fn list() -> &'static [KeyDerivationFunction] {
+        &[
+            KeyDerivationFunction::Sha256,
+            KeyDerivationFunction::Argon2id,
+        ]
+    }
+
+    pub fn from_u8(value: u8) -> Option<KeyDerivationFunction> {
+        KeyDerivationFunction::list().get(value as usize).copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    Fernet = 0,
+    Aes256Gcm = 1,
+    XChaCha20Poly1305 = 2,
+}
+impl fmt::Display for Cipher {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
+impl Cipher {
+    pub fn list() -> &'static [Cipher] {
+        &[Cipher::Fernet, Cipher::Aes256Gcm, Cipher::XChaCha20Poly1305]
+    }
+
+    pub fn from_u8(value: u8) -> Option<Cipher> {
+        Cipher::list().get(value as usize).copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HashingAlgorithm {
+    None = 0,
+    Xxh3 = 1,
+    Xxh64 = 2,
+    Xxh32 = 3,
+    Blake3 = 4,
+    Sha256 = 5,
+}
+impl fmt::Display for HashingAlgorithm {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
+impl HashingAlgorithm {
+    pub fn list() -> &'static [HashingAlgorithm] {
+        &[
+            HashingAlgorithm::None,
+            HashingAlgorithm::Xxh3,
+            HashingAlgorithm::Xxh64,
+            HashingAlgorithm::Xxh32,
+            HashingAlgorithm::Blake3,
+            HashingAlgorithm::Sha256,
+        ]
+    }
+
+    pub fn from_u8(value: u8) -> HashingAlgorithm {
+        let mut value = value.into();
+        let hashing_algorithm_list = HashingAlgorithm::list();
+        if value >= hashing_algorithm_list.len() {
+            value = 0
+        }
+        hashing_algorithm_list[value]
+    }
+
+    pub fn digest_size(&self) -> usize {
+        match self {
+            HashingAlgorithm::None => 0,
+            HashingAlgorithm::Xxh3 | HashingAlgorithm::Xxh64 => 8,
+            HashingAlgorithm::Xxh32 => 4,
+            HashingAlgorithm::Blake3 | HashingAlgorithm::Sha256 => 32,
+        }
+    }
+
+    /// v1 and v2 files stored the checksum as a 64-bit integer.
+    pub fn legacy_checksum(&self, checksum: u64) -> Vec<u8> {
+        match self {
+            HashingAlgorithm::None => Vec::new(),
+            HashingAlgorithm::Xxh32 => (checksum as u32).to_be_bytes().to_vec(),
+            _ => checksum.to_be_bytes().to_vec(),
+        }
+    }
+}
+
+pub trait Hasher {
+    fn update(&mut self, _: &[u8]);
+    fn digest(&mut self) -> Vec<u8>;
+    fn reset(&mut self);
+}
+
+#[derive(Clone)]
+pub struct DummyHasher {}
+impl Hasher for DummyHasher {
+    fn update(&mut self, _: &[u8]) {}
+    fn digest(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+    fn reset(&mut self) {}
+}
+
+#[derive(Clone)]
+pub struct Xxh3Hasher {
+    pub hasher: Xxh3,
+}
+impl Hasher for Xxh3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn digest(&mut self) -> Vec<u8> {
+        self.hasher.digest().to_be_bytes().to_vec()
+    }
+
+    fn reset(&mut self) {
+        self.hasher.reset()
+    }
+}
+
+pub struct Xxh64Hasher {
+    pub hasher: Xxh64,
+}
+impl Hasher for Xxh64Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn digest(&mut self) -> Vec<u8> {
+        self.hasher.digest().to_be_bytes().to_vec()
+    }
+
+    fn reset(&mut self) {
+        self.hasher.reset(0)
+    }
+}
+
+pub struct Xxh32Hasher {
+    pub hasher: Xxh32,
+}
+impl Hasher for Xxh32Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn digest(&mut self) -> Vec<u8> {
+        self.hasher.digest().to_be_bytes().to_vec()
+    }
+
+    fn reset(&mut self) {
+        self.hasher.reset(0)
+    }
+}
+
+pub struct Blake3Hasher {
+    pub hasher: blake3::Hasher,
+}
+impl Hasher for Blake3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    fn digest(&mut self) -> Vec<u8> {
+        self.hasher.finalize().as_bytes().to_vec()
+    }
+
+    fn reset(&mut self) {
+        self.hasher.reset();
+    }
+}
+
+pub struct Sha256Hasher {
+    pub hasher: Sha256,
+}
+impl Hasher for Sha256Hasher {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(&mut self.hasher, data);
+    }
+
+    fn digest(&mut self) -> Vec<u8> {
+        self.hasher.clone().finalize().to_vec()
+    }
+
+    fn reset(&mut self) {
+        Digest::reset(&mut self.hasher)
+    }
+}
+
+/// Every chunk is compressed on its own before it's encrypted, so chunks can
+/// still be decrypted (and authenticated) one at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None = 0,
+    Zstd = 1,
+    Deflate = 2,
+}
+impl fmt::Display for Compression {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
+impl Compression {
+    pub fn list() -> &'static [Compression] {
+        &[Compression::None, Compression::Zstd, Compression::Deflate]
+    }
+
+    pub fn from_u8(value: u8) -> Option<Compression> {
+        Compression::list().get(value as usize).copied()
+    }
+
+    /// The level is clamped to what the algorithm supports (1 to 22 for
+    /// zstd, 0 to 9 for deflate).
+    pub fn compress(&self, data: &[u8], level: i32) -> Vec<u8> {
+        match self {
+            Compression::None => data.to_vec(),
+            Compression::Zstd => zstd::bulk::compress(data, level.clamp(1, 22)).unwrap(),
+            Compression::Deflate => {
+                let mut encoder = flate2::write::DeflateEncoder::new(
+                    Vec::new(),
+                    flate2::Compression::new(level.clamp(0, 9) as u32),
+                );
+                std::io::Write::write_all(&mut encoder, data).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    /// Returns `None` if the data is invalid or decompresses to more than
+    /// `maximum_size` bytes.
+    pub fn decompress(&self, data: &[u8], maximum_size: usize) -> Option<Vec<u8>> {
+        match self {
+            Compression::None => Some(data.to_vec()),
+            Compression::Zstd => zstd::bulk::decompress(data, maximum_size).ok(),
+            Compression::Deflate => {
+                let mut decompressed = Vec::new();
+                flate2::read::DeflateDecoder::new(data)
+                    .take(maximum_size as u64 + 1)
+                    .read_to_end(&mut decompressed)
+                    .ok()?;
+                if decompressed.len() > maximum_size {
+                    return None;
+                }
+                Some(decompressed)
+            }
+        }
+    }
+}
+
+/// Padding hides the size of the data by adding padding records (which are
+/// encrypted and authenticated like chunks) before the trailer, so that the
+/// size of the file only depends on the padded size. The amount of padding
+/// is stored in the trailer, and is based on the size of the data after
+/// compression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Padding {
+    None,
+    /// Pads to the next power of two.
+    PowerOfTwo,
+    /// Pads to the next multiple of the bucket size.
+    Bucket(u64),
+    /// Adds a random amount of padding, up to the percentage of the size.
+    Random(u64),
+}
+impl fmt::Display for Padding {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Padding::None => write!(formatter, "NONE"),
+            Padding::PowerOfTwo => write!(formatter, "POWER OF TWO"),
+            Padding::Bucket(bucket_size) => write!(formatter, "BUCKET ({} bytes)", bucket_size),
+            Padding::Random(percentage) => write!(formatter, "RANDOM (up to {}%)", percentage),
+        }
+    }
+}
+impl Padding {
+    pub fn padding_bytes(&self, size: u64) -> u64 {
+        match self {
+            Padding::None => 0,
+            Padding::PowerOfTwo => size.checked_next_power_of_two().unwrap_or(size) - size,
+            Padding::Bucket(0) => 0,
+            Padding::Bucket(bucket_size) => (bucket_size - size % bucket_size) % bucket_size,
+            Padding::Random(percentage) => {
+                rand::thread_rng().gen_range(0..=(size as u128 * *percentage as u128 / 100) as u64)
+            }
+        }
+    }
+}
+
+fn get_hasher(hashing_algorithm: HashingAlgorithm) -> Box<dyn Hasher> {
+    match hashing_algorithm {
+        HashingAlgorithm::None => Box::new(DummyHasher {}),
+        HashingAlgorithm::Xxh3 => Box::new(Xxh3Hasher {
+            hasher: Xxh3::new(),
+        }),
+        HashingAlgorithm::Xxh64 => Box::new(Xxh64Hasher {
+            hasher: Xxh64::new(0),
+        }),
+        HashingAlgorithm::Xxh32 => Box::new(Xxh32Hasher {
+            hasher: Xxh32::new(0),
+        }),
+        HashingAlgorithm::Blake3 => Box::new(Blake3Hasher {
+            hasher: blake3::Hasher::new(),
+        }),
+        HashingAlgorithm::Sha256 => Box::new(Sha256Hasher {
+            hasher: Sha256::new(),
+        }),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Metadata = 0,
+    Chunk = 1,
+    Trailer = 2,
+    Padding = 3,
+}
+
+/// v3 files are a `FileHeader` followed by records, each of which is a kind,
+/// a 32-bit length and that many bytes of ciphertext. v1 and v2 files are
+/// read as one chunk record per line.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub kind: u8,
+    pub data: Vec<u8>,
+}
+impl Record {
+    #[allow(clippy::unused_io_amount)]
+    pub fn pack(&self) -> Vec<u8> {
+        let record_structure = structure!("BI");
+        let mut record = record_structure
+            .pack(self.kind, self.data.len() as u32)
+            .unwrap();
+        record.extend_from_slice(&self.data);
+        record
+    }
+
+    pub fn size(data_size: usize) -> usize {
+        structure!("BI").size() + data_size
+    }
+
+    #[allow(clippy::unused_io_amount)]
+    pub fn read(reader: &mut impl BufRead, format_version: u8) -> Result<Option<Self>, Error> {
+        if format_version < 3 {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            return Ok(Some(Record {
+                kind: RecordKind::Chunk as u8,
+                data: line.trim().as_bytes().to_vec(),
+            }));
+        }
+
+        if reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let record_structure = structure!("BI");
+        let mut record_bytes = vec![0; record_structure.size()];
+        read_exact(reader, &mut record_bytes)?;
+        let (kind, length) = record_structure.unpack(&record_bytes)?;
+        let mut data = Vec::new();
+        reader.take(length.into()).read_to_end(&mut data)?;
+        if data.len() != length as usize {
+            return Err(Error::Truncated);
+        }
+        Ok(Some(Record { kind, data }))
+    }
+}
+
+/// The cipher of a file, set up with its key. The nonce of every record is
+/// the random nonce from the header followed by a 64-bit record counter, and
+/// the file ID from the header is part of the associated data of every record.
+#[derive(Clone)]
+pub enum FileCipher {
+    Fernet(fernet::Fernet),
+    Aes256Gcm(Box<Aes256Gcm>, Vec<u8>, Vec<u8>),
+    XChaCha20Poly1305(Box<XChaCha20Poly1305>, Vec<u8>, Vec<u8>),
+}
+impl FileCipher {
+    pub fn new(header: &FileHeader, key: &[u8]) -> Result<Self, Error> {
+        match Cipher::from_u8(header.cipher) {
+            Some(Cipher::Aes256Gcm) => match Aes256Gcm::new_from_slice(key) {
+                Ok(cipher) => Ok(FileCipher::Aes256Gcm(
+                    Box::new(cipher),
+                    header.nonce[..4].to_vec(),
+                    header.file_id.clone(),
+                )),
+                Err(error) => Err(Error::InvalidKey(error.to_string())),
+            },
+            Some(Cipher::XChaCha20Poly1305) => match XChaCha20Poly1305::new_from_slice(key) {
+                Ok(cipher) => Ok(FileCipher::XChaCha20Poly1305(
+                    Box::new(cipher),
+                    header.nonce.clone(),
+                    header.file_id.clone(),
+                )),
+                Err(error) => Err(Error::InvalidKey(error.to_string())),
+            },
+            _ => Err(Error::UnsupportedCipher(header.cipher)),
+        }
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        match self {
+            FileCipher::Fernet(_) => Cipher::Fernet,
+            FileCipher::Aes256Gcm(_, _, _) => Cipher::Aes256Gcm,
+            FileCipher::XChaCha20Poly1305(_, _, _) => Cipher::XChaCha20Poly1305,
+        }
+    }
+
+    fn nonce(prefix: &[u8], counter: u64) -> Vec<u8> {
+        let mut nonce = prefix.to_vec();
+        nonce.extend_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    fn associated_data(&self, kind: RecordKind, index: u64) -> Vec<u8> {
+        let mut associated_data = vec![kind as u8];
+        match self {
+            FileCipher::Fernet(_) => (),
+            FileCipher::Aes256Gcm(_, _, file_id) | FileCipher::XChaCha20Poly1305(_, _, file_id) => {
+                associated_data.extend_from_slice(file_id)
+            }
+        }
+        associated_data.extend_from_slice(&index.to_be_bytes());
+        associated_data
+    }
+
+    fn seal(&self, counter: u64, aad: &[u8], data: &[u8]) -> Vec<u8> {
+        let payload = Payload { msg: data, aad };
+        match self {
+            FileCipher::Fernet(fernet) => fernet.encrypt(data).into_bytes(),
+            FileCipher::Aes256Gcm(cipher, prefix, _) => cipher
+                .encrypt(
+                    FileCipher::nonce(prefix, counter).as_slice().into(),
+                    payload,
+                )
+                .unwrap(),
+            FileCipher::XChaCha20Poly1305(cipher, prefix, _) => cipher
+                .encrypt(
+                    FileCipher::nonce(prefix, counter).as_slice().into(),
+                    payload,
+                )
+                .unwrap(),
+        }
+    }
+
+    /// Returns `None` if the data failed authentication.
+    fn open(&self, counter: u64, aad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+        let payload = Payload { msg: data, aad };
+        match self {
+            FileCipher::Fernet(fernet) => fernet.decrypt(&String::from_utf8_lossy(data)).ok(),
+            FileCipher::Aes256Gcm(cipher, prefix, _) => cipher
+                .decrypt(
+                    FileCipher::nonce(prefix, counter).as_slice().into(),
+                    payload,
+                )
+                .ok(),
+            FileCipher::XChaCha20Poly1305(cipher, prefix, _) => cipher
+                .decrypt(
+                    FileCipher::nonce(prefix, counter).as_slice().into(),
+                    payload,
+                )
+                .ok(),
+        }
+    }
+
+    pub fn encrypt_metadata(&self, metadata: &FileMetadata) -> Vec<u8> {
+        Record {
+            kind: RecordKind::Metadata as u8,
+            data: self.seal(
+                0,
+                &self.associated_data(RecordKind::Metadata, 0),
+                &metadata.pack(),
+            ),
+        }
+        .pack()
+    }
+
+    /// Returns the packed metadata, which can be unpacked with `FileMetadata::parse`.
+    /// Files older than v3 don't have a header, so the metadata is the first thing
+    /// that's decrypted with the password, and failing to do so means that it's wrong.
+    pub fn decrypt_metadata(&self, record: &Record) -> Result<Vec<u8>, Error> {
+        if record.kind != RecordKind::Metadata as u8 && self.cipher() != Cipher::Fernet {
+            return Err(Error::CorruptMetadata(String::from(
+                "first record is not the metadata",
+            )));
+        }
+        match self.open(
+            0,
+            &self.associated_data(RecordKind::Metadata, 0),
+            &record.data,
+        ) {
+            Some(metadata) => Ok(metadata),
+            None if self.cipher() == Cipher::Fernet => Err(Error::WrongKey),
+            None => Err(Error::HeaderMismatch),
+        }
+    }
+
+    pub fn encrypt_trailer(&self, trailer: &FileTrailer) -> Vec<u8> {
+        Record {
+            kind: RecordKind::Trailer as u8,
+            data: self.seal(
+                u64::MAX,
+                &self.associated_data(RecordKind::Trailer, u64::MAX),
+                &trailer.pack(),
+            ),
+        }
+        .pack()
+    }
+
+    pub fn decrypt_trailer(&self, record: &Record) -> Result<FileTrailer, Error> {
+        if record.kind != RecordKind::Trailer as u8 {
+            return Err(Error::CorruptTrailer(String::from(
+                "record is not the trailer",
+            )));
+        }
+        match self.open(
+            u64::MAX,
+            &self.associated_data(RecordKind::Trailer, u64::MAX),
+            &record.data,
+        ) {
+            Some(trailer) => FileTrailer::parse(&trailer),
+            None => Err(Error::CorruptTrailer(String::from(
+                "trailer failed authentication",
+            ))),
+        }
+    }
+
+    /// The trailer is always the last record, so it can be read without
+    /// going through the chunks first. Its size depends on the hashing algorithm.
+    pub fn read_trailer<R: BufRead + Seek>(
+        &self,
+        reader: &mut R,
+        hashing_algorithm: HashingAlgorithm,
+    ) -> Result<FileTrailer, Error> {
+        let trailer = FileTrailer {
+            checksum: vec![0; hashing_algorithm.digest_size()],
+            ..Default::default()
+        };
+        let trailer_size = Record::size(self.seal(0, &[], &trailer.pack()).len());
+        let read_trailer = |reader: &mut R| -> Result<Option<Record>, Error> {
+            let position = reader.stream_position()?;
+            reader.seek(SeekFrom::End(-(trailer_size as i64)))?;
+            let record = Record::read(reader, SFS_FORMAT_VERSION);
+            reader.seek(SeekFrom::Start(position))?;
+            record
+        };
+        match read_trailer(reader)? {
+            Some(record) => self.decrypt_trailer(&record),
+            None => Err(Error::Truncated),
+        }
+    }
+}
+
+pub struct Encrypter<'a> {
+    pub cipher: FileCipher,
+    pub hasher: Box<dyn Hasher + 'a>,
+    pub total_bytes: u64,
+    pub chunk_index: u64,
+    pub compression: Compression,
+    pub compression_level: i32,
+    pub padding: Padding,
+    /// How many bytes have been encrypted after compression.
+    pub stored_bytes: u64,
+    pub padding_bytes: u64,
+    padding_index: u64,
+    padding_left: Option<u64>,
+}
+impl<'a> Encrypter<'a> {
+    pub fn new(cipher: FileCipher, hashing_algorithm: HashingAlgorithm) -> Encrypter<'a> {
+        Encrypter {
+            cipher,
+            hasher: get_hasher(hashing_algorithm),
+            total_bytes: 0,
+            chunk_index: 0,
+            compression: Compression::None,
+            compression_level: 0,
+            padding: Padding::None,
+            stored_bytes: 0,
+            padding_bytes: 0,
+            padding_index: 0,
+            padding_left: None,
+        }
+    }
+
+    pub fn get_checksum(&mut self) -> Vec<u8> {
+        self.hasher.digest()
+    }
+
+    /// The chunk index is part of the nonce and the associated data, so chunks
+    /// can't be dropped, duplicated, reordered or moved between files without
+    /// `Decrypter` noticing.
+    pub fn encrypt(&mut self, data: &[u8]) -> Vec<u8> {
+        self.total_bytes += data.len() as u64;
+        self.hasher.update(data);
+        let compressed = self.compression.compress(data, self.compression_level);
+        self.stored_bytes += compressed.len() as u64;
+        let record = Record {
+            kind: RecordKind::Chunk as u8,
+            data: self.cipher.seal(
+                self.chunk_index + 1,
+                &self
+                    .cipher
+                    .associated_data(RecordKind::Chunk, self.chunk_index),
+                &compressed,
+            ),
+        };
+        self.chunk_index += 1;
+        record.pack()
+    }
+
+    /// Returns the next padding record (of at most `record_size` bytes), or
+    /// `None` once there's no padding left. Has to be called until it returns
+    /// `None` after the last chunk, and before `finish`.
+    pub fn pad(&mut self, record_size: usize) -> Option<Vec<u8>> {
+        let padding_left = *self
+            .padding_left
+            .get_or_insert_with(|| self.padding.padding_bytes(self.stored_bytes));
+        if padding_left == 0 || record_size == 0 {
+            return None;
+        }
+        let size = padding_left.min(record_size as u64);
+        let record = Record {
+            kind: RecordKind::Padding as u8,
+            data: self.cipher.seal(
+                u64::MAX - 1 - self.padding_index,
+                &self
+                    .cipher
+                    .associated_data(RecordKind::Padding, self.padding_index),
+                &vec![0; size as usize],
+            ),
+        };
+        self.padding_index += 1;
+        self.padding_bytes += size;
+        self.padding_left = Some(padding_left - size);
+        Some(record.pack())
+    }
+
+    /// Returns the trailer, which has to be written after the last chunk.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let trailer = FileTrailer {
+            chunk_count: self.chunk_index,
+            total_bytes: self.total_bytes,
+            stored_bytes: self.stored_bytes,
+            padding_bytes: self.padding_bytes,
+            checksum: self.get_checksum(),
+        };
+        self.cipher.encrypt_trailer(&trailer)
+    }
+}
+
+pub struct Decrypter<'a> {
+    pub cipher: FileCipher,
+    pub hasher: Box<dyn Hasher + 'a>,
+    pub total_bytes: u64,
+    pub chunk_index: u64,
+    pub trailer: Option<FileTrailer>,
+    pub compression: Compression,
+    /// The most that a compressed chunk is allowed to decompress to.
+    pub chunk_size: u64,
+    pub stored_bytes: u64,
+    pub padding_bytes: u64,
+    padding_index: u64,
+}
+impl<'a> Decrypter<'a> {
+    pub fn new(cipher: FileCipher, hashing_algorithm: HashingAlgorithm) -> Decrypter<'a> {
+        Decrypter {
+            cipher,
+            hasher: get_hasher(hashing_algorithm),
+            total_bytes: 0,
+            chunk_index: 0,
+            trailer: None,
+            compression: Compression::None,
+            chunk_size: u64::MAX,
+            stored_bytes: 0,
+            padding_bytes: 0,
+            padding_index: 0,
+        }
+    }
+
+    pub fn get_checksum(&mut self) -> Vec<u8> {
+        self.hasher.digest()
+    }
+
+    /// Decrypts a chunk record, or checks the padding or the trailer (in which
+    /// case no data is returned).
+    pub fn decrypt(&mut self, record: &Record) -> Result<Vec<u8>, Error> {
+        if self.trailer.is_some() {
+            return Err(Error::TrailingData);
+        }
+        if record.kind == RecordKind::Trailer as u8 {
+            let trailer = self.cipher.decrypt_trailer(record)?;
+            if trailer.chunk_count != self.chunk_index {
+                return Err(Error::CorruptTrailer(format!(
+                    "expected {} chunks but found {}",
+                    trailer.chunk_count, self.chunk_index
+                )));
+            }
+            if trailer.total_bytes != self.total_bytes {
+                return Err(Error::CorruptTrailer(format!(
+                    "expected {} bytes but found {}",
+                    trailer.total_bytes, self.total_bytes
+                )));
+            }
+            if trailer.stored_bytes != self.stored_bytes {
+                return Err(Error::CorruptTrailer(format!(
+                    "expected {} compressed bytes but found {}",
+                    trailer.stored_bytes, self.stored_bytes
+                )));
+            }
+            if trailer.padding_bytes != self.padding_bytes {
+                return Err(Error::CorruptTrailer(format!(
+                    "expected {} bytes of padding but found {}",
+                    trailer.padding_bytes, self.padding_bytes
+                )));
+            }
+            self.trailer = Some(trailer);
+            return Ok(Vec::new());
+        } else if record.kind == RecordKind::Padding as u8 {
+            let padding = match self.cipher.open(
+                u64::MAX - 1 - self.padding_index,
+                &self
+                    .cipher
+                    .associated_data(RecordKind::Padding, self.padding_index),
+                &record.data,
+            ) {
+                Some(padding) => padding,
+                None => {
+                    return Err(Error::CorruptPadding(format!(
+                        "padding record {} failed authentication",
+                        self.padding_index
+                    )))
+                }
+            };
+            self.padding_index += 1;
+            self.padding_bytes += padding.len() as u64;
+            return Ok(Vec::new());
+        } else if record.kind != RecordKind::Chunk as u8 {
+            return Err(Error::CorruptTrailer(format!(
+                "unexpected record kind {} instead of the trailer",
+                record.kind
+            )));
+        }
+
+        let data = match self.cipher.open(
+            self.chunk_index + 1,
+            &self
+                .cipher
+                .associated_data(RecordKind::Chunk, self.chunk_index),
+            &record.data,
+        ) {
+            Some(data) => data,
+            None => {
+                return Err(Error::ChunkAuthFailed {
+                    index: self.chunk_index,
+                })
+            }
+        };
+        self.stored_bytes += data.len() as u64;
+        let data = match self
+            .compression
+            .decompress(&data, self.chunk_size.try_into().unwrap_or(usize::MAX))
+        {
+            Some(data) => data,
+            None => {
+                return Err(Error::DecompressionFailed {
+                    index: self.chunk_index,
+                })
+            }
+        };
+        self.chunk_index += 1;
+        self.total_bytes += data.len() as u64;
+        self.hasher.update(&data);
+        Ok(data)
+    }
+
+    fn cipher(&self) -> Cipher {
+        self.cipher.cipher()
+    }
+
+    /// Makes sure that the trailer has been decrypted, which means that the
+    /// file hasn't been truncated. v1 and v2 files don't have a trailer.
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.cipher() != Cipher::Fernet && self.trailer.is_none() {
+            return Err(Error::Truncated);
+        }
+        Ok(())
+    }
+
+    /// Compares the checksum of everything decrypted so far with the one from
+    /// the trailer (or from the metadata of v1 and v2 files).
+    pub fn verify_checksum(&mut self, expected_checksum: &[u8]) -> Result<(), Error> {
+        let actual_checksum = self.get_checksum();
+        if actual_checksum != expected_checksum {
+            return Err(Error::ChecksumMismatch {
+                expected: expected_checksum.to_vec(),
+                actual: actual_checksum,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// How much of a file has been encrypted or decrypted so far. The total is
+/// unknown when reading from a stream.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub processed_bytes: u64,
+    pub total_bytes: Option<u64>,
+}
+
+/// The metadata of a file that has been encrypted or decrypted, with its
+/// size and checksum filled in.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub metadata: FileMetadata,
+    pub total_bytes: u64,
+    pub checksum: Vec<u8>,
+}
+
+pub struct EncryptOptions<'a> {
+    pub credentials: &'a Credentials,
+    /// If there are any, the file is only encrypted for them instead of with the credentials.
+    pub recipients: Vec<PublicKey>,
+    pub cipher: Cipher,
+    pub hashing_algorithm: HashingAlgorithm,
+    pub chunk_size: u64,
+    pub compression: Compression,
+    pub compression_level: i32,
+    pub padding: Padding,
+    /// Set by `encrypt_archive`.
+    pub archive: bool,
+    pub key_derivation_memory_cost: u32,
+    pub key_derivation_time_cost: u32,
+    pub key_derivation_parallelism: u32,
+    /// Defaults to the name of the input file for `encrypt_file`.
+    pub original_name: Option<String>,
+    pub restore_name: bool,
+    /// Decrypt the output of `encrypt_file` and `encrypt_archive` again before
+    /// it's renamed, to make sure that it can be decrypted and that the checksum matches.
+    pub verify: bool,
+    /// Used to verify files that are only encrypted for recipients, if it's one of them.
+    pub identity: Option<&'a Identity>,
+    pub progress: Option<&'a dyn Fn(Progress)>,
+}
+impl<'a> EncryptOptions<'a> {
+    pub fn new(credentials: &'a Credentials) -> Self {
+        EncryptOptions {
+            credentials,
+            recipients: Vec::new(),
+            cipher: Cipher::XChaCha20Poly1305,
+            hashing_algorithm: HashingAlgorithm::Xxh3,
+            chunk_size: 1048576,
+            compression: Compression::None,
+            compression_level: 3,
+            padding: Padding::None,
+            archive: false,
+            key_derivation_memory_cost: 19456,
+            key_derivation_time_cost: 2,
+            key_derivation_parallelism: 1,
+            original_name: None,
+            restore_name: false,
+            verify: false,
+            identity: None,
+            progress: None,
+        }
+    }
+}
+
+pub struct DecryptOptions<'a> {
+    pub credentials: &'a Credentials,
+    pub identity: Option<&'a Identity>,
+    /// Compare the checksum of the decrypted data with the one from when it was encrypted.
+    pub verify_checksum: bool,
+    pub progress: Option<&'a dyn Fn(Progress)>,
+}
+impl<'a> DecryptOptions<'a> {
+    pub fn new(credentials: &'a Credentials) -> Self {
+        DecryptOptions {
+            credentials,
+            identity: None,
+            verify_checksum: true,
+            progress: None,
+        }
+    }
+}
+
+/// Encrypts everything from the reader into the writer.
+pub fn encrypt(
+    reader: impl Read,
+    writer: impl std::io::Write,
+    options: &EncryptOptions,
+) -> Result<Summary, Error> {
+    Ok(encrypt_with_total(reader, EncryptWriter::new(writer, options)?, options, None)?.1)
+}
+
+pub fn encrypt_file(
+    input_path: &Path,
+    output_path: &Path,
+    options: &EncryptOptions,
+) -> Result<Summary, Error> {
+    let input_file = File::open(input_path)?;
+    let total_bytes = input_file.metadata()?.len();
+    let original_name = match &options.original_name {
+        Some(original_name) => original_name.clone(),
+        None => input_path
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+    let options = EncryptOptions {
+        original_name: Some(original_name),
+        recipients: options.recipients.clone(),
+        ..*options
+    };
+    write_atomically(output_path, |output_file| {
+        let encrypt_writer = EncryptWriter::new(&mut *output_file, &options)?;
+        let cipher = encrypt_writer.encrypter.cipher.clone();
+        let (_, summary) = encrypt_with_total(
+            BufReader::new(input_file),
+            encrypt_writer,
+            &options,
+            Some(total_bytes),
+        )?;
+        if options.verify {
+            verify_encrypted_file(output_file, cipher, &summary, &options)?;
+        }
+        Ok(summary)
+    })
+}
+
+/// A hidden file with a random name next to the path, that outputs are
+/// written to before they're renamed over the path.
+pub fn temporary_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let random_name = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(8)
+        .map(char::from)
+        .collect::<String>();
+    path.with_file_name(format!(".{}.{}.tmp", file_name, random_name))
+}
+
+/// Writes to a temporary file, which is synced and renamed over the output
+/// path if writing succeeded, and removed if it didn't. The output path is
+/// never left with a partially written file.
+fn write_atomically<T>(
+    output_path: &Path,
+    write: impl FnOnce(&mut File) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let temporary_path = temporary_path(output_path);
+    let mut temporary_file = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&temporary_path)?;
+    let result = write(&mut temporary_file).and_then(|value| {
+        temporary_file.sync_all()?;
+        fs::rename(&temporary_path, output_path)?;
+        sync_parent_directory(output_path);
+        Ok(value)
+    });
+    if result.is_err() {
+        fs::remove_file(&temporary_path).ok();
+    }
+    result
+}
+
+/// Makes sure that a rename is on the disk. This only works on Unix, and
+/// failing to do it isn't treated as an error, since the data itself is already synced.
+pub fn sync_parent_directory(path: &Path) {
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        if let Ok(directory) = File::open(parent) {
+            directory.sync_all().ok();
+        }
+    }
+}
+
+/// Overwrites a file with random data for a number of passes (syncing it to
+/// the disk after every pass), renames it to a random name so that the old name
+/// isn't left behind in the directory either, and removes it.
+pub fn shred_file(path: &Path, passes: u32) -> Result<(), Error> {
+    // A symlink would otherwise overwrite the file it points to
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_file() {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "only regular files can be shredded",
+        )));
+    }
+    let mut file = File::options().write(true).open(path)?;
+    let mut buffer = vec![0; 1048576];
+    for _ in 0..passes {
+        file.seek(SeekFrom::Start(0))?;
+        let mut written_bytes = 0;
+        while written_bytes < metadata.len() {
+            let size = buffer.len().min((metadata.len() - written_bytes) as usize);
+            rand::thread_rng().fill_bytes(&mut buffer[..size]);
+            std::io::Write::write_all(&mut file, &buffer[..size])?;
+            written_bytes += size as u64;
+        }
+        file.sync_all()?;
+    }
+    drop(file);
+
+    let random_path = path.with_file_name(
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect::<String>(),
+    );
+    fs::rename(path, &random_path)?;
+    sync_parent_directory(path);
+    fs::remove_file(&random_path)?;
+    sync_parent_directory(path);
+    Ok(())
+}
+
+/// The name of the filesystem if it's copy-on-write or log-structured, where
+/// overwriting a file writes the new data somewhere else on the disk and
+/// `shred_file` can't remove the old data.
+#[cfg(target_os = "linux")]
+pub fn copy_on_write_filesystem(path: &Path) -> Option<&'static str> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut statfs = std::mem::MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(path.as_ptr(), statfs.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // `f_type` is a different type on different architectures
+    #[allow(clippy::unnecessary_cast)]
+    match unsafe { statfs.assume_init() }.f_type as u32 {
+        0x9123683e => Some("Btrfs"),
+        0x2fc12fc1 => Some("ZFS"),
+        0xca451a4e => Some("bcachefs"),
+        0xf2f52010 => Some("F2FS"),
+        0x3434 => Some("NILFS"),
+        0x72b6 => Some("JFFS2"),
+        0x24051905 => Some("UBIFS"),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn copy_on_write_filesystem(_: &Path) -> Option<&'static str> {
+    None
+}
+
+/// Packs a directory into a tar archive, with the paths, permissions,
+/// modification times, symlinks and empty directories of everything in it,
+/// and encrypts it into one file.
+pub fn encrypt_archive(
+    input_path: &Path,
+    output_path: &Path,
+    options: &EncryptOptions,
+) -> Result<Summary, Error> {
+    if !fs::metadata(input_path)?.is_dir() {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "only directories can be archived",
+        )));
+    }
+    let original_name = match &options.original_name {
+        Some(original_name) => original_name.clone(),
+        None => input_path
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+    write_atomically(output_path, |output_file| {
+        let encrypt_writer = EncryptWriter::new(
+            std::io::BufWriter::new(&mut *output_file),
+            &EncryptOptions {
+                original_name: Some(original_name),
+                recipients: options.recipients.clone(),
+                archive: true,
+                ..*options
+            },
+        )?;
+        let cipher = encrypt_writer.encrypter.cipher.clone();
+        let mut builder = tar::Builder::new(ProgressWriter {
+            writer: encrypt_writer,
+            processed_bytes: 0,
+            progress: options.progress,
+        });
+        builder.follow_symlinks(false);
+        builder.append_dir_all(".", input_path)?;
+        let (_, summary) = builder.into_inner()?.writer.finish()?;
+        if options.verify {
+            verify_encrypted_file(output_file, cipher, &summary, options)?;
+        }
+        Ok(summary)
+    })
+}
+
+/// Decrypts a file that was just written, unlocking it with the credentials
+/// (or the identity) like it would be later, and compares the checksum with the
+/// one from encrypting it. Files that are only encrypted for other people can't
+/// be unlocked, so they're decrypted with the cipher they were encrypted with.
+fn verify_encrypted_file(
+    file: &mut File,
+    cipher: FileCipher,
+    summary: &Summary,
+    options: &EncryptOptions,
+) -> Result<(), Error> {
+    file.seek(SeekFrom::Start(0))?;
+    let reader = BufReader::new(file);
+    let can_unlock = options.recipients.is_empty()
+        || options
+            .identity
+            .is_some_and(|identity| options.recipients.contains(&identity.public_key()));
+    let encrypted_file = if can_unlock {
+        EncryptedFile::open(reader, options.credentials, options.identity)?
+    } else {
+        let mut reader = reader;
+        let header = match FileHeader::read(&mut reader)? {
+            Some(header) => header,
+            None => return Err(Error::Truncated),
+        };
+        EncryptedFile::open_with_cipher(reader, Some(header), cipher)?
+    };
+    let decrypted =
+        encrypted_file.decrypt_to(std::io::sink(), &DecryptOptions::new(options.credentials))?;
+    if decrypted.total_bytes != summary.total_bytes || decrypted.checksum != summary.checksum {
+        return Err(Error::ChecksumMismatch {
+            expected: summary.checksum.clone(),
+            actual: decrypted.checksum,
+        });
+    }
+    Ok(())
+}
+
+struct ProgressWriter<'a, W> {
+    writer: W,
+    processed_bytes: u64,
+    progress: Option<&'a dyn Fn(Progress)>,
+}
+impl<W: std::io::Write> std::io::Write for ProgressWriter<'_, W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let written = self.writer.write(data)?;
+        self.processed_bytes += written as u64;
+        if let Some(progress) = self.progress {
+            progress(Progress {
+                processed_bytes: self.processed_bytes,
+                total_bytes: None,
+            })
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn encrypt_with_total<W: std::io::Write>(
+    mut reader: impl Read,
+    mut encrypt_writer: EncryptWriter<W>,
+    options: &EncryptOptions,
+    total_bytes: Option<u64>,
+) -> Result<(W, Summary), Error> {
+    let mut buffer = vec![0; options.chunk_size as usize];
+    let mut processed_bytes = 0;
+    loop {
+        let read = read_chunk(&mut reader, &mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        std::io::Write::write_all(&mut encrypt_writer, &buffer[..read])?;
+        processed_bytes += read as u64;
+        if let Some(progress) = options.progress {
+            progress(Progress {
+                processed_bytes,
+                total_bytes,
+            })
+        }
+    }
+    encrypt_writer.finish()
+}
+
+/// Encrypts everything that's written to it into the writer, one chunk at a time.
+///
+/// `finish` has to be called after the last write. It isn't called when this
+/// is dropped, so that a file that was only written halfway (because of an
+/// error or a panic) is always reported as truncated when it's decrypted.
+pub struct EncryptWriter<W: std::io::Write> {
+    writer: W,
+    metadata: FileMetadata,
+    encrypter: Encrypter<'static>,
+    chunk: Vec<u8>,
+    chunk_size: usize,
+}
+impl<W: std::io::Write> EncryptWriter<W> {
+    /// Writes the header and metadata right away.
+    pub fn new(mut writer: W, options: &EncryptOptions) -> Result<Self, Error> {
+        if options.chunk_size == 0 {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "chunk size must be greater than 0",
+            )));
+        }
+        let content_key = generate_content_key();
+        let mut header = FileHeader::new(options.cipher);
+        if options.recipients.is_empty() {
+            header.add_password(
+                &content_key,
+                options.credentials,
+                options.key_derivation_memory_cost,
+                options.key_derivation_time_cost,
+                options.key_derivation_parallelism,
+            )?;
+        } else {
+            for recipient in &options.recipients {
+                header.add_recipient(&content_key, recipient)
+            }
+        }
+        let file_cipher = FileCipher::new(&header, &content_key)?;
+        let metadata = FileMetadata {
+            format_version: SFS_FORMAT_VERSION,
+            original_name: options.original_name.clone().unwrap_or_default(),
+            restore_name: options.restore_name,
+            total_bytes: 0,
+            hashing_algorithm: options.hashing_algorithm as u8,
+            checksum: Vec::new(),
+            chunk_size: options.chunk_size,
+            header_checksum: header.checksum(),
+            compression: options.compression as u8,
+            archive: options.archive,
+        };
+        let mut encrypted = header.pack();
+        encrypted.append(&mut file_cipher.encrypt_metadata(&metadata));
+        writer.write_all(&encrypted)?;
+
+        Ok(EncryptWriter {
+            writer,
+            metadata,
+            encrypter: Encrypter {
+                compression: options.compression,
+                compression_level: options.compression_level,
+                padding: options.padding,
+                ..Encrypter::new(file_cipher, options.hashing_algorithm)
+            },
+            chunk: Vec::with_capacity(options.chunk_size as usize),
+            chunk_size: options.chunk_size as usize,
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Encrypts whatever is left, writes the trailer and returns the writer.
+    pub fn finish(mut self) -> Result<(W, Summary), Error> {
+        if !self.chunk.is_empty() {
+            self.write_chunk()?;
+        }
+        while let Some(padding) = self.encrypter.pad(self.chunk_size) {
+            self.writer.write_all(&padding)?;
+        }
+        self.writer.write_all(&self.encrypter.finish())?;
+        self.writer.flush()?;
+
+        let checksum = self.encrypter.get_checksum();
+        let summary = Summary {
+            metadata: FileMetadata {
+                total_bytes: self.encrypter.total_bytes,
+                checksum: checksum.clone(),
+                ..self.metadata
+            },
+            total_bytes: self.encrypter.total_bytes,
+            checksum,
+        };
+        Ok((self.writer, summary))
+    }
+
+    fn write_chunk(&mut self) -> std::io::Result<()> {
+        self.writer
+            .write_all(&self.encrypter.encrypt(&self.chunk))?;
+        self.chunk.clear();
+        Ok(())
+    }
+}
+impl<W: std::io::Write> std::io::Write for EncryptWriter<W> {
+    /// Full chunks are only encrypted once more data (or `finish`) comes after
+    /// them, since only the last chunk may be shorter than the chunk size.
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if self.chunk.len() == self.chunk_size {
+            self.write_chunk()?;
+        }
+        let size = data.len().min(self.chunk_size - self.chunk.len());
+        self.chunk.extend_from_slice(&data[..size]);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Fills the buffer as far as possible, so that every chunk (except for the
+/// last one) has the same size, even when reading from a pipe.
+fn read_chunk(reader: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(size) => read += size,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(read)
+}
+
+/// Decrypts an encrypted file from the reader into the writer.
+pub fn decrypt(
+    reader: impl BufRead,
+    writer: impl std::io::Write,
+    options: &DecryptOptions,
+) -> Result<Summary, Error> {
+    EncryptedFile::open(reader, options.credentials, options.identity)?.decrypt_to(writer, options)
+}
+
+pub fn decrypt_file(
+    input_path: &Path,
+    output_path: &Path,
+    options: &DecryptOptions,
+) -> Result<Summary, Error> {
+    let mut encrypted_file = EncryptedFile::open(
+        BufReader::new(File::open(input_path)?),
+        options.credentials,
+        options.identity,
+    )?;
+    // The trailer is checked again after decrypting, so this is only for the progress
+    encrypted_file.read_trailer().ok();
+    encrypted_file.decrypt_to_file(output_path, options)
+}
+
+/// The name of the encrypted file, which is either next to the original or
+/// has a random name in the same directory.
+pub fn encrypted_path(input_path: &Path, assign_random_name: bool) -> PathBuf {
+    let mut output_path = if assign_random_name {
+        input_path
+            .with_file_name(
+                rand::thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(32)
+                    .map(char::from)
+                    .collect::<String>(),
+            )
+            .into_os_string()
+    } else {
+        input_path.as_os_str().to_owned()
+    };
+    output_path.push(".sfs");
+    PathBuf::from(output_path)
+}
+
+/// The name of the decrypted file, which is the encrypted file without `.sfs`,
+/// or the original name (if it was stored and should be used) in the same directory.
+pub fn decrypted_path(
+    input_path: &Path,
+    metadata: &FileMetadata,
+    use_original_name: bool,
+) -> PathBuf {
+    // Only the last component is used, so that a file can't be decrypted into another directory
+    let original_name = Path::new(&metadata.original_name).file_name();
+    match original_name {
+        Some(original_name) if use_original_name != metadata.restore_name => {
+            input_path.with_file_name(original_name)
+        }
+        _ => match input_path.to_string_lossy().strip_suffix(".sfs") {
+            Some(output_path) => PathBuf::from(output_path),
+            None => {
+                let mut output_path = input_path.as_os_str().to_owned();
+                output_path.push(".decrypted");
+                PathBuf::from(output_path)
+            }
+        },
+    }
+}
+
+/// A file whose header and metadata have been read and unlocked, so that
+/// the rest of it can be decrypted.
+pub struct EncryptedFile<R> {
+    /// `None` for v1 and v2 files.
+    pub header: Option<FileHeader>,
+    pub metadata: FileMetadata,
+    pub cipher: FileCipher,
+    /// Known in advance for v1 and v2 files, and for v3 files once the trailer has been read.
+    pub total_bytes: Option<u64>,
+    /// The size of the chunks after compression, known like `total_bytes`.
+    pub stored_bytes: Option<u64>,
+    reader: R,
+}
+impl<R: BufRead> EncryptedFile<R> {
+    pub fn open(
+        mut reader: R,
+        credentials: &Credentials,
+        identity: Option<&Identity>,
+    ) -> Result<Self, Error> {
+        let header = FileHeader::read(&mut reader)?;
+        let cipher = match &header {
+            Some(header) => {
+                let content_key = header.unwrap_content_key(credentials, identity)?;
+                FileCipher::new(header, &content_key)?
+            }
+            None => FileCipher::Fernet(generate_legacy_fernet(&credentials.password)),
+        };
+        Self::open_with_cipher(reader, header, cipher)
+    }
+
+    /// Reads the metadata after the header, with a cipher that's already set up.
+    fn open_with_cipher(
+        mut reader: R,
+        header: Option<FileHeader>,
+        cipher: FileCipher,
+    ) -> Result<Self, Error> {
+        let format_version = match &header {
+            Some(header) => header.format_version,
+            None => SFS_OLDEST_FORMAT_VERSION,
+        };
+        let record = match Record::read(&mut reader, format_version)? {
+            Some(record) => record,
+            None => return Err(Error::Truncated),
+        };
+        let metadata = FileMetadata::parse(&cipher.decrypt_metadata(&record)?)?;
+        let total_bytes = match &header {
+            Some(header) => {
+                header.verify_metadata(&metadata)?;
+                None
+            }
+            None => Some(metadata.total_bytes),
+        };
+        Ok(EncryptedFile {
+            header,
+            metadata,
+            cipher,
+            total_bytes,
+            stored_bytes: total_bytes,
+            reader,
+        })
+    }
+
+    /// Decrypts the chunks into the writer, and makes sure that none of them
+    /// are missing and that the checksum matches.
+    pub fn decrypt_to(
+        self,
+        mut writer: impl std::io::Write,
+        options: &DecryptOptions,
+    ) -> Result<Summary, Error> {
+        let total_bytes = self.total_bytes;
+        let mut decrypt_reader = self.into_reader(options.verify_checksum);
+        while let Some(chunk) = decrypt_reader.next_chunk()? {
+            writer.write_all(&chunk)?;
+            if let Some(progress) = options.progress {
+                progress(Progress {
+                    processed_bytes: decrypt_reader.decrypter.total_bytes,
+                    total_bytes,
+                })
+            }
+        }
+        writer.flush()?;
+        Ok(decrypt_reader.summary())
+    }
+
+    /// Extracts an archive made by `encrypt_archive` into the output directory,
+    /// which is created if it doesn't exist. Entries that would end up outside
+    /// of it are skipped.
+    pub fn extract_to(
+        self,
+        output_path: &Path,
+        options: &DecryptOptions,
+    ) -> Result<Summary, Error> {
+        if !self.metadata.archive {
+            return Err(Error::NotAnArchive);
+        }
+        let total_bytes = self.total_bytes;
+        let mut archive = tar::Archive::new(ProgressReader {
+            reader: self.into_reader(options.verify_checksum),
+            processed_bytes: 0,
+            total_bytes,
+            progress: options.progress,
+            error: None,
+        });
+        archive.set_preserve_permissions(true);
+        let temporary_path = temporary_path(output_path);
+        let mut unpack = || -> std::io::Result<()> {
+            fs::create_dir(&temporary_path)?;
+            // Directories are created last so that their permissions don't get in the
+            // way, and their modification times are set after everything in them exists
+            let mut directories = Vec::new();
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if entry.header().entry_type() == tar::EntryType::Directory {
+                    directories.push(entry);
+                } else {
+                    entry.unpack_in(&temporary_path)?;
+                }
+            }
+            let mut modification_times = Vec::new();
+            for mut directory in directories {
+                if directory.unpack_in(&temporary_path)? {
+                    modification_times.push((
+                        temporary_path.join(directory.path()?.components().collect::<PathBuf>()),
+                        directory.header().mtime()?,
+                    ));
+                }
+            }
+            for (path, modified) in modification_times {
+                filetime::set_file_mtime(
+                    path,
+                    filetime::FileTime::from_unix_time(modified as i64, 0),
+                )?;
+            }
+            Ok(())
+        };
+        let result = unpack();
+        let mut progress_reader = archive.into_inner();
+        // The end of the archive is padded, and the trailer comes after that
+        let result = result
+            .and_then(|_| std::io::copy(&mut progress_reader, &mut std::io::sink()))
+            .and_then(|_| {
+                for entry in walkdir::WalkDir::new(&temporary_path) {
+                    let entry = entry?;
+                    if entry.file_type().is_file() {
+                        File::open(entry.path())?.sync_all()?;
+                    }
+                }
+                // Directories can't be replaced by a rename, so an existing output is
+                // moved aside first, and only removed once the new one is in its place
+                let old_path = match fs::symlink_metadata(output_path) {
+                    Ok(metadata) => {
+                        let old_path = self::temporary_path(output_path);
+                        fs::rename(output_path, &old_path)?;
+                        Some((old_path, metadata.is_dir()))
+                    }
+                    Err(_) => None,
+                };
+                if let Err(error) = fs::rename(&temporary_path, output_path) {
+                    if let Some((old_path, _)) = &old_path {
+                        fs::rename(old_path, output_path).ok();
+                    }
+                    return Err(error);
+                }
+                sync_parent_directory(output_path);
+                match old_path {
+                    Some((old_path, true)) => fs::remove_dir_all(old_path).ok(),
+                    Some((old_path, false)) => fs::remove_file(old_path).ok(),
+                    None => None,
+                };
+                Ok(())
+            });
+        if let Err(error) = result {
+            fs::remove_dir_all(&temporary_path).ok();
+            return Err(progress_reader.error.take().unwrap_or(Error::Io(error)));
+        }
+        Ok(progress_reader.reader.summary())
+    }
+
+    /// Decrypts into a temporary file next to the output path, which only
+    /// replaces the output path once everything has been decrypted and verified.
+    pub fn decrypt_to_file(
+        self,
+        output_path: &Path,
+        options: &DecryptOptions,
+    ) -> Result<Summary, Error> {
+        write_atomically(output_path, |output_file| {
+            self.decrypt_to(std::io::BufWriter::new(output_file), options)
+        })
+    }
+
+    /// Lists the contents of an archive made by `encrypt_archive`. The whole
+    /// file has to be decrypted for this, but nothing is written to disk.
+    pub fn list_archive(self, verify_checksum: bool) -> Result<Vec<ArchiveEntry>, Error> {
+        if !self.metadata.archive {
+            return Err(Error::NotAnArchive);
+        }
+        let mut archive = tar::Archive::new(ProgressReader {
+            reader: self.into_reader(verify_checksum),
+            processed_bytes: 0,
+            total_bytes: None,
+            progress: None,
+            error: None,
+        });
+        let mut archive_entries = Vec::new();
+        let mut read_entries = || -> std::io::Result<()> {
+            for entry in archive.entries()? {
+                let entry = entry?;
+                let path = entry
+                    .path()?
+                    .components()
+                    .filter(|component| *component != std::path::Component::CurDir)
+                    .collect::<PathBuf>();
+                if path.as_os_str().is_empty() {
+                    continue;
+                }
+                let header = entry.header();
+                archive_entries.push(ArchiveEntry {
+                    path,
+                    kind: match header.entry_type() {
+                        tar::EntryType::Regular => ArchiveEntryKind::File,
+                        tar::EntryType::Directory => ArchiveEntryKind::Directory,
+                        tar::EntryType::Symlink => ArchiveEntryKind::Symlink,
+                        _ => ArchiveEntryKind::Other,
+                    },
+                    size: header.size()?,
+                    mode: header.mode()?,
+                    modified: header.mtime()?,
+                    link_target: entry.link_name()?.map(|link_name| link_name.into_owned()),
+                })
+            }
+            Ok(())
+        };
+        let result = read_entries();
+        let mut progress_reader = archive.into_inner();
+        if let Err(error) =
+            result.and_then(|_| std::io::copy(&mut progress_reader, &mut std::io::sink()))
+        {
+            return Err(progress_reader.error.take().unwrap_or(Error::Io(error)));
+        }
+        Ok(archive_entries)
+    }
+
+    pub fn into_reader(self, verify_checksum: bool) -> DecryptReader<R> {
+        let hashing_algorithm = if verify_checksum {
+            HashingAlgorithm::from_u8(self.metadata.hashing_algorithm)
+        } else {
+            HashingAlgorithm::None
+        };
+        let decrypter = Decrypter {
+            compression: Compression::from_u8(self.metadata.compression)
+                .unwrap_or(Compression::None),
+            chunk_size: self.metadata.chunk_size,
+            ..Decrypter::new(self.cipher, hashing_algorithm)
+        };
+        DecryptReader {
+            header: self.header,
+            metadata: self.metadata,
+            reader: self.reader,
+            decrypter,
+            verify_checksum,
+            checksum_requested: verify_checksum,
+            chunk: Vec::new(),
+            position: 0,
+            finished: false,
+            record_bytes: 0,
+            trailer: None,
+        }
+    }
+}
+impl<'a, R: BufRead + 'a> EncryptedFile<R> {
+    /// Erases the type of the reader, so that files opened from different
+    /// kinds of readers (like files and stdin) can be handled the same way.
+    pub fn boxed(self) -> EncryptedFile<Box<dyn BufRead + 'a>> {
+        EncryptedFile {
+            header: self.header,
+            metadata: self.metadata,
+            cipher: self.cipher,
+            total_bytes: self.total_bytes,
+            stored_bytes: self.stored_bytes,
+            reader: Box::new(self.reader),
+        }
+    }
+}
+impl<R: BufRead + Seek> EncryptedFile<R> {
+    /// Reads the size and checksum of a v3 file from its trailer, without
+    /// decrypting the chunks.
+    pub fn read_trailer(&mut self) -> Result<(), Error> {
+        if self.header.is_some() {
+            let trailer = self.cipher.read_trailer(
+                &mut self.reader,
+                HashingAlgorithm::from_u8(self.metadata.hashing_algorithm),
+            )?;
+            self.metadata.total_bytes = trailer.total_bytes;
+            self.metadata.checksum = trailer.checksum;
+            self.total_bytes = Some(trailer.total_bytes);
+            self.stored_bytes = Some(trailer.stored_bytes);
+        }
+        Ok(())
+    }
+}
+
+/// Decrypts an encrypted file while it's being read. Every chunk is
+/// authenticated before any of it is returned, and reaching the end of the
+/// file means that nothing has been cut off and that the checksum matches.
+/// Errors are returned as `std::io::Error`s that wrap an `sfs::Error`.
+pub struct DecryptReader<R> {
+    /// `None` for v1 and v2 files.
+    pub header: Option<FileHeader>,
+    pub metadata: FileMetadata,
+    reader: R,
+    decrypter: Decrypter<'static>,
+    verify_checksum: bool,
+    /// Whether the checksum is verified again after seeking back to the start.
+    checksum_requested: bool,
+    chunk: Vec<u8>,
+    position: usize,
+    finished: bool,
+    /// How many bytes of records have been read after the metadata.
+    record_bytes: u64,
+    /// Read from the end of the file the first time it's needed for seeking.
+    trailer: Option<FileTrailer>,
+}
+impl<R: Read> DecryptReader<BufReader<R>> {
+    /// Reads the header and metadata, and unlocks the file with the credentials or identity.
+    pub fn new(
+        reader: R,
+        credentials: &Credentials,
+        identity: Option<&Identity>,
+    ) -> Result<Self, Error> {
+        Ok(EncryptedFile::open(BufReader::new(reader), credentials, identity)?.into_reader(true))
+    }
+}
+impl<R: BufRead> DecryptReader<R> {
+    /// Decrypts the next chunk, or returns `None` once the trailer and checksum have been checked.
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if self.finished {
+            return Ok(None);
+        }
+        while let Some(record) = Record::read(&mut self.reader, self.metadata.format_version)? {
+            self.record_bytes += Record::size(record.data.len()) as u64;
+            let data = self.decrypter.decrypt(&record)?;
+            if !data.is_empty() {
+                return Ok(Some(data));
+            }
+        }
+        self.decrypter.finish()?;
+        if self.verify_checksum {
+            let expected_checksum = match &self.decrypter.trailer {
+                Some(trailer) => trailer.checksum.clone(),
+                None => self.metadata.checksum.clone(),
+            };
+            self.decrypter.verify_checksum(&expected_checksum)?;
+        }
+        self.finished = true;
+        Ok(None)
+    }
+
+    /// The size and checksum of everything that has been decrypted so far.
+    pub fn summary(&mut self) -> Summary {
+        let checksum = self.decrypter.get_checksum();
+        Summary {
+            metadata: FileMetadata {
+                total_bytes: self.decrypter.total_bytes,
+                checksum: checksum.clone(),
+                ..self.metadata.clone()
+            },
+            total_bytes: self.decrypter.total_bytes,
+            checksum,
+        }
+    }
+}
+impl<R: BufRead> Read for DecryptReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.chunk.len() {
+            match self.next_chunk()? {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let size = buffer.len().min(self.chunk.len() - self.position);
+        buffer[..size].copy_from_slice(&self.chunk[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+/// Only v3 files can be seeked, since every chunk record except the last one
+/// has the same size, so the record that holds any position can be found
+/// without decrypting everything before it. The chunks that are read are still
+/// authenticated, but the checksum can only be verified after reading the
+/// whole file from the start, so it isn't checked anymore after seeking
+/// anywhere else (until seeking back to the start).
+impl<R: BufRead + Seek> Seek for DecryptReader<R> {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        if self.metadata.format_version < 3 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "only v3 files can be seeked",
+            ));
+        }
+        if self.metadata.compression != Compression::None as u8 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "compressed files can't be seeked",
+            ));
+        }
+        if self.trailer.is_none() {
+            let hashing_algorithm = HashingAlgorithm::from_u8(self.metadata.hashing_algorithm);
+            self.trailer = Some(
+                self.decrypter
+                    .cipher
+                    .read_trailer(&mut self.reader, hashing_algorithm)?,
+            );
+        }
+        let (chunk_count, total_bytes) = match &self.trailer {
+            Some(trailer) => (trailer.chunk_count, trailer.total_bytes),
+            None => unreachable!(),
+        };
+        let current_position =
+            self.decrypter.total_bytes - (self.chunk.len() - self.position) as u64;
+        let new_position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => current_position.checked_add_signed(offset),
+            SeekFrom::End(offset) => total_bytes.checked_add_signed(offset),
+        };
+        let new_position = match new_position {
+            Some(new_position) => new_position,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "invalid seek to a negative position",
+                ))
+            }
+        };
+        if new_position == current_position {
+            return Ok(new_position);
+        }
+
+        let chunk_size = self.metadata.chunk_size;
+        let tag_size = self.decrypter.cipher.seal(0, &[], &[]).len() as u64;
+        let chunk_record_size = Record::size((chunk_size + tag_size) as usize) as u64;
+        let data_start = self.reader.stream_position()? - self.record_bytes;
+        let (chunk_index, record_bytes) = if new_position >= total_bytes {
+            // The trailer is next, and it comes after the last chunk, which
+            // is usually smaller than the others.
+            match chunk_count.checked_sub(1) {
+                Some(last_chunk_index) => {
+                    let last_chunk_size = total_bytes - last_chunk_index * chunk_size;
+                    (
+                        chunk_count,
+                        last_chunk_index * chunk_record_size
+                            + Record::size((last_chunk_size + tag_size) as usize) as u64,
+                    )
+                }
+                None => (0, 0),
+            }
+        } else {
+            let chunk_index = new_position / chunk_size;
+            (chunk_index, chunk_index * chunk_record_size)
+        };
+        self.reader
+            .seek(SeekFrom::Start(data_start + record_bytes))?;
+        self.record_bytes = record_bytes;
+        self.decrypter.chunk_index = chunk_index;
+        self.decrypter.total_bytes = total_bytes.min(chunk_index * chunk_size);
+        // Only uncompressed files can be seeked
+        self.decrypter.stored_bytes = self.decrypter.total_bytes;
+        self.decrypter.trailer = None;
+        self.decrypter.padding_bytes = 0;
+        self.decrypter.padding_index = 0;
+        self.chunk.clear();
+        self.position = 0;
+        self.finished = false;
+        if new_position == 0 && self.checksum_requested {
+            self.decrypter.hasher =
+                get_hasher(HashingAlgorithm::from_u8(self.metadata.hashing_algorithm));
+            self.verify_checksum = true;
+        } else {
+            self.verify_checksum = false;
+        }
+
+        if new_position < total_bytes {
+            if let Some(chunk) = self.next_chunk()? {
+                self.chunk = chunk;
+                self.position = (new_position - chunk_index * chunk_size) as usize;
+            }
+        }
+        Ok(new_position)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveEntryKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    /// Relative to the directory that was archived.
+    pub path: PathBuf,
+    pub kind: ArchiveEntryKind,
+    pub size: u64,
+    pub mode: u32,
+    /// Seconds since the Unix epoch.
+    pub modified: u64,
+    pub link_target: Option<PathBuf>,
+}
+
+/// Reports the progress of reading archives, and keeps the `Error` that
+/// reading failed with, since tar wraps the errors it runs into in its own.
+struct ProgressReader<'a, R> {
+    reader: R,
+    processed_bytes: u64,
+    total_bytes: Option<u64>,
+    progress: Option<&'a dyn Fn(Progress)>,
+    error: Option<Error>,
+}
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        match self.reader.read(buffer) {
+            Ok(read) => {
+                self.processed_bytes += read as u64;
+                if let Some(progress) = self.progress {
+                    progress(Progress {
+                        processed_bytes: self.processed_bytes,
+                        total_bytes: self.total_bytes,
+                    })
+                }
+                Ok(read)
+            }
+            Err(error) => {
+                let error = Error::from(error);
+                let kind = match &error {
+                    Error::Io(error) => error.kind(),
+                    _ => std::io::ErrorKind::InvalidData,
+                };
+                let message = error.to_string();
+                self.error = Some(error);
+                Err(std::io::Error::new(kind, message))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn test_options(credentials: &Credentials) -> EncryptOptions<'_> {
+        EncryptOptions {
+            chunk_size: 16,
+            key_derivation_memory_cost: 8,
+            key_derivation_time_cost: 1,
+            ..EncryptOptions::new(credentials)
+        }
+    }
+
+    fn test_data(size: usize) -> Vec<u8> {
+        (0..size).map(|index| (index * 7 % 251) as u8).collect()
+    }
+
+    fn encrypt_bytes(data: &[u8], options: &EncryptOptions) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        encrypt(data, &mut encrypted, options).unwrap();
+        encrypted
+    }
+
+    fn decrypt_bytes(encrypted: &[u8], options: &DecryptOptions) -> Result<Vec<u8>, Error> {
+        let mut decrypted = Vec::new();
+        decrypt(encrypted, &mut decrypted, options)?;
+        Ok(decrypted)
+    }
+
+    /// Splits a v3 file into its header and the records after it.
+    fn split_records(encrypted: &[u8]) -> (Vec<u8>, Vec<Record>) {
+        let mut reader = encrypted;
+        FileHeader::read(&mut reader).unwrap().unwrap();
+        let header = encrypted[..encrypted.len() - reader.len()].to_vec();
+        let mut records = Vec::new();
+        while let Some(record) = Record::read(&mut reader, SFS_FORMAT_VERSION).unwrap() {
+            records.push(record)
+        }
+        (header, records)
+    }
+
+    fn join_records(header: &[u8], records: &[Record]) -> Vec<u8> {
+        let mut encrypted = header.to_vec();
+        for record in records {
+            encrypted.extend_from_slice(&record.pack())
+        }
+        encrypted
+    }
+
+    /// Writes a v1 or v2 file, which is the encrypted metadata followed by
+    /// one encrypted chunk per line.
+    fn legacy_file(password: &str, metadata: &[u8], chunks: &[&[u8]]) -> Vec<u8> {
+        let fernet = generate_legacy_fernet(password);
+        let mut encrypted = String::new();
+        for data in [metadata].iter().chain(chunks) {
+            encrypted.push_str(&fernet.encrypt(data));
+            encrypted.push('\n');
+        }
+        encrypted.into_bytes()
+    }
+
+    fn legacy_checksum(data: &[u8]) -> u64 {
+        let mut hasher = Xxh64::new(0);
+        hasher.update(data);
+        hasher.digest()
+    }
+
+    /// The metadata of a v1 file, laid out like `BBQQQ` (which is big-endian).
+    fn v1_metadata(checksum: u64, total_bytes: u64, chunk_size: u64) -> Vec<u8> {
+        let mut metadata = vec![1, HashingAlgorithm::Xxh64 as u8];
+        metadata.extend_from_slice(&checksum.to_be_bytes());
+        metadata.extend_from_slice(&total_bytes.to_be_bytes());
+        metadata.extend_from_slice(&chunk_size.to_be_bytes());
+        metadata
+    }
+
+    /// The metadata of a v2 file, laid out like `B255S?QBQQ`.
+    fn v2_metadata(
+        original_name: &str,
+        checksum: u64,
+        total_bytes: u64,
+        chunk_size: u64,
+    ) -> Vec<u8> {
+        let mut metadata = vec![2];
+        let mut original_name = original_name.as_bytes().to_vec();
+        original_name.resize(255, 0);
+        metadata.extend_from_slice(&original_name);
+        metadata.push(true as u8);
+        metadata.extend_from_slice(&total_bytes.to_be_bytes());
+        metadata.push(HashingAlgorithm::Xxh64 as u8);
+        metadata.extend_from_slice(&checksum.to_be_bytes());
+        metadata.extend_from_slice(&chunk_size.to_be_bytes());
+        metadata
+    }
+
+    #[test]
+    fn round_trip() {
+        let credentials = Credentials::new(String::from("password"), None);
+        for cipher in [Cipher::XChaCha20Poly1305, Cipher::Aes256Gcm] {
+            for compression in [Compression::None, Compression::Zstd, Compression::Deflate] {
+                for size in [0, 1, 16, 17, 100] {
+                    let data = test_data(size);
+                    let options = EncryptOptions {
+                        cipher,
+                        compression,
+                        ..test_options(&credentials)
+                    };
+                    let encrypted = encrypt_bytes(&data, &options);
+                    let decrypted =
+                        decrypt_bytes(&encrypted, &DecryptOptions::new(&credentials)).unwrap();
+                    assert_eq!(decrypted, data);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let data = b"some content\n".repeat(1000);
+        for (compression, compression_level) in [
+            (Compression::Zstd, 1),
+            (Compression::Zstd, 19),
+            (Compression::Deflate, 0),
+            (Compression::Deflate, 9),
+        ] {
+            let options = EncryptOptions {
+                chunk_size: 4096,
+                compression,
+                compression_level,
+                ..test_options(&credentials)
+            };
+            let encrypted = encrypt_bytes(&data, &options);
+            let mut encrypted_file =
+                EncryptedFile::open(Cursor::new(&encrypted), &credentials, None).unwrap();
+            encrypted_file.read_trailer().unwrap();
+            assert_eq!(encrypted_file.metadata.compression, compression as u8);
+            let stored_bytes = encrypted_file.stored_bytes.unwrap();
+            // Deflate only stores the data at level 0
+            if compression_level > 0 {
+                assert!(stored_bytes * 10 < data.len() as u64, "{:?}", compression);
+                assert!(encrypted.len() < data.len() / 10, "{:?}", compression);
+            }
+            assert_eq!(
+                decrypt_bytes(&encrypted, &DecryptOptions::new(&credentials)).unwrap(),
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn streaming_round_trip() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let options = test_options(&credentials);
+        let data = test_data(100);
+        let mut encrypt_writer = EncryptWriter::new(Vec::new(), &options).unwrap();
+        for piece in data.chunks(7) {
+            std::io::Write::write_all(&mut encrypt_writer, piece).unwrap();
+        }
+        let (encrypted, summary) = encrypt_writer.finish().unwrap();
+        assert_eq!(summary.total_bytes, 100);
+
+        let mut decrypt_reader =
+            DecryptReader::new(encrypted.as_slice(), &credentials, None).unwrap();
+        let mut decrypted = Vec::new();
+        let mut buffer = [0; 5];
+        loop {
+            match decrypt_reader.read(&mut buffer).unwrap() {
+                0 => break,
+                read => decrypted.extend_from_slice(&buffer[..read]),
+            }
+        }
+        assert_eq!(decrypted, data);
+        assert_eq!(decrypt_reader.summary().checksum, summary.checksum);
+    }
+
+    #[test]
+    fn unfinished_streams_are_rejected() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let options = test_options(&credentials);
+        let mut encrypt_writer = EncryptWriter::new(Vec::new(), &options).unwrap();
+        std::io::Write::write_all(&mut encrypt_writer, &test_data(100)).unwrap();
+        let encrypted = encrypt_writer.get_ref().clone();
+
+        let mut decrypt_reader =
+            DecryptReader::new(encrypted.as_slice(), &credentials, None).unwrap();
+        let error = decrypt_reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert!(matches!(Error::from(error), Error::Truncated));
+    }
+
+    fn seekable_reader(
+        encrypted: Vec<u8>,
+        credentials: &Credentials,
+    ) -> DecryptReader<Cursor<Vec<u8>>> {
+        EncryptedFile::open(Cursor::new(encrypted), credentials, None)
+            .unwrap()
+            .into_reader(true)
+    }
+
+    #[test]
+    fn seeking() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let data = test_data(100);
+        for padding in [Padding::None, Padding::Bucket(256)] {
+            let encrypted = encrypt_bytes(
+                &data,
+                &EncryptOptions {
+                    padding,
+                    ..test_options(&credentials)
+                },
+            );
+            let mut decrypt_reader = seekable_reader(encrypted, &credentials);
+            for position in [99, 0, 15, 16, 17, 32, 50, 100, 150] {
+                assert_eq!(
+                    decrypt_reader.seek(SeekFrom::Start(position)).unwrap(),
+                    position
+                );
+                let mut decrypted = Vec::new();
+                decrypt_reader.read_to_end(&mut decrypted).unwrap();
+                assert_eq!(decrypted, data[data.len().min(position as usize)..]);
+            }
+
+            decrypt_reader.seek(SeekFrom::Start(20)).unwrap();
+            assert_eq!(decrypt_reader.seek(SeekFrom::Current(-10)).unwrap(), 10);
+            let mut buffer = [0; 4];
+            decrypt_reader.read_exact(&mut buffer).unwrap();
+            assert_eq!(buffer, data[10..14]);
+
+            assert_eq!(decrypt_reader.seek(SeekFrom::End(-3)).unwrap(), 97);
+            let mut decrypted = Vec::new();
+            decrypt_reader.read_to_end(&mut decrypted).unwrap();
+            assert_eq!(decrypted, data[97..]);
+
+            assert_eq!(decrypt_reader.seek(SeekFrom::End(0)).unwrap(), 100);
+            assert_eq!(decrypt_reader.read(&mut buffer).unwrap(), 0);
+            assert!(decrypt_reader.seek(SeekFrom::Current(-101)).is_err());
+        }
+    }
+
+    #[test]
+    fn seeking_to_the_start_verifies_the_checksum() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let data = test_data(100);
+        let encrypted = encrypt_bytes(&data, &test_options(&credentials));
+        let expected_checksum = {
+            let mut decrypt_reader = seekable_reader(encrypted.clone(), &credentials);
+            decrypt_reader.read_to_end(&mut Vec::new()).unwrap();
+            decrypt_reader.summary().checksum
+        };
+
+        let mut decrypt_reader = seekable_reader(encrypted, &credentials);
+        decrypt_reader.seek(SeekFrom::End(-10)).unwrap();
+        decrypt_reader.seek(SeekFrom::Start(0)).unwrap();
+        let mut decrypted = Vec::new();
+        decrypt_reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+        assert_eq!(decrypt_reader.summary().checksum, expected_checksum);
+    }
+
+    #[test]
+    fn compressed_files_cant_be_seeked() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let encrypted = encrypt_bytes(
+            &test_data(100),
+            &EncryptOptions {
+                compression: Compression::Zstd,
+                ..test_options(&credentials)
+            },
+        );
+        let mut decrypt_reader = seekable_reader(encrypted, &credentials);
+        assert_eq!(
+            decrypt_reader.seek(SeekFrom::Start(50)).unwrap_err().kind(),
+            std::io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn padding_is_removed() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let data = test_data(20);
+        let options = EncryptOptions {
+            padding: Padding::Bucket(64),
+            ..test_options(&credentials)
+        };
+        let encrypted = encrypt_bytes(&data, &options);
+        let (_, records) = split_records(&encrypted);
+        assert!(records
+            .iter()
+            .any(|record| record.kind == RecordKind::Padding as u8));
+        let decrypted = decrypt_bytes(&encrypted, &DecryptOptions::new(&credentials)).unwrap();
+        assert_eq!(decrypted, data);
+    }
+
+    #[test]
+    fn reordered_chunks_are_rejected() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let encrypted = encrypt_bytes(&test_data(64), &test_options(&credentials));
+        let (header, mut records) = split_records(&encrypted);
+        records.swap(1, 2);
+        assert!(matches!(
+            decrypt_bytes(
+                &join_records(&header, &records),
+                &DecryptOptions::new(&credentials)
+            ),
+            Err(Error::ChunkAuthFailed { index: 0 })
+        ));
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let options = DecryptOptions::new(&credentials);
+        let encrypted = encrypt_bytes(&test_data(64), &test_options(&credentials));
+        let (header, records) = split_records(&encrypted);
+
+        let without_trailer = join_records(&header, &records[..records.len() - 1]);
+        assert!(matches!(
+            decrypt_bytes(&without_trailer, &options),
+            Err(Error::Truncated)
+        ));
+
+        let mut without_last_chunk = records.clone();
+        without_last_chunk.remove(records.len() - 2);
+        assert!(matches!(
+            decrypt_bytes(&join_records(&header, &without_last_chunk), &options),
+            Err(Error::CorruptTrailer(_))
+        ));
+
+        assert!(matches!(
+            decrypt_bytes(&encrypted[..encrypted.len() - 1], &options),
+            Err(Error::Truncated)
+        ));
+
+        let mut trailing_data = encrypted.clone();
+        trailing_data.extend_from_slice(&records[1].pack());
+        assert!(matches!(
+            decrypt_bytes(&trailing_data, &options),
+            Err(Error::TrailingData)
+        ));
+    }
+
+    #[test]
+    fn modified_trailer_and_padding_are_rejected() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let options = DecryptOptions::new(&credentials);
+        let encrypted = encrypt_bytes(
+            &test_data(20),
+            &EncryptOptions {
+                padding: Padding::Bucket(64),
+                ..test_options(&credentials)
+            },
+        );
+        let (header, records) = split_records(&encrypted);
+
+        let mut modified_trailer = records.clone();
+        modified_trailer.last_mut().unwrap().data[0] ^= 1;
+        assert!(matches!(
+            decrypt_bytes(&join_records(&header, &modified_trailer), &options),
+            Err(Error::CorruptTrailer(_))
+        ));
+
+        let padding_index = records
+            .iter()
+            .position(|record| record.kind == RecordKind::Padding as u8)
+            .unwrap();
+        let mut modified_padding = records.clone();
+        modified_padding[padding_index].data[0] ^= 1;
+        assert!(matches!(
+            decrypt_bytes(&join_records(&header, &modified_padding), &options),
+            Err(Error::CorruptPadding(_))
+        ));
+
+        let without_padding: Vec<Record> = records
+            .into_iter()
+            .filter(|record| record.kind != RecordKind::Padding as u8)
+            .collect();
+        assert!(matches!(
+            decrypt_bytes(&join_records(&header, &without_padding), &options),
+            Err(Error::CorruptTrailer(_))
+        ));
+    }
+
+    #[test]
+    fn headers_from_other_files_are_rejected() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let (header, _) =
+            split_records(&encrypt_bytes(&test_data(20), &test_options(&credentials)));
+        let (_, records) =
+            split_records(&encrypt_bytes(&test_data(40), &test_options(&credentials)));
+        assert!(matches!(
+            decrypt_bytes(
+                &join_records(&header, &records),
+                &DecryptOptions::new(&credentials)
+            ),
+            Err(Error::HeaderMismatch)
+        ));
+    }
+
+    #[test]
+    fn wrong_credentials_are_rejected() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let encrypted = encrypt_bytes(&test_data(20), &test_options(&credentials));
+
+        let wrong_password = Credentials::new(String::from("wrong password"), None);
+        assert!(matches!(
+            decrypt_bytes(&encrypted, &DecryptOptions::new(&wrong_password)),
+            Err(Error::WrongKey)
+        ));
+
+        let with_keyfile = Credentials::new(String::from("password"), Some(b"keyfile"));
+        assert!(matches!(
+            decrypt_bytes(&encrypted, &DecryptOptions::new(&with_keyfile)),
+            Err(Error::WrongFactors { .. })
+        ));
+    }
+
+    #[test]
+    fn keyfiles_are_required_when_they_were_used() {
+        let data = test_data(20);
+        let with_keyfile = Credentials::new(String::from("password"), Some(b"keyfile"));
+        let encrypted = encrypt_bytes(&data, &test_options(&with_keyfile));
+        let decrypted = decrypt_bytes(&encrypted, &DecryptOptions::new(&with_keyfile)).unwrap();
+        assert_eq!(decrypted, data);
+
+        let password_only = Credentials::new(String::from("password"), None);
+        assert!(matches!(
+            decrypt_bytes(&encrypted, &DecryptOptions::new(&password_only)),
+            Err(Error::WrongFactors { .. })
+        ));
+        let other_keyfile = Credentials::new(String::from("password"), Some(b"other keyfile"));
+        assert!(matches!(
+            decrypt_bytes(&encrypted, &DecryptOptions::new(&other_keyfile)),
+            Err(Error::WrongKey)
+        ));
+
+        let keyfile_only = Credentials::new(String::new(), Some(b"keyfile"));
+        let encrypted = encrypt_bytes(&data, &test_options(&keyfile_only));
+        let decrypted = decrypt_bytes(&encrypted, &DecryptOptions::new(&keyfile_only)).unwrap();
+        assert_eq!(decrypted, data);
+        assert!(matches!(
+            decrypt_bytes(&encrypted, &DecryptOptions::new(&with_keyfile)),
+            Err(Error::WrongFactors { .. })
+        ));
+    }
+
+    #[test]
+    fn password_verifier() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let verifier =
+            PasswordVerifier::parse(&PasswordVerifier::new(&credentials, 8, 1, 1).unwrap().pack())
+                .unwrap();
+        assert!(verifier.verify(&credentials).unwrap());
+        let wrong_password = Credentials::new(String::from("wrong password"), None);
+        assert!(!verifier.verify(&wrong_password).unwrap());
+        let with_keyfile = Credentials::new(String::from("password"), Some(b"keyfile"));
+        assert!(!verifier.verify(&with_keyfile).unwrap());
+        assert!(matches!(
+            PasswordVerifier::parse(b"not a verifier"),
+            Err(Error::InvalidVerifier(_))
+        ));
+    }
+
+    #[test]
+    fn only_recipients_can_decrypt() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let recipient = Identity::generate();
+        let other_identity = Identity::generate();
+        let data = test_data(20);
+        let encrypted = encrypt_bytes(
+            &data,
+            &EncryptOptions {
+                recipients: vec![recipient.public_key()],
+                ..test_options(&credentials)
+            },
+        );
+
+        let decrypted = decrypt_bytes(
+            &encrypted,
+            &DecryptOptions {
+                identity: Some(&recipient),
+                ..DecryptOptions::new(&credentials)
+            },
+        )
+        .unwrap();
+        assert_eq!(decrypted, data);
+
+        assert!(matches!(
+            decrypt_bytes(&encrypted, &DecryptOptions::new(&credentials)),
+            Err(Error::WrongKey)
+        ));
+        assert!(matches!(
+            decrypt_bytes(
+                &encrypted,
+                &DecryptOptions {
+                    identity: Some(&other_identity),
+                    ..DecryptOptions::new(&credentials)
+                }
+            ),
+            Err(Error::WrongKey)
+        ));
+    }
+
+    #[test]
+    fn legacy_files_can_be_decrypted() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let data = test_data(20);
+        let chunks: Vec<&[u8]> = data.chunks(8).collect();
+        let checksum = legacy_checksum(&data);
+
+        let encrypted = legacy_file("password", &v1_metadata(checksum, 20, 8), &chunks);
+        let decrypted = decrypt_bytes(&encrypted, &DecryptOptions::new(&credentials)).unwrap();
+        assert_eq!(decrypted, data);
+
+        let encrypted = legacy_file(
+            "password",
+            &v2_metadata("file.txt", checksum, 20, 8),
+            &chunks,
+        );
+        let mut decrypted = Vec::new();
+        let summary = decrypt(
+            encrypted.as_slice(),
+            &mut decrypted,
+            &DecryptOptions::new(&credentials),
+        )
+        .unwrap();
+        assert_eq!(decrypted, data);
+        assert_eq!(summary.metadata.original_name, "file.txt");
+
+        let wrong_password = Credentials::new(String::from("wrong password"), None);
+        assert!(matches!(
+            decrypt_bytes(&encrypted, &DecryptOptions::new(&wrong_password)),
+            Err(Error::WrongKey)
+        ));
+
+        let encrypted = legacy_file("password", &v1_metadata(checksum ^ 1, 20, 8), &chunks);
+        assert!(matches!(
+            decrypt_bytes(&encrypted, &DecryptOptions::new(&credentials)),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn headers_of_older_versions_are_rejected() {
+        let credentials = Credentials::new(String::from("password"), None);
+        let mut encrypted = encrypt_bytes(&test_data(20), &test_options(&credentials));
+        for version in [0, 1, 2] {
+            encrypted[SFS_MAGIC.len()] = version;
+            assert!(matches!(
+                decrypt_bytes(&encrypted, &DecryptOptions::new(&credentials)),
+                Err(Error::CorruptHeader(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn failed_verification_removes_the_output() {
+        let directory = tempfile::tempdir().unwrap();
+        let input_path = directory.path().join("file.txt");
+        let output_path = directory.path().join("file.txt.sfs");
+        fs::write(&input_path, test_data(100)).unwrap();
+        let credentials = Credentials::new(String::from("password"), None);
+
+        // Something else modifies the encrypted file while it's being written
+        let corrupted = std::cell::Cell::new(false);
+        let corrupt = |_: Progress| {
+            for entry in fs::read_dir(directory.path()).unwrap() {
+                let path = entry.unwrap().path();
+                let mut data = fs::read(&path).unwrap();
+                if path.extension().unwrap() == "tmp" && !data.is_empty() && !corrupted.get() {
+                    *data.last_mut().unwrap() ^= 1;
+                    fs::write(&path, data).unwrap();
+                    corrupted.set(true);
+                }
+            }
+        };
+        let options = EncryptOptions {
+            verify: true,
+            progress: Some(&corrupt),
+            ..test_options(&credentials)
+        };
+        assert!(encrypt_file(&input_path, &output_path, &options).is_err());
+        assert!(corrupted.get());
+        assert_eq!(fs::read(&input_path).unwrap(), test_data(100));
+        let file_names: Vec<_> = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(file_names, ["file.txt"]);
+    }
+
+    #[test]
+    fn shredding_overwrites_and_removes_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("file.txt");
+        fs::write(&path, test_data(100000)).unwrap();
+        // The hard link keeps the overwritten data around after the file is removed
+        let link_path = directory.path().join("link");
+        fs::hard_link(&path, &link_path).unwrap();
+
+        shred_file(&path, 2).unwrap();
+        assert!(!path.exists());
+        let overwritten = fs::read(&link_path).unwrap();
+        assert_eq!(overwritten.len(), 100000);
+        assert_ne!(overwritten, test_data(100000));
+        let file_names: Vec<_> = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(file_names, ["link"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_regular_files_are_shredded() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("file.txt");
+        fs::write(&path, "some content").unwrap();
+        let symlink_path = directory.path().join("symlink");
+        std::os::unix::fs::symlink(&path, &symlink_path).unwrap();
+
+        assert!(shred_file(&symlink_path, 1).is_err());
+        assert!(shred_file(directory.path(), 1).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "some content");
+        assert!(fs::symlink_metadata(&symlink_path).is_ok());
+    }
+
+    #[test]
+    fn copy_on_write_filesystems_are_detected() {
+        assert_eq!(copy_on_write_filesystem(Path::new("/missing/path")), None);
+        #[cfg(target_os = "linux")]
+        assert_eq!(copy_on_write_filesystem(Path::new("/proc")), None);
+    }
+
+    #[test]
+    fn archives_round_trip() {
+        let directory = tempfile::tempdir().unwrap();
+        let input_path = directory.path().join("directory");
+        fs::create_dir_all(input_path.join("nested/empty")).unwrap();
+        fs::write(input_path.join("file.txt"), test_data(1000)).unwrap();
+        fs::write(input_path.join("nested/file.txt"), "nested content").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("file.txt", input_path.join("symlink")).unwrap();
+        let archive_path = directory.path().join("directory.sfs");
+        let credentials = Credentials::new(String::from("password"), None);
+        encrypt_archive(&input_path, &archive_path, &test_options(&credentials)).unwrap();
+
+        let open = || {
+            EncryptedFile::open(
+                BufReader::new(File::open(&archive_path).unwrap()),
+                &credentials,
+                None,
+            )
+        };
+        let mut entries = open().unwrap().list_archive(true).unwrap();
+        entries.sort_by(|first, second| first.path.cmp(&second.path));
+        let mut expected_entries = vec![
+            (PathBuf::from("file.txt"), ArchiveEntryKind::File, 1000),
+            (PathBuf::from("nested"), ArchiveEntryKind::Directory, 0),
+            (
+                PathBuf::from("nested/empty"),
+                ArchiveEntryKind::Directory,
+                0,
+            ),
+            (PathBuf::from("nested/file.txt"), ArchiveEntryKind::File, 14),
+        ];
+        #[cfg(unix)]
+        expected_entries.push((PathBuf::from("symlink"), ArchiveEntryKind::Symlink, 0));
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.path.clone(), entry.kind, entry.size))
+                .collect::<Vec<_>>(),
+            expected_entries
+        );
+
+        let output_path = directory.path().join("extracted");
+        open()
+            .unwrap()
+            .extract_to(&output_path, &DecryptOptions::new(&credentials))
+            .unwrap();
+        assert_eq!(
+            fs::read(output_path.join("file.txt")).unwrap(),
+            test_data(1000)
+        );
+        assert_eq!(
+            fs::read_to_string(output_path.join("nested/file.txt")).unwrap(),
+            "nested content"
+        );
+        assert!(output_path.join("nested/empty").is_dir());
+        #[cfg(unix)]
+        assert_eq!(
+            fs::read_link(output_path.join("symlink")).unwrap(),
+            Path::new("file.txt")
+        );
+
+        // Files that aren't archives can't be listed or extracted
+        let file_path = directory.path().join("file.txt.sfs");
+        encrypt_file(
+            &input_path.join("file.txt"),
+            &file_path,
+            &test_options(&credentials),
+        )
+        .unwrap();
+        let open_file = || {
+            EncryptedFile::open(
+                BufReader::new(File::open(&file_path).unwrap()),
+                &credentials,
+                None,
+            )
+        };
+        assert!(matches!(
+            open_file().unwrap().list_archive(true),
+            Err(Error::NotAnArchive)
+        ));
+        assert!(matches!(
+            open_file()
+                .unwrap()
+                .extract_to(&output_path, &DecryptOptions::new(&credentials)),
+            Err(Error::NotAnArchive)
+        ));
+    }
+
+    #[test]
+    fn archive_entries_cant_be_extracted_outside_of_the_output() {
+        let directory = tempfile::tempdir().unwrap();
+        let outside_path = directory.path().join("outside.txt");
+        // The tar crate refuses to write these paths, so the headers are filled in by hand
+        let mut builder = tar::Builder::new(Vec::new());
+        for name in [
+            String::from("inside.txt"),
+            String::from("../outside.txt"),
+            String::from("nested/../../outside.txt"),
+            outside_path.display().to_string(),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_size(7);
+            header.set_cksum();
+            builder.append(&header, &b"content"[..]).unwrap();
+        }
+        let credentials = Credentials::new(String::from("password"), None);
+        let encrypted = encrypt_bytes(
+            &builder.into_inner().unwrap(),
+            &EncryptOptions {
+                archive: true,
+                ..test_options(&credentials)
+            },
+        );
+
+        let output_path = directory.path().join("extracted");
+        EncryptedFile::open(Cursor::new(encrypted), &credentials, None)
+            .unwrap()
+            .extract_to(&output_path, &DecryptOptions::new(&credentials))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(output_path.join("inside.txt")).unwrap(),
+            "content"
+        );
+        assert!(!outside_path.exists());
+        // Absolute paths end up inside of the output
+        for entry in walkdir::WalkDir::new(&output_path) {
+            assert!(entry.unwrap().path().starts_with(&output_path));
+        }
+        let file_names: Vec<_> = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(file_names, ["extracted"]);
+    }
+
+    #[test]
+    fn failed_writes_leave_the_destination_untouched() {
+        let directory = tempfile::tempdir().unwrap();
+        let output_path = directory.path().join("file.txt");
+        fs::write(&output_path, "old content").unwrap();
+
+        let result = write_atomically(&output_path, |file| -> Result<(), Error> {
+            std::io::Write::write_all(file, b"partial content")?;
+            Err(Error::Truncated)
+        });
+        assert!(matches!(result, Err(Error::Truncated)));
+        assert_eq!(fs::read_to_string(&output_path).unwrap(), "old content");
+        let file_names: Vec<_> = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(file_names, ["file.txt"]);
+
+        // The temporary file is hidden next to the destination until it's renamed
+        write_atomically(&output_path, |file| {
+            std::io::Write::write_all(file, b"new content")?;
+            let file_names: Vec<_> = fs::read_dir(directory.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .filter(|file_name| file_name != "file.txt")
+                .collect();
+            assert_eq!(file_names.len(), 1);
+            let random_name = file_names[0]
+                .strip_prefix(".file.txt.")
+                .and_then(|file_name| file_name.strip_suffix(".tmp"))
+                .unwrap();
+            assert_eq!(random_name.len(), 8);
+            assert!(random_name.chars().all(|c| c.is_ascii_alphanumeric()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(fs::read_to_string(&output_path).unwrap(), "new content");
+        let file_names: Vec<_> = fs::read_dir(directory.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(file_names, ["file.txt"]);
+    }
 }
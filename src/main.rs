@@ -1,9 +1,10 @@
 mod commands;
+mod journal;
 mod utilities;
 
 use commands::{
-    get_commands, Context, DecryptCommandConfiguration, EncryptCommandConfiguration,
-    LsCommandConfiguration, ParsedCommand, ParsedFlag,
+    get_commands, Command, Context, DecryptCommandConfiguration, EncryptCommandConfiguration,
+    LsCommandConfiguration, ParsedCommand, ParsedFlag, ShredCommandConfiguration,
 };
 use rustyline::highlight::{Highlighter, MatchingBracketHighlighter};
 use rustyline::{
@@ -11,20 +12,40 @@ use rustyline::{
 };
 use rustyline_derive::{Completer, Helper, Hinter, Validator};
 use serde_derive::{Deserialize, Serialize};
+use sfs::{Credentials, PasswordVerifier, SecretBytes};
 use std::borrow::Cow::{self, Owned};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Once};
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::{fs, io::Write};
-use utilities::{debug_print, format_colors, generate_fernet, quit_sfs, tokenize};
+use utilities::{
+    debug_print, format_colors, get_configuration_directory, quit_sfs, read_password_source,
+    tokenize, PasswordSource, INTERRUPTED,
+};
+use zeroize::Zeroizing;
+
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_COMMAND_FAILED: i32 = 1;
+const EXIT_USAGE_ERROR: i32 = 2;
+const EXIT_CREDENTIALS_ERROR: i32 = 3;
+const EXIT_INTERRUPTED: i32 = 130;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Configuration {
     prompt: String,
     debug_mode: bool,
+    #[serde(default)]
+    keyfile: String,
+    #[serde(default)]
+    keyfile_only: bool,
+    #[serde(default = "default_verify_password")]
+    verify_password: bool,
     list_command: LsCommandConfiguration,
     encrypt_command: EncryptCommandConfiguration,
     decrypt_command: DecryptCommandConfiguration,
+    #[serde(default = "default_shred_command")]
+    shred_command: ShredCommandConfiguration,
 }
 
 impl Configuration {
@@ -32,6 +53,9 @@ impl Configuration {
         Configuration {
             prompt: String::from("$BOLD$$BLUE$$sfs::path$ >$NORMAL$ "),
             debug_mode: false,
+            keyfile: String::new(),
+            keyfile_only: false,
+            verify_password: true,
             list_command: LsCommandConfiguration {
                 display_all_files: false,
                 list_view: false,
@@ -50,6 +74,15 @@ impl Configuration {
                 hashing_algorithm: String::from("xxh3"),
                 chunk_size: 1048576,
                 assign_random_name: false,
+                cipher: String::from("xchacha20poly1305"),
+                compression: String::from("none"),
+                compression_level: 3,
+                padding: String::from("none"),
+                verify: true,
+                secure_delete: false,
+                key_derivation_memory_cost: 19456,
+                key_derivation_time_cost: 2,
+                key_derivation_parallelism: 1,
                 progress_bar_format: String::from(
                     "$BOLD$$sfs::file.name$:$NORMAL$ [{elapsed_precise}] [{wide_bar:.blue/white}] {bytes}/{total_bytes} ({eta})",
                 ),
@@ -61,14 +94,26 @@ impl Configuration {
                 keep_file: false,
                 use_original_name: false,
                 no_verify_chunks: false,
+                verify: true,
                 progress_bar_format: String::from(
                     "$BOLD$$sfs::file.name$:$NORMAL$ [{elapsed_precise}] [{wide_bar:.blue/white}] {bytes}/{total_bytes} ({eta})",
                 ),
             },
+            shred_command: default_shred_command(),
         }
     }
 }
 
+fn default_verify_password() -> bool {
+    true
+}
+
+fn default_shred_command() -> ShredCommandConfiguration {
+    ShredCommandConfiguration {
+        passes: commands::default_passes(),
+    }
+}
+
 #[derive(Helper, Completer, Hinter, Validator)]
 struct AutocompleteHelper {
     #[rustyline(Completer)]
@@ -93,15 +138,7 @@ impl Highlighter for AutocompleteHelper {
 }
 
 fn main() {
-    let mut configuration_path = match home::home_dir() {
-        Some(path) => path,
-        None => PathBuf::from("."),
-    };
-    if cfg!(unix) {
-        configuration_path.push(".config/sfs")
-    } else if cfg!(windows) {
-        configuration_path.push("AppData/Roaming/sfs")
-    }
+    let configuration_path = get_configuration_directory();
     match fs::create_dir_all(&configuration_path) {
         Ok(_) => (),
         Err(error) => {
@@ -115,42 +152,115 @@ fn main() {
             return;
         }
     };
+    let mut keyfile_path = None;
+    let mut password_source = None;
+    let mut skip_verify = false;
+    let mut command_tokens = Vec::new();
+    let mut arguments = std::env::args().skip(1);
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--keyfile" => match arguments.next() {
+                Some(path) => keyfile_path = Some(path),
+                None => {
+                    println!("No keyfile specified. Quitting...");
+                    std::process::exit(EXIT_USAGE_ERROR)
+                }
+            },
+            "--skip-verify" => skip_verify = true,
+            "--password-fd" | "--password-file" | "--password-command" => {
+                let value = match arguments.next() {
+                    Some(value) => value,
+                    None => {
+                        println!(
+                            "{}",
+                            format_colors(&format!(
+                                "No value specified for $BOLD$`{}`$NORMAL$. Quitting...",
+                                argument
+                            ))
+                        );
+                        std::process::exit(EXIT_USAGE_ERROR)
+                    }
+                };
+                if password_source.is_some() {
+                    println!("Only one password source can be used. Quitting...");
+                    std::process::exit(EXIT_USAGE_ERROR)
+                }
+                password_source = Some(match argument.as_str() {
+                    "--password-fd" => match value.parse() {
+                        Ok(file_descriptor) => PasswordSource::FileDescriptor(file_descriptor),
+                        Err(_) => {
+                            println!(
+                                "{}",
+                                format_colors(&format!(
+                                    "Invalid file descriptor $BOLD$`{}`$NORMAL$. Quitting...",
+                                    value
+                                ))
+                            );
+                            std::process::exit(EXIT_USAGE_ERROR)
+                        }
+                    },
+                    "--password-file" => PasswordSource::File(value),
+                    _ => PasswordSource::Command(value),
+                });
+            }
+            _ if !argument.starts_with('-') => {
+                command_tokens.push(argument);
+                command_tokens.extend(arguments.by_ref());
+            }
+            _ => {
+                println!(
+                    "{}",
+                    format_colors(&format!("Unknown option $BOLD$`{}`$NORMAL$", argument))
+                );
+                std::process::exit(EXIT_USAGE_ERROR)
+            }
+        }
+    }
     let mut configuration_file = configuration_path.clone();
     configuration_file.push("configuration.toml");
     let configuration_string = match fs::read_to_string(&configuration_file) {
         Ok(configuration) => configuration.trim().to_string(),
         Err(_) => String::new(),
     };
-    let configuration: Configuration = match toml::from_str(&configuration_string.as_str()) {
+    let configuration: Configuration = match toml::from_str(configuration_string.as_str()) {
         Ok(configuration) => configuration,
-        Err(_) => {
+        Err(error) => {
             if !configuration_string.is_empty() {
+                // Scripts can't answer the question, so they stop instead of replacing the file
                 let mut input = String::new();
-                loop {
-                    if input.to_lowercase().starts_with("n") {
-                        return;
-                    } else if input.to_lowercase().starts_with("y") {
-                        println!();
-                        break;
-                    } else {
-                        print!("{}", format_colors(&String::from("Your configuration file seems to be corrupted/incomplete. Would you like to overwrite it with a completely new one? $BOLD$Y/N:$NORMAL$ ")));
-                        std::io::stdout().flush().unwrap();
-                        input.clear();
-                        match std::io::stdin().read_line(&mut input) {
-                            Ok(_) => (),
-                            Err(error) => {
-                                println!(
-                                    "{} {:?}",
-                                    format_colors(&String::from(
-                                        "$BOLD$Unable to read input:$NORMAL$"
-                                    )),
-                                    error
-                                );
-                                std::process::exit(1)
-                            }
+                while command_tokens.is_empty()
+                    && !input.to_lowercase().starts_with('n')
+                    && !input.to_lowercase().starts_with('y')
+                {
+                    print!("{}", format_colors(&String::from("Your configuration file seems to be corrupted/incomplete. Would you like to overwrite it with a completely new one? $BOLD$Y/N:$NORMAL$ ")));
+                    std::io::stdout().flush().unwrap();
+                    input.clear();
+                    match std::io::stdin().read_line(&mut input) {
+                        // Nobody is there to answer the question when the input has ended
+                        Ok(0) => {
+                            println!();
+                            break;
+                        }
+                        Ok(_) => (),
+                        Err(error) => {
+                            println!(
+                                "{} {:?}",
+                                format_colors(&String::from("$BOLD$Unable to read input:$NORMAL$")),
+                                error
+                            );
+                            std::process::exit(EXIT_COMMAND_FAILED)
                         }
                     }
                 }
+                if !input.to_lowercase().starts_with('y') {
+                    println!(
+                        "{} {}",
+                        format_colors(&String::from("$BOLD$Unable to read configuration:$NORMAL$")),
+                        error
+                    );
+                    std::process::exit(EXIT_COMMAND_FAILED)
+                }
+                println!();
             }
 
             let configuration = Configuration::default();
@@ -166,51 +276,147 @@ fn main() {
         }
     };
 
-    if configuration_string.is_empty() {
-        println!("{}", format_colors(&format!("$BOLD$Welcome to the $BLUE$SFS$NORMAL$$BOLD$ shell! This message will only appear once.\n$BOLD$Please enter a password. This password is used to encrypt/decrypt your files, and you must re-enter it every time you launch SFS.\nYou may enter a different password every time you launch SFS, but the files encrypted with your old password won't be accessible.$NORMAL$")));
-    }
-    print!("Password: ");
-    std::io::stdout().flush().unwrap();
-    let mut password;
-    match rpassword::read_password() {
-        Ok(result) => password = result,
-        Err(error) => {
-            println!(
-                "{} {:?}",
-                format_colors(&String::from("$BOLD$Unable to read input:$NORMAL$")),
-                error
-            );
-            std::process::exit(1)
+    let keyfile_path = keyfile_path.unwrap_or_else(|| configuration.keyfile.clone());
+    let keyfile = if keyfile_path.is_empty() {
+        None
+    } else {
+        match fs::read(&keyfile_path) {
+            Ok(keyfile) => Some(sfs::hash_keyfile(&Zeroizing::new(keyfile))),
+            Err(error) => {
+                println!(
+                    "{} {:?}",
+                    format_colors(&format!(
+                        "$BOLD$[{}] Unable to read keyfile:$NORMAL$",
+                        keyfile_path
+                    )),
+                    error
+                );
+                std::process::exit(EXIT_CREDENTIALS_ERROR)
+            }
         }
+    };
+    if password_source.is_none() && std::env::var_os("SFS_PASSWORD").is_some() {
+        password_source = Some(PasswordSource::Environment(String::from("SFS_PASSWORD")));
     }
-    if password.len() <= 0 {
-        println!("No password specified. Quitting...");
-        std::process::exit(1)
-    }
-    print!("Repeat Password: ");
-    std::io::stdout().flush().unwrap();
-    match rpassword::read_password() {
-        Ok(repeat_password) => {
-            if password != repeat_password {
+    let verifier_path = configuration_path.join("verifier");
+    let verify_password = configuration.verify_password && !skip_verify;
+    let commands = get_commands();
+
+    // Commands that are given on the command line are run once, without the shell
+    if !command_tokens.is_empty() {
+        if configuration.debug_mode {
+            debug_print(&format!("tokens: {:?}", command_tokens));
+        }
+        let command = match find_command(&commands, &command_tokens[0]) {
+            Some(command) => command,
+            None => {
                 println!(
                     "{}",
-                    format_colors(&String::from("$BOLD$Passwords do not match!$NORMAL$"))
+                    format_colors(&format!(
+                        "Unknown command $BOLD$`{}`$NORMAL$. Run $BOLD$`sfs help`$NORMAL$ for a list of commands.",
+                        command_tokens[0]
+                    ))
                 );
-                return;
+                std::process::exit(EXIT_USAGE_ERROR)
             }
+        };
+        let mut credentials = None;
+        if command.contexts.contains(&"credentials") {
+            let password = match &password_source {
+                Some(password_source) => match read_password_source(password_source) {
+                    Ok(password) => password,
+                    Err(error) => {
+                        println!("{}", error);
+                        std::process::exit(EXIT_CREDENTIALS_ERROR)
+                    }
+                },
+                None if keyfile.is_some() => String::new(),
+                None => {
+                    println!(
+                        "{}",
+                        format_colors("$BOLD$No password given:$NORMAL$ use $BOLD$--password-fd$NORMAL$, $BOLD$--password-file$NORMAL$, $BOLD$--password-command$NORMAL$, $BOLD$SFS_PASSWORD$NORMAL$ or a keyfile")
+                    );
+                    std::process::exit(EXIT_CREDENTIALS_ERROR)
+                }
+            };
+            let command_credentials = Credentials::with_keyfile_digest(password, keyfile.clone());
+            // A verifier is only saved by the shell (and by rekey, which changes the password
+            // on purpose), so that a script can't set the password that's expected from then
+            // on without anyone noticing
+            if verify_password
+                && !verify_credentials(&verifier_path, &command_credentials, &configuration, false)
+            {
+                println!("{}", format_colors("$BOLD$Your password (or keyfile) is different from the one you used last time.$NORMAL$ Run SFS interactively to set it as your new password, pass $BOLD$--skip-verify$NORMAL$, or turn off $BOLD$verify_password$NORMAL$."));
+                std::process::exit(EXIT_CREDENTIALS_ERROR)
+            }
+            credentials = Some(Arc::new(command_credentials));
         }
-        Err(error) => {
-            println!(
-                "{} {:?}",
-                format_colors(&String::from("$BOLD$Unable to read input:$NORMAL$")),
-                error,
-            );
-            std::process::exit(1)
+        if run_command(
+            command,
+            &command_tokens,
+            &configuration,
+            credentials.as_ref(),
+        ) {
+            std::process::exit(EXIT_SUCCESS)
+        } else {
+            std::process::exit(EXIT_COMMAND_FAILED)
+        }
+    }
+
+    if configuration_string.is_empty() {
+        println!("{}", format_colors("$BOLD$Welcome to the $BLUE$SFS$NORMAL$$BOLD$ shell! This message will only appear once.\n$BOLD$Please enter a password. This password is used to encrypt/decrypt your files, and you must re-enter it every time you launch SFS.\nYou may enter a different password every time you launch SFS, but the files encrypted with your old password won't be accessible unless you change their password with `rekey`.$NORMAL$"));
+    }
+    // The password verifier catches typos, so the password only has to be repeated the first time
+    let repeat_password = !verify_password || !verifier_path.exists();
+    let mut credentials = match &password_source {
+        Some(password_source) => match read_password_source(password_source) {
+            Ok(password) => Credentials::with_keyfile_digest(password, keyfile.clone()),
+            Err(error) => {
+                println!("{}", error);
+                std::process::exit(EXIT_CREDENTIALS_ERROR)
+            }
+        },
+        None => read_credentials(
+            keyfile.as_ref(),
+            configuration.keyfile_only,
+            repeat_password,
+        ),
+    };
+    if verify_password {
+        'verify_loop: while !verify_credentials(&verifier_path, &credentials, &configuration, true)
+        {
+            let mut input = String::new();
+            loop {
+                let choice = input.trim().to_lowercase();
+                if choice.starts_with('c') {
+                    break 'verify_loop;
+                } else if choice.starts_with('s') {
+                    write_password_verifier(&verifier_path, &credentials, &configuration);
+                    break 'verify_loop;
+                } else if choice.starts_with('r') {
+                    credentials =
+                        read_credentials(keyfile.as_ref(), configuration.keyfile_only, false);
+                    continue 'verify_loop;
+                } else {
+                    print!("{}", format_colors(&String::from("$BOLD$Warning:$NORMAL$ Your password (or keyfile) is different from the one you used last time. Would you like to continue anyway, set it as your new password, or re-enter it? $BOLD$C/S/R:$NORMAL$ ")));
+                    std::io::stdout().flush().unwrap();
+                    input.clear();
+                    match std::io::stdin().read_line(&mut input) {
+                        Ok(0) => std::process::exit(1),
+                        Ok(_) => (),
+                        Err(error) => {
+                            println!(
+                                "{} {:?}",
+                                format_colors(&String::from("$BOLD$Unable to read input:$NORMAL$")),
+                                error
+                            );
+                            std::process::exit(1)
+                        }
+                    }
+                }
+            }
         }
     }
-    let fernet = generate_fernet(&password);
-    password = String::new();
-    password.clear();
 
     let editor_configuration = Config::builder()
         .history_ignore_space(true)
@@ -224,9 +430,10 @@ fn main() {
     };
     editor.set_helper(Some(autocomplete_helper));
 
-    let commands = get_commands();
+    let credentials = Arc::new(credentials);
+
     loop {
-        println!("{}", format_colors(&String::new()));
+        println!("{}", format_colors(""));
 
         let current_path;
         match std::env::current_dir() {
@@ -257,7 +464,6 @@ fn main() {
             }
             Err(ReadlineError::Eof) => {
                 println!("EOF!");
-                input = String::new();
                 quit_sfs();
             }
             Err(error) => {
@@ -266,7 +472,6 @@ fn main() {
                     format_colors(&String::from("$BOLD$Error:$NORMAL$")),
                     error
                 );
-                input = String::new();
                 quit_sfs();
             }
         }
@@ -275,14 +480,14 @@ fn main() {
         if configuration.debug_mode {
             debug_print(&format!("tokens: {:?}", tokens));
         }
-        let first_token = match tokens.iter().nth(0) {
+        let first_token = match tokens.first() {
             Some(token) => token.to_string(),
             None => continue,
         };
         if first_token.starts_with("!") {
             let mut characters = input.chars();
             characters.next();
-            let tokens = tokenize(&characters.as_str().to_string());
+            let tokens = tokenize(characters.as_str());
             match std::process::Command::new(&tokens[0])
                 .args(&tokens[1..])
                 .spawn()
@@ -304,104 +509,334 @@ fn main() {
             continue;
         }
 
-        let mut command_found = false;
-        for command in &commands {
-            let mut matched = false;
-            if command.name.to_string() == first_token {
-                matched = true;
-            } else {
-                for alias in command.aliases {
-                    if alias.to_string() == first_token {
-                        matched = true;
+        match find_command(&commands, &first_token) {
+            Some(command) => {
+                run_command(command, &tokens, &configuration, Some(&credentials));
+            }
+            None => println!(
+                "{}",
+                format_colors(&String::from(
+                    "$BOLD$Unknown command!$NORMAL$ Type $BOLD$`help`$NORMAL$ for a list of commands."
+                ))
+            ),
+        }
+    }
+}
+
+fn find_command<'a>(commands: &'a [Command], name: &str) -> Option<&'a Command> {
+    commands
+        .iter()
+        .find(|command| command.name == name || command.aliases.contains(&name))
+}
+
+/// Parses the flags of a command and runs it, returning whether it succeeded.
+fn run_command(
+    command: &Command,
+    tokens: &[String],
+    configuration: &Configuration,
+    credentials: Option<&Arc<Credentials>>,
+) -> bool {
+    if configuration.debug_mode {
+        debug_print(&format!("matched command: {:?}", command));
+    }
+
+    let mut parsed_flags = Vec::new();
+    let mut matched_flag: Option<ParsedFlag> = None;
+    let mut looking_for_value = false;
+    'token_loop: for token in tokens.iter().skip(1) {
+        if !looking_for_value {
+            for flag in command.flags {
+                if (String::from("--") + flag.name) == *token
+                    || (String::from("-") + flag.short_name) == *token
+                {
+                    matched_flag = Some(ParsedFlag {
+                        name: Some(flag.name.to_string()),
+                        value: None,
+                    });
+                    if flag.has_value {
+                        looking_for_value = true;
+                        continue 'token_loop;
                     }
                 }
             }
-            if matched {
-                command_found = true;
-                if configuration.debug_mode {
-                    debug_print(&format!("matched command: {:?}", command));
+        }
+
+        match matched_flag {
+            Some(mut flag) => {
+                if looking_for_value {
+                    looking_for_value = false;
+                    flag.value = Some(token.clone());
                 }
+                parsed_flags.push(flag);
+                matched_flag = None;
+            }
+            None => parsed_flags.push(ParsedFlag {
+                name: None,
+                value: Some(token.clone()),
+            }),
+        };
+    }
+    if configuration.debug_mode {
+        debug_print(&format!("parsed flags: {:?}", parsed_flags));
+    }
 
-                let mut parsed_flags = Vec::new();
-                let mut matched_flag: Option<ParsedFlag> = None;
-                let mut looking_for_value = false;
-                'token_loop: for token in tokens.iter().skip(1) {
-                    if !looking_for_value {
-                        for flag in command.flags {
-                            if &(String::from("--") + flag.name) == token
-                                || &(String::from("-") + &flag.short_name) == token
-                            {
-                                matched_flag = Some(ParsedFlag {
-                                    name: Some(flag.name.to_string()),
-                                    value: None,
-                                });
-                                if flag.has_value {
-                                    looking_for_value = true;
-                                    continue 'token_loop;
-                                }
-                            }
-                        }
-                    }
+    handle_interrupts();
 
-                    match matched_flag {
-                        Some(mut flag) => {
-                            if looking_for_value {
-                                looking_for_value = false;
-                                flag.value = Some(token.clone());
-                            }
-                            parsed_flags.push(flag);
-                            matched_flag = None;
-                        }
-                        None => parsed_flags.push(ParsedFlag {
-                            name: None,
-                            value: Some(token.clone()),
-                        }),
-                    };
-                }
-                if configuration.debug_mode {
-                    debug_print(&format!("parsed flags: {:?}", parsed_flags));
-                }
+    let mut contexts: HashMap<String, Context> = HashMap::new();
+    for required_context in command.contexts {
+        match *required_context {
+            "configuration" => contexts.insert(
+                String::from("configuration"),
+                Context::Configuration(Box::new(configuration.clone())),
+            ),
+            "credentials" => match credentials {
+                Some(credentials) => contexts.insert(
+                    String::from("credentials"),
+                    Context::Credentials(Arc::clone(credentials)),
+                ),
+                None => None,
+            },
+            _ => None,
+        };
+    }
 
-                let mut contexts: HashMap<String, Context> = HashMap::new();
-                for required_context in command.contexts {
-                    match required_context {
-                        &"configuration" => contexts.insert(
-                            String::from("configuration"),
-                            Context::Configuration(configuration.clone()),
-                        ),
-                        &"fernet" => {
-                            contexts.insert(String::from("fernet"), Context::Fernet(fernet.clone()))
-                        }
-                        _ => None,
-                    };
+    let command_start = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let succeeded = (command.callback)(ParsedCommand {
+        flags: parsed_flags,
+        contexts,
+    });
+    let command_end = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    if configuration.debug_mode {
+        debug_print(&format!("command took {} ms", command_end - command_start))
+    }
+    succeeded
+}
+
+/// The first Ctrl-C lets the command stop between files (instead of in the
+/// middle of one), and the second one quits right away.
+fn handle_interrupts() {
+    static HANDLER: Once = Once::new();
+    HANDLER.call_once(|| {
+        let result = ctrlc::set_handler(|| {
+            if INTERRUPTED.swap(true, Ordering::SeqCst) {
+                std::process::exit(EXIT_INTERRUPTED)
+            }
+            eprintln!(
+                "{}",
+                format_colors("\n$BOLD$Interrupted!$NORMAL$ Stopping after the current file (press Ctrl-C again to quit right away)")
+            );
+        });
+        if let Err(error) = result {
+            eprintln!(
+                "{} {}",
+                format_colors("$BOLD$Unable to handle Ctrl-C:$NORMAL$"),
+                error
+            );
+        }
+    });
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+fn read_credentials(
+    keyfile: Option<&SecretBytes>,
+    keyfile_only: bool,
+    repeat_password: bool,
+) -> Credentials {
+    let mut password = String::new();
+    if keyfile.is_none() || !keyfile_only {
+        if keyfile.is_some() {
+            print!("Password (leave empty to only use your keyfile): ");
+        } else {
+            print!("Password: ");
+        }
+        std::io::stdout().flush().unwrap();
+        match rpassword::read_password() {
+            Ok(result) => password = result,
+            Err(error) => {
+                println!(
+                    "{} {:?}",
+                    format_colors(&String::from("$BOLD$Unable to read input:$NORMAL$")),
+                    error
+                );
+                std::process::exit(1)
+            }
+        }
+        if password.is_empty() && keyfile.is_none() {
+            println!("No password specified. Quitting...");
+            std::process::exit(1)
+        }
+    }
+    if repeat_password && !password.is_empty() {
+        print!("Repeat Password: ");
+        std::io::stdout().flush().unwrap();
+        match rpassword::read_password() {
+            Ok(repeat_password) => {
+                if password != *Zeroizing::new(repeat_password) {
+                    println!(
+                        "{}",
+                        format_colors(&String::from("$BOLD$Passwords do not match!$NORMAL$"))
+                    );
+                    std::process::exit(1)
                 }
+            }
+            Err(error) => {
+                println!(
+                    "{} {:?}",
+                    format_colors(&String::from("$BOLD$Unable to read input:$NORMAL$")),
+                    error,
+                );
+                std::process::exit(1)
+            }
+        }
+    }
+    Credentials::with_keyfile_digest(password, keyfile.cloned())
+}
 
-                let command_start = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis();
-                (command.callback)(ParsedCommand {
-                    name: first_token.clone(),
-                    flags: parsed_flags,
-                    contexts,
-                });
-                let command_end = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis();
-                if configuration.debug_mode {
-                    debug_print(&format!("command took {} ms", command_end - command_start))
+/// Checks the credentials against the password verifier, and saves a new one if
+/// there isn't one yet (and `save_verifier` is set). Returns false only if the
+/// credentials are different.
+fn verify_credentials(
+    verifier_path: &std::path::Path,
+    credentials: &Credentials,
+    configuration: &Configuration,
+    save_verifier: bool,
+) -> bool {
+    let verifier = match fs::read(verifier_path) {
+        Ok(verifier_bytes) => match PasswordVerifier::parse(&verifier_bytes) {
+            Ok(verifier) => verifier,
+            Err(error) => {
+                println!(
+                    "{} {}",
+                    format_colors(&String::from(
+                        "$BOLD$Unable to parse password verifier:$NORMAL$"
+                    )),
+                    error
+                );
+                if save_verifier {
+                    write_password_verifier(verifier_path, credentials, configuration);
                 }
+                return true;
+            }
+        },
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            if save_verifier {
+                write_password_verifier(verifier_path, credentials, configuration);
             }
+            return true;
+        }
+        Err(error) => {
+            println!(
+                "{} {:?}",
+                format_colors(&String::from(
+                    "$BOLD$Unable to read password verifier:$NORMAL$"
+                )),
+                error
+            );
+            return true;
+        }
+    };
+    match verifier.verify(credentials) {
+        Ok(verified) => verified,
+        Err(error) => {
+            println!(
+                "{} {}",
+                format_colors(&String::from("$BOLD$Unable to verify password:$NORMAL$")),
+                error
+            );
+            true
         }
+    }
+}
 
-        if !command_found {
+pub fn write_password_verifier(
+    verifier_path: &std::path::Path,
+    credentials: &Credentials,
+    configuration: &Configuration,
+) {
+    let verifier = match PasswordVerifier::new(
+        credentials,
+        configuration.encrypt_command.key_derivation_memory_cost,
+        configuration.encrypt_command.key_derivation_time_cost,
+        configuration.encrypt_command.key_derivation_parallelism,
+    ) {
+        Ok(verifier) => verifier,
+        Err(error) => {
             println!(
-                "{}",
+                "{} {}",
                 format_colors(&String::from(
-                    "$BOLD$Unknown command!$NORMAL$ Type $BOLD$`help`$NORMAL$ for a list of commands."
-                ))
+                    "$BOLD$Unable to create password verifier:$NORMAL$"
+                )),
+                error
             );
+            return;
         }
+    };
+    match fs::write(verifier_path, verifier.pack()) {
+        Ok(_) => (),
+        Err(error) => println!(
+            "{} {:?}",
+            format_colors(&String::from(
+                "$BOLD$Unable to save password verifier:$NORMAL$"
+            )),
+            error
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configurations_are_completed_with_the_defaults() {
+        let default_configuration = toml::to_string(&Configuration::default()).unwrap();
+        assert_eq!(include_str!("../configuration.toml"), default_configuration);
+
+        // The configuration from before any settings were added
+        let configuration: Configuration = toml::from_str(
+            r#"prompt = "$BOLD$$BLUE$$sfs::path$ >$NORMAL$ "
+debug_mode = false
+
+[list_command]
+display_all_files = false
+list_view = false
+grid_columns = 6
+decrypt_name = false
+file_format = "$sfs::name$"
+folder_format = "$BLUE$$sfs::name$"
+encrypted_format = "$YELLOW$$sfs::name$"
+decrypted_name_format = "$YELLOW$$sfs::decrypted_name$ $BOLD$($sfs::name$)"
+
+[encrypt_command]
+recursive = false
+silent = false
+overwrite = false
+keep_file = false
+hashing_algorithm = "xxh3"
+chunk_size = 1048576
+assign_random_name = false
+progress_bar_format = "$BOLD$$sfs::file.name$:$NORMAL$ [{elapsed_precise}] [{wide_bar:.blue/white}] {bytes}/{total_bytes} ({eta})"
+
+[decrypt_command]
+recursive = false
+silent = false
+overwrite = false
+keep_file = false
+use_original_name = false
+no_verify_chunks = false
+progress_bar_format = "$BOLD$$sfs::file.name$:$NORMAL$ [{elapsed_precise}] [{wide_bar:.blue/white}] {bytes}/{total_bytes} ({eta})"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            toml::to_string(&configuration).unwrap(),
+            default_configuration
+        );
     }
 }
@@ -1,12 +1,10 @@
-use fernet::Fernet;
-use sha2::{Digest, Sha256};
-
-pub fn determine_encrypted_size(input_size: usize) -> usize {
-    Fernet::new(&Fernet::generate_key())
-        .unwrap()
-        .encrypt(&"A".repeat(input_size).into_bytes())
-        .len()
-}
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Set when Ctrl-C is pressed, so that commands can stop between files.
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
 pub fn debug_print(message: &String) {
     println!(
@@ -16,8 +14,8 @@ pub fn debug_print(message: &String) {
     )
 }
 
-pub fn format_colors(text: &String) -> String {
-    let mut text = String::from("$NORMAL$") + &text.clone();
+pub fn format_colors(text: &str) -> String {
+    let mut text = String::from("$NORMAL$") + text;
 
     text = text.replace("$NORMAL$", "\u{001b}[0m");
     text = text.replace("$BOLD$", "\u{001b}[1m");
@@ -34,8 +32,8 @@ pub fn format_colors(text: &String) -> String {
     text
 }
 
-pub fn remove_colors(text: &String) -> String {
-    let mut text = text.clone();
+pub fn remove_colors(text: &str) -> String {
+    let mut text = text.to_string();
 
     text = text.replace("\u{001b}[0m", "");
     text = text.replace("\u{001b}[1m", "");
@@ -52,25 +50,7 @@ pub fn remove_colors(text: &String) -> String {
     text
 }
 
-pub fn generate_fernet(password: &String) -> fernet::Fernet {
-    let mut result = password.to_owned();
-    for _ in 0..256 {
-        let mut hasher = Sha256::new();
-        hasher.update(result.clone().into_bytes());
-        result = format!("{:X}", hasher.finalize());
-    }
-
-    let mut key = String::new();
-    for (index, letter) in result.chars().enumerate() {
-        if index % 2 == 0 {
-            key.push(letter);
-        }
-    }
-
-    fernet::Fernet::new(&base64::encode_config(key, base64::URL_SAFE)).unwrap()
-}
-
-pub fn tokenize(command: &String) -> Vec<String> {
+pub fn tokenize(command: &str) -> Vec<String> {
     let mut tokens = Vec::new();
     let mut current_token = String::new();
     let mut in_string = (false, "");
@@ -80,7 +60,7 @@ pub fn tokenize(command: &String) -> Vec<String> {
             continue;
         }
         if letter == ' ' && !in_string.0 {
-            if current_token.len() > 0 {
+            if !current_token.is_empty() {
                 tokens.push(current_token);
                 current_token = String::new();
             }
@@ -88,14 +68,14 @@ pub fn tokenize(command: &String) -> Vec<String> {
         }
         if letter == '"' && !in_string.0 {
             in_string = (true, "\"");
-            if current_token.len() > 0 {
+            if !current_token.is_empty() {
                 tokens.push(current_token);
                 current_token = String::new();
             }
             continue;
         } else if letter == '"' && in_string.0 {
             in_string = (false, "");
-            if current_token.len() > 0 {
+            if !current_token.is_empty() {
                 tokens.push(current_token);
                 current_token = String::new();
             }
@@ -106,13 +86,297 @@ pub fn tokenize(command: &String) -> Vec<String> {
             in_string = (false, "");
         }
     }
-    if current_token.len() > 0 {
+    if !current_token.is_empty() {
         tokens.push(current_token);
     }
     tokens
 }
 
-pub fn quit_sfs() {
+pub fn get_configuration_directory() -> PathBuf {
+    let mut configuration_directory = match home::home_dir() {
+        Some(path) => path,
+        None => PathBuf::from("."),
+    };
+    if cfg!(unix) {
+        configuration_directory.push(".config/sfs")
+    } else if cfg!(windows) {
+        configuration_directory.push("AppData/Roaming/sfs")
+    }
+    configuration_directory
+}
+
+pub fn format_checksum(checksum: &[u8]) -> String {
+    if checksum.is_empty() {
+        return String::from("None");
+    }
+    checksum
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub fn quit_sfs() -> ! {
     println!("Quitting SFS...");
     std::process::exit(0)
 }
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub enum PasswordSource {
+    /// The name of the environment variable.
+    Environment(String),
+    FileDescriptor(i32),
+    File(String),
+    Command(String),
+}
+
+/// Reads the password from a non-interactive source. Only the first line is
+/// used, so that a trailing newline isn't part of the password.
+pub fn read_password_source(password_source: &PasswordSource) -> Result<String, String> {
+    let result = match password_source {
+        PasswordSource::Environment(variable) => match std::env::var(variable) {
+            Ok(password) => {
+                // So that it isn't passed on to the commands that are run from SFS. `rekey`
+                // gets here while the Ctrl-C handler's thread is running, which is still sound:
+                // it's the only other thread, and it never reads or changes the environment
+                std::env::remove_var(variable);
+                Ok(password)
+            }
+            Err(error) => Err(format!(
+                "{} {:?}",
+                format_colors(&format!("$BOLD$Unable to read {}:$NORMAL$", variable)),
+                error
+            )),
+        },
+        PasswordSource::FileDescriptor(file_descriptor) => {
+            match read_file_descriptor(*file_descriptor) {
+                Ok(password) => Ok(password),
+                Err(error) => Err(format!(
+                    "{} {:?}",
+                    format_colors(&format!(
+                        "$BOLD$[{}] Unable to read password from file descriptor:$NORMAL$",
+                        file_descriptor
+                    )),
+                    error
+                )),
+            }
+        }
+        PasswordSource::File(path) => match read_password_file(path) {
+            Ok(password) => Ok(password),
+            Err(error) => Err(format!(
+                "{} {:?}",
+                format_colors(&format!(
+                    "$BOLD$[{}] Unable to read password file:$NORMAL$",
+                    path
+                )),
+                error
+            )),
+        },
+        PasswordSource::Command(command) => {
+            let mut process = if cfg!(windows) {
+                let mut process = std::process::Command::new("cmd");
+                process.arg("/C");
+                process
+            } else {
+                let mut process = std::process::Command::new("sh");
+                process.arg("-c");
+                process
+            };
+            match process
+                .arg(command)
+                .stdin(std::process::Stdio::inherit())
+                .stderr(std::process::Stdio::inherit())
+                .output()
+            {
+                Ok(output) if output.status.success() => match String::from_utf8(output.stdout) {
+                    Ok(password) => Ok(password),
+                    Err(error) => {
+                        error.into_bytes().zeroize();
+                        Err(format!(
+                            "{} the output is not valid UTF-8",
+                            format_colors(&format!(
+                                "$BOLD$[{}] Unable to read password from command:$NORMAL$",
+                                command
+                            ))
+                        ))
+                    }
+                },
+                Ok(mut output) => {
+                    output.stdout.zeroize();
+                    Err(format!(
+                        "{} {}",
+                        format_colors(&format!(
+                            "$BOLD$[{}] Password command failed:$NORMAL$",
+                            command
+                        )),
+                        output.status
+                    ))
+                }
+                Err(error) => Err(format!(
+                    "{} {:?}",
+                    format_colors(&format!(
+                        "$BOLD$[{}] Unable to run password command:$NORMAL$",
+                        command
+                    )),
+                    error
+                )),
+            }
+        }
+    };
+    result.map(|mut password| {
+        let line_length = password.find(['\r', '\n']).unwrap_or(password.len());
+        // Zeroes the rest of the string before it's truncated (zeroes are still valid UTF-8)
+        unsafe { password[line_length..].as_bytes_mut() }.zeroize();
+        password.truncate(line_length);
+        password
+    })
+}
+
+/// The longest password that is read from a file descriptor or a file.
+const MAXIMUM_PASSWORD_SIZE: usize = 4096;
+
+/// Reads the first line from the file descriptor, one byte at a time so that
+/// nothing after it is consumed. The file descriptor is only borrowed, so it
+/// stays open (`--password-fd 0` doesn't close stdin, for example).
+#[cfg(unix)]
+fn read_file_descriptor(file_descriptor: i32) -> std::io::Result<String> {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    if unsafe { libc::fcntl(file_descriptor, libc::F_GETFD) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut file = std::mem::ManuallyDrop::new(unsafe { fs::File::from_raw_fd(file_descriptor) });
+    // Allocated up front, so that no copies of the password are left behind by growing it
+    let mut password = Zeroizing::new(vec![0; MAXIMUM_PASSWORD_SIZE]);
+    let mut length = 0;
+    loop {
+        if length == password.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the password is too long",
+            ));
+        }
+        match file.read(&mut password[length..length + 1]) {
+            Ok(0) => break,
+            Ok(_) if password[length] == b'\n' => break,
+            Ok(_) => length += 1,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+    password.truncate(length);
+    match String::from_utf8(std::mem::take(&mut *password)) {
+        Ok(password) => Ok(password),
+        Err(error) => {
+            error.into_bytes().zeroize();
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the password is not valid UTF-8",
+            ))
+        }
+    }
+}
+
+/// Reads the start of the file into a buffer that's allocated up front, like
+/// `read_file_descriptor` does. The first line has to fit into it.
+fn read_password_file(path: &str) -> std::io::Result<String> {
+    use std::io::Read;
+
+    let mut file = fs::File::open(path)?;
+    let mut password = Zeroizing::new(vec![0; MAXIMUM_PASSWORD_SIZE]);
+    let mut length = 0;
+    while length < password.len() {
+        match file.read(&mut password[length..]) {
+            Ok(0) => break,
+            Ok(read) => length += read,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(error) => return Err(error),
+        }
+    }
+    // Only the first line is kept, so the rest of the file doesn't have to be valid UTF-8
+    let line_length = match password[..length]
+        .iter()
+        .position(|byte| *byte == b'\n' || *byte == b'\r')
+    {
+        Some(line_length) => line_length,
+        None if length == password.len() => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the password is too long",
+            ))
+        }
+        None => length,
+    };
+    password[line_length..].zeroize();
+    password.truncate(line_length);
+    match String::from_utf8(std::mem::take(&mut *password)) {
+        Ok(password) => Ok(password),
+        Err(error) => {
+            error.into_bytes().zeroize();
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the password is not valid UTF-8",
+            ))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn read_file_descriptor(_: i32) -> std::io::Result<String> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "file descriptors can only be used on unix",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_first_line_is_read() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("password");
+        let read_file = |content: &[u8]| {
+            fs::write(&path, content).unwrap();
+            read_password_source(&PasswordSource::File(path.display().to_string()))
+        };
+        assert_eq!(read_file(b"password").unwrap(), "password");
+        assert_eq!(read_file(b"password\nsecond line\n").unwrap(), "password");
+        assert_eq!(read_file(b"password\r\n").unwrap(), "password");
+        assert_eq!(read_file(b"\npassword").unwrap(), "");
+        // Only the first line has to fit, and be valid UTF-8
+        let mut content = b"password\n".to_vec();
+        content.extend(vec![0xff; MAXIMUM_PASSWORD_SIZE]);
+        assert_eq!(read_file(&content).unwrap(), "password");
+        assert!(read_file(&vec![b'a'; MAXIMUM_PASSWORD_SIZE]).is_err());
+        assert!(read_file(&[0xff, b'\n']).is_err());
+        assert!(read_password_source(&PasswordSource::File(
+            directory.path().join("missing").display().to_string()
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn environment_variables_are_removed_after_reading() {
+        let source = PasswordSource::Environment(String::from("SFS_PASSWORD"));
+        std::env::set_var("SFS_PASSWORD", "password\nsecond line");
+        assert_eq!(read_password_source(&source).unwrap(), "password");
+        assert!(std::env::var_os("SFS_PASSWORD").is_none());
+        assert!(read_password_source(&source).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn password_commands_have_to_succeed() {
+        let source = PasswordSource::Command(String::from("printf 'password\\nsecond line'"));
+        assert_eq!(read_password_source(&source).unwrap(), "password");
+        let source = PasswordSource::Command(String::from("echo password; exit 1"));
+        assert!(read_password_source(&source)
+            .unwrap_err()
+            .contains("Password command failed"));
+    }
+}
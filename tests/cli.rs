@@ -0,0 +1,431 @@
+//! Runs the `sfs` binary like a script would, with a home directory (and so a
+//! configuration) of its own for every test.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+
+struct Sandbox {
+    directory: TempDir,
+}
+impl Sandbox {
+    /// Writes the default configuration, with a key derivation that's cheap enough for tests.
+    fn new() -> Self {
+        let sandbox = Sandbox {
+            directory: tempfile::tempdir().unwrap(),
+        };
+        fs::create_dir(sandbox.home()).unwrap();
+        assert!(sandbox.run("password", &["version"]).status.success());
+        let configuration_path = sandbox.configuration_directory().join("configuration.toml");
+        let configuration = fs::read_to_string(&configuration_path)
+            .unwrap()
+            .replace(
+                "key_derivation_memory_cost = 19456",
+                "key_derivation_memory_cost = 8",
+            )
+            .replace(
+                "key_derivation_time_cost = 2",
+                "key_derivation_time_cost = 1",
+            );
+        fs::write(&configuration_path, configuration).unwrap();
+        sandbox
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.directory.path().join(name)
+    }
+
+    fn home(&self) -> PathBuf {
+        self.path("home")
+    }
+
+    fn configuration_directory(&self) -> PathBuf {
+        self.home().join(".config/sfs")
+    }
+
+    fn command(&self, password: &str, arguments: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_sfs"));
+        command
+            .args(arguments)
+            .current_dir(self.directory.path())
+            .env("HOME", self.home())
+            .env("SFS_PASSWORD", password)
+            .env_remove("SFS_NEW_PASSWORD")
+            .stdin(Stdio::null());
+        command
+    }
+
+    fn run(&self, password: &str, arguments: &[&str]) -> Output {
+        self.command(password, arguments).output().unwrap()
+    }
+
+    /// Runs the command and makes sure that it succeeded.
+    fn succeed(&self, password: &str, arguments: &[&str]) -> String {
+        let output = self.run(password, arguments);
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        assert!(
+            output.status.success(),
+            "sfs {:?} failed: {}{}",
+            arguments,
+            stdout,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        stdout
+    }
+}
+
+fn file_names(directory: &Path) -> Vec<String> {
+    let mut file_names: Vec<String> = fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    file_names.sort();
+    file_names
+}
+
+#[test]
+fn rekey_changes_the_password() {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path("file.txt"), "some content").unwrap();
+    sandbox.succeed("old", &["encrypt", "file.txt"]);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(
+            sandbox.path("file.txt.sfs"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+    }
+
+    let output = sandbox
+        .command("old", &["rekey", "file.txt.sfs"])
+        .env("SFS_NEW_PASSWORD", "new")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(sandbox.path("file.txt.sfs"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // rekey also sets the password that's expected from then on
+    let output = sandbox.run("old", &["decrypt", "file.txt.sfs"]);
+    assert_eq!(output.status.code(), Some(3));
+    let output = sandbox.run("old", &["--skip-verify", "decrypt", "file.txt.sfs"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!sandbox.path("file.txt").exists());
+    sandbox.succeed("new", &["decrypt", "file.txt.sfs"]);
+    assert_eq!(
+        fs::read_to_string(sandbox.path("file.txt")).unwrap(),
+        "some content"
+    );
+}
+
+#[test]
+fn rekey_skips_files_that_are_only_encrypted_for_recipients() {
+    let sandbox = Sandbox::new();
+    let keygen_output = sandbox.succeed("password", &["keygen"]);
+    let public_key = keygen_output
+        .split_whitespace()
+        .find(|word| word.contains("sfs-public-"))
+        .unwrap();
+    let public_key = &public_key[public_key.find("sfs-public-").unwrap()..];
+    fs::create_dir(sandbox.path("files")).unwrap();
+    fs::write(sandbox.path("files/file.txt"), "some content").unwrap();
+    sandbox.succeed(
+        "password",
+        &["encrypt", "--recipient", public_key, "files/file.txt"],
+    );
+    let encrypted = fs::read(sandbox.path("files/file.txt.sfs")).unwrap();
+
+    let output = sandbox
+        .command("password", &["rekey", "files"])
+        .env("SFS_NEW_PASSWORD", "new")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("only encrypted for recipients"));
+    assert_eq!(
+        fs::read(sandbox.path("files/file.txt.sfs")).unwrap(),
+        encrypted
+    );
+    assert_eq!(file_names(&sandbox.path("files")), ["file.txt.sfs"]);
+}
+
+#[test]
+fn keyfile_only_mode() {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path("keyfile"), "keyfile content").unwrap();
+    let configuration_path = sandbox.configuration_directory().join("configuration.toml");
+    let configuration = fs::read_to_string(&configuration_path)
+        .unwrap()
+        .replace(
+            "keyfile = \"\"",
+            &format!("keyfile = {:?}", sandbox.path("keyfile")),
+        )
+        .replace("keyfile_only = false", "keyfile_only = true");
+    fs::write(&configuration_path, configuration).unwrap();
+    fs::write(sandbox.path("file.txt"), "some content").unwrap();
+
+    let run_without_password = |arguments: &[&str]| {
+        sandbox
+            .command("", arguments)
+            .env_remove("SFS_PASSWORD")
+            .output()
+            .unwrap()
+    };
+    assert!(run_without_password(&["encrypt", "file.txt"])
+        .status
+        .success());
+    let output = sandbox.run("password", &["decrypt", "-k", "file.txt.sfs"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(run_without_password(&["decrypt", "file.txt.sfs"])
+        .status
+        .success());
+    assert_eq!(
+        fs::read_to_string(sandbox.path("file.txt")).unwrap(),
+        "some content"
+    );
+}
+
+#[test]
+fn broken_configurations_are_kept_without_anyone_to_ask() {
+    let sandbox = Sandbox::new();
+    let configuration_path = sandbox.configuration_directory().join("configuration.toml");
+    fs::write(&configuration_path, "prompt = ").unwrap();
+
+    // Neither a command nor the end of the input answers the question
+    let output = sandbox.run("password", &["version"]);
+    assert_eq!(output.status.code(), Some(1));
+    let output = sandbox.run("password", &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        fs::read_to_string(&configuration_path).unwrap(),
+        "prompt = "
+    );
+}
+
+#[test]
+fn random_padding_percentages_are_checked() {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path("file.txt"), "some content").unwrap();
+    for padding in ["random:0", "random:101"] {
+        let output = sandbox.run("password", &["encrypt", "--padding", padding, "file.txt"]);
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(file_names(sandbox.directory.path()), ["file.txt", "home"]);
+    }
+    sandbox.succeed(
+        "password",
+        &["encrypt", "--padding", "random:100", "file.txt"],
+    );
+    assert_eq!(
+        file_names(sandbox.directory.path()),
+        ["file.txt.sfs", "home"]
+    );
+}
+
+/// Leaves behind what an `encrypt` run that was interrupted with an input in every
+/// state of the journal looks like.
+fn interrupted_encryption(sandbox: &Sandbox) {
+    fs::create_dir(sandbox.path("files")).unwrap();
+    for name in ["pending", "started", "written", "shredding", "done"] {
+        fs::write(sandbox.path(&format!("files/{}.txt", name)), name).unwrap();
+    }
+    sandbox.succeed(
+        "password",
+        &[
+            "encrypt",
+            "-k",
+            "files/written.txt",
+            "files/shredding.txt",
+            "files/done.txt",
+        ],
+    );
+    fs::write(
+        sandbox.path("files/.started.txt.sfs.Ab3dE6gH.tmp"),
+        "partial",
+    )
+    .unwrap();
+    fs::write(sandbox.path("files/shredding.txt"), "overwritten").unwrap();
+    fs::remove_file(sandbox.path("files/done.txt")).unwrap();
+
+    let mut journal = String::from("command = \"encrypt\"\n");
+    for (state, name, has_output) in [
+        ("pending", "pending", false),
+        ("started", "started", true),
+        ("written", "written", true),
+        ("shredding", "shredding", true),
+        ("done", "done", true),
+    ] {
+        let input_path = sandbox.path(&format!("files/{}.txt", name));
+        journal.push_str(&format!(
+            "\n[[entries]]\nstate = {:?}\ninput_path = {:?}\n",
+            state, input_path
+        ));
+        if has_output {
+            journal.push_str(&format!(
+                "output_path = {:?}\n",
+                sandbox.path(&format!("files/{}.txt.sfs", name))
+            ));
+        }
+    }
+    fs::write(
+        sandbox.configuration_directory().join("journal.toml"),
+        journal,
+    )
+    .unwrap();
+}
+
+#[test]
+fn resume_finishes_interrupted_runs() {
+    let sandbox = Sandbox::new();
+    interrupted_encryption(&sandbox);
+
+    sandbox.succeed("password", &["resume"]);
+    assert!(!sandbox
+        .configuration_directory()
+        .join("journal.toml")
+        .exists());
+    let names = ["done", "pending", "shredding", "started", "written"];
+    assert_eq!(
+        file_names(&sandbox.path("files")),
+        names.map(|name| format!("{}.txt.sfs", name))
+    );
+    sandbox.succeed("password", &["decrypt", "-r", "files"]);
+    for name in names {
+        assert_eq!(
+            fs::read_to_string(sandbox.path(&format!("files/{}.txt", name))).unwrap(),
+            name
+        );
+    }
+}
+
+#[test]
+fn resume_rolls_back_interrupted_runs() {
+    let sandbox = Sandbox::new();
+    interrupted_encryption(&sandbox);
+
+    sandbox.succeed("password", &["resume", "--rollback"]);
+    assert!(!sandbox
+        .configuration_directory()
+        .join("journal.toml")
+        .exists());
+    let names = ["done", "pending", "shredding", "started", "written"];
+    assert_eq!(
+        file_names(&sandbox.path("files")),
+        names.map(|name| format!("{}.txt", name))
+    );
+    // The inputs that were intact are kept, and the others are restored from their outputs
+    for name in names {
+        assert_eq!(
+            fs::read_to_string(sandbox.path(&format!("files/{}.txt", name))).unwrap(),
+            name
+        );
+    }
+}
+
+#[test]
+fn decryption_can_only_be_rolled_back_while_the_encrypted_files_exist() {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path("file.txt"), "some content").unwrap();
+    sandbox.succeed("password", &["encrypt", "file.txt"]);
+    sandbox.succeed("password", &["decrypt", "-k", "file.txt.sfs"]);
+    fs::remove_file(sandbox.path("file.txt.sfs")).unwrap();
+    let journal_path = sandbox.configuration_directory().join("journal.toml");
+    let journal = format!(
+        "command = \"decrypt\"\n\n[[entries]]\nstate = \"done\"\ninput_path = {:?}\noutput_path = {:?}\n",
+        sandbox.path("file.txt.sfs"),
+        sandbox.path("file.txt")
+    );
+    fs::write(&journal_path, journal).unwrap();
+
+    let output = sandbox.run("password", &["resume", "--rollback"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(journal_path.exists());
+    assert_eq!(file_names(sandbox.directory.path()), ["file.txt", "home"]);
+    sandbox.succeed("password", &["resume"]);
+    assert!(!journal_path.exists());
+    assert_eq!(file_names(sandbox.directory.path()), ["file.txt", "home"]);
+}
+
+/// The path and content of everything in the directory, to compare it before and after.
+fn snapshot(directory: &Path) -> Vec<(PathBuf, Option<Vec<u8>>)> {
+    let mut snapshot = Vec::new();
+    for entry in fs::read_dir(directory).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            snapshot.push((path.clone(), None));
+            snapshot.extend(self::snapshot(&path));
+        } else {
+            snapshot.push((path.clone(), Some(fs::read(&path).unwrap())));
+        }
+    }
+    snapshot.sort();
+    snapshot
+}
+
+#[test]
+fn dry_runs_dont_change_anything() {
+    let sandbox = Sandbox::new();
+    fs::create_dir(sandbox.path("files")).unwrap();
+    fs::write(sandbox.path("files/file.txt"), "some content").unwrap();
+    fs::write(sandbox.path("file.txt"), "some content").unwrap();
+    sandbox.succeed("password", &["encrypt", "-k", "file.txt"]);
+
+    let before = snapshot(sandbox.directory.path());
+    for arguments in [
+        &["encrypt", "--dry-run", "file.txt"][..],
+        &["encrypt", "--dry-run", "-r", "files"],
+        &["decrypt", "--dry-run", "file.txt.sfs"],
+        &["shred", "--dry-run", "-r", "file.txt", "files"],
+    ] {
+        let stdout = sandbox.succeed("password", arguments);
+        assert!(!stdout.is_empty(), "sfs {:?} printed nothing", arguments);
+        assert_eq!(
+            snapshot(sandbox.directory.path()),
+            before,
+            "{:?}",
+            arguments
+        );
+    }
+}
+
+#[test]
+fn information_shows_the_compression_ratio() {
+    let sandbox = Sandbox::new();
+    fs::write(sandbox.path("small.txt"), "some content").unwrap();
+    fs::write(sandbox.path("large.txt"), "some content\n".repeat(10000)).unwrap();
+    fs::write(sandbox.path("empty.txt"), "").unwrap();
+    sandbox.succeed("password", &["encrypt", "small.txt", "empty.txt"]);
+    sandbox.succeed("password", &["encrypt", "-z", "zstd", "large.txt"]);
+
+    let compression = |name: &str| {
+        let stdout = sandbox.succeed("password", &["info", name]);
+        let line = stdout
+            .lines()
+            .find(|line| line.contains("Compression:"))
+            .unwrap()
+            .to_string();
+        line[line.find(':').unwrap() + 1..]
+            .replace("\x1b[0m", "")
+            .trim()
+            .to_string()
+    };
+    assert_eq!(compression("small.txt.sfs"), "NONE (ratio: 1.00)");
+    assert_eq!(compression("empty.txt.sfs"), "NONE (ratio: N/A)");
+    let large = compression("large.txt.sfs");
+    let ratio: f64 = large
+        .strip_prefix("ZSTD (ratio: ")
+        .and_then(|ratio| ratio.strip_suffix(')'))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(ratio > 10.0, "{}", large);
+}